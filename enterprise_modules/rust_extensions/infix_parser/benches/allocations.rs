@@ -0,0 +1,76 @@
+//! Allocations of `simplify` on a mostly symbolic tree, against rebuilding
+//! every node as it did before `map_children` reused child boxes. Run with
+//! `cargo bench --bench allocations`.
+
+use infix_parser::{build_balanced, node_count, parse_source, simplify, simplify_node};
+use infix_parser::{Expr, FoldOptions};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// Counts heap allocations so the runs can report them.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// `simplify` as it was before `map_children` reused child boxes: every
+/// child is moved out and boxed again, even when nothing changed.
+fn reboxing_simplify(expr: Expr) -> Expr {
+    let expr = match expr {
+        Expr::Binary { op, left, right } => Expr::Binary {
+            op,
+            left: Box::new(reboxing_simplify(*left)),
+            right: Box::new(reboxing_simplify(*right)),
+        },
+        Expr::Unary { op, expr } => Expr::Unary { op, expr: Box::new(reboxing_simplify(*expr)) },
+        Expr::Call { name, args } => {
+            Expr::Call { name, args: args.into_iter().map(reboxing_simplify).collect() }
+        }
+        other => other,
+    };
+    simplify_node(expr, &FoldOptions::default())
+}
+
+/// Mean time and allocations per run of `f` on fresh clones of `tree`.
+fn measure_runs(runs: u32, tree: &Expr, f: impl Fn(Expr) -> Expr) -> (f64, usize) {
+    let (mut total, mut allocations) = (Duration::ZERO, 0);
+    for _ in 0..runs {
+        let input = tree.clone();
+        let before = ALLOCATIONS.load(Ordering::Relaxed);
+        let start = Instant::now();
+        let output = f(input);
+        total += start.elapsed();
+        allocations += ALLOCATIONS.load(Ordering::Relaxed) - before;
+        drop(output);
+    }
+    (total.as_secs_f64() * 1000.0 / f64::from(runs), allocations / runs as usize)
+}
+
+fn main() {
+    let groups = (0..5000).map(|i| {
+        parse_source(&format!("(x{i} * y{i} - z{i} / (w{i} + 2*k))")).unwrap()
+    });
+    let tree = build_balanced("+", groups.collect());
+    let fold = FoldOptions::default();
+    assert_eq!(reboxing_simplify(tree.clone()), simplify(tree.clone(), &fold));
+    let (before_ms, before) = measure_runs(20, &tree, reboxing_simplify);
+    let (after_ms, after) = measure_runs(20, &tree, |t| simplify(t, &fold));
+    println!("{} nodes", node_count(&tree));
+    println!("rebuilding: {:.2} ms/run, {} allocations/run", before_ms, before);
+    println!("in place:   {:.2} ms/run, {} allocations/run", after_ms, after);
+    assert_eq!(after, 0);
+}
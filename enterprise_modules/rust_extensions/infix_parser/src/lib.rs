@@ -1,171 +1,5141 @@
+use pyo3::create_exception;
+use pyo3::exceptions::{PyRuntimeWarning, PyTimeoutError, PyTypeError, PyValueError};
 use pyo3::prelude::*;
-use pyo3::types::PyDict;
+use pyo3::pyclass::CompareOp;
+use pyo3::types::{PyComplex, PyDict, PyFloat, PyIterator, PyList, PyLong, PyTuple};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant};
+
+create_exception!(infix_parser, ParseError, PyValueError);
+create_exception!(infix_parser, EvaluationError, PyValueError);
+create_exception!(infix_parser, LimitError, PyValueError);
+// A `ParseError` for a token that cannot be a literal, such as `1.2.3`.
+create_exception!(infix_parser, TokenizeError, ParseError);
+
+/// An error from the parse pipeline, raised as the exception of the same
+/// name. Kept free of Python so the pipeline runs without an interpreter.
+#[derive(Debug, Clone, PartialEq)]
+enum Failure {
+    Parse(String),
+    Tokenize(String),
+    Limit(String),
+    Evaluation(String),
+}
+
+impl Failure {
+    fn message(&self) -> &str {
+        match self {
+            Failure::Parse(message)
+            | Failure::Tokenize(message)
+            | Failure::Limit(message)
+            | Failure::Evaluation(message) => message,
+        }
+    }
+
+    /// The same kind of failure with its message rewritten.
+    fn map(self, f: impl FnOnce(&str) -> String) -> Failure {
+        match self {
+            Failure::Parse(message) => Failure::Parse(f(&message)),
+            Failure::Tokenize(message) => Failure::Tokenize(f(&message)),
+            Failure::Limit(message) => Failure::Limit(f(&message)),
+            Failure::Evaluation(message) => Failure::Evaluation(f(&message)),
+        }
+    }
+}
+
+impl From<Failure> for PyErr {
+    fn from(failure: Failure) -> PyErr {
+        match failure {
+            Failure::Parse(message) => ParseError::new_err(message),
+            Failure::Tokenize(message) => TokenizeError::new_err(message),
+            Failure::Limit(message) => LimitError::new_err(message),
+            Failure::Evaluation(message) => EvaluationError::new_err(message),
+        }
+    }
+}
+
+/// Binary operators by precedence level, lowest first. The parser and
+/// `features` both read these so the capability report stays accurate.
+/// `//` is floor division: `(a / b).floor()`, rounding toward negative
+/// infinity like Python, so `-7 // 2` is `-4`. A zero divisor follows the
+/// same IEEE rules as `/` (`inf`, `-inf` or `NaN`).
+///
+/// The words `div`, `mod` and `rem` are multiplicative operators too. `div`
+/// is `//` and `mod` is `%`, both rounding the quotient down, so the modulus
+/// takes the sign of the divisor (`-7 mod 3` is `2`). `rem` truncates the
+/// quotient instead, so the remainder takes the sign of the dividend
+/// (`-7 rem 3` is `-1`).
+///
+/// `^` is exponentiation and groups to the right, so `2^3^2` is `2^(3^2)`;
+/// like Python's `**` it binds tighter than a unary minus on its left, so
+/// `-2^2` is `-4`.
+///
+/// Comparisons and `and` produce `1` for true and `0` for false; `and`
+/// treats any nonzero operand as true. The result is an ordinary number, so
+/// `(x > 0) * x` is a relu; since comparisons bind looser than arithmetic,
+/// the comparison needs its parentheses there.
+///
+/// `<<` and `>>` shift 64-bit integers and bind looser than `+`, as in Python.
+/// See `apply_shift` for the checks on their operands.
+const AND_OPS: &[&str] = &["and"];
+const COMPARISON_OPS: &[&str] = &["<", "<=", ">", ">=", "==", "!="];
+const SHIFT_OPS: &[&str] = &["<<", ">>"];
+const ADDITIVE_OPS: &[&str] = &["+", "-"];
+const MULTIPLICATIVE_OPS: &[&str] = &["*", "/", "//", "%", "div", "mod", "rem"];
+const POWER_OPS: &[&str] = &["^"];
+const UNARY_OPS: &[&str] = &["+", "-"];
+
+/// The operators spelled as words, which are reserved and never read as a
+/// variable or function name.
+const WORD_OPS: &[&str] = &["and", "div", "mod", "rem"];
+
+/// Builtin functions with their minimum and maximum arity (`usize::MAX` for
+/// variadic ones). Calls to these are arity-checked at parse time and folded
+/// once every argument is a number; calls to any other name are kept
+/// symbolic.
+const FUNCTIONS: &[(&str, usize, usize)] = &[
+    ("floor", 1, 1),
+    ("ceil", 1, 1),
+    ("round", 1, 2),
+    ("trunc", 1, 1),
+    ("sin", 1, 1),
+    ("cos", 1, 1),
+    ("tan", 1, 1),
+    ("asin", 1, 1),
+    ("acos", 1, 1),
+    ("atan", 1, 1),
+    ("atan2", 2, 2),
+    ("sinh", 1, 1),
+    ("cosh", 1, 1),
+    ("tanh", 1, 1),
+    ("asinh", 1, 1),
+    ("acosh", 1, 1),
+    ("atanh", 1, 1),
+    ("max", 1, usize::MAX),
+    ("min", 1, usize::MAX),
+    ("gcd", 1, usize::MAX),
+    ("lcm", 1, usize::MAX),
+    ("if", 3, 3),
+    ("approx", 3, 3),
+];
+
+/// Opt-in physical constants in SI units (CODATA 2018; all but `G` and
+/// `m_e` are exact by definition of the SI).
+const PHYSICAL_CONSTANTS: &[(&str, f64)] = &[
+    ("c", 299_792_458.0),         // speed of light in vacuum, m/s
+    ("g", 9.806_65),              // standard gravity, m/s^2
+    ("G", 6.674_30e-11),          // gravitational constant, m^3/(kg s^2)
+    ("h", 6.626_070_15e-34),      // Planck constant, J s
+    ("hbar", 1.054_571_817e-34),  // reduced Planck constant, J s
+    ("k_B", 1.380_649e-23),       // Boltzmann constant, J/K
+    ("N_A", 6.022_140_76e23),     // Avogadro constant, 1/mol
+    ("q_e", 1.602_176_634e-19),   // elementary charge, C
+    ("m_e", 9.109_383_701_5e-31), // electron mass, kg
+];
+
+/// IEEE special values, bound only when `allow_special` is set.
+const SPECIAL_VALUES: &[(&str, f64)] =
+    &[("inf", f64::INFINITY), ("infinity", f64::INFINITY), ("nan", f64::NAN)];
+
+fn physical_constant(name: &str) -> Option<f64> {
+    PHYSICAL_CONSTANTS.iter().find(|(n, _)| *n == name).map(|(_, v)| *v)
+}
 
 /// Simple AST node
-#[derive(Debug, Clone)]
-enum Expr {
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
     Number(f64),
-    Unary { op: char, expr: Box<Expr> },
-    Binary { op: char, left: Box<Expr>, right: Box<Expr> },
+    Variable(String),
+    Unary { op: &'static str, expr: Box<Expr> },
+    Binary { op: &'static str, left: Box<Expr>, right: Box<Expr> },
+    Call { name: String, args: Vec<Expr> },
+}
+
+/// Resource guards applied by `parse_infix`; `None` means unlimited.
+#[derive(Debug, Clone, Default)]
+struct Limits {
+    max_tokens: Option<usize>,
+    max_depth: Option<usize>,
+    max_nodes: Option<usize>,
+    /// Deepest nesting of function calls, e.g. 3 for `f(g(h(x)))`. Unlike
+    /// the other tree limits this is checked on the tokens before parsing,
+    /// so a pathologically nested input is rejected before the recursive
+    /// parser and folder can exhaust the stack.
+    max_call_depth: Option<usize>,
+    /// Distinct free variables, counted after constants are bound and before
+    /// simplifying, so `x - x` still references one.
+    max_variables: Option<usize>,
+}
+
+impl Limits {
+    fn from_dict(dict: &Bound<'_, PyDict>) -> PyResult<Self> {
+        let mut limits = Limits::default();
+        for (key, value) in dict.iter() {
+            let key: String = key.extract()?;
+            let value: usize = value.extract()?;
+            match key.as_str() {
+                "max_tokens" => limits.max_tokens = Some(value),
+                "max_depth" => limits.max_depth = Some(value),
+                "max_nodes" => limits.max_nodes = Some(value),
+                "max_call_depth" => limits.max_call_depth = Some(value),
+                "max_variables" => limits.max_variables = Some(value),
+                _ => return Err(PyValueError::new_err(format!("unknown limit '{}'", key))),
+            }
+        }
+        Ok(limits)
+    }
+}
+
+fn check_limit(name: &str, limit: Option<usize>, actual: usize) -> Result<(), Failure> {
+    match limit {
+        Some(max) if actual > max => Err(Failure::Limit(format!(
+            "{} exceeded: {} > {}",
+            name, actual, max
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// A single program statement
+#[derive(Debug, Clone)]
+enum Statement {
+    Assign(String, Expr),
+    Expr(Expr),
+}
+
+/// Identifiers start with a letter or `_` and continue with letters, ASCII
+/// digits or `_`. "Letter" means any Unicode alphabetic character, so `x_1`,
+/// `theta` and `α` are all single identifiers.
+fn is_identifier_start(c: char) -> bool {
+    c.is_alphabetic() || c == '_'
+}
+
+fn is_identifier_continue(c: char) -> bool {
+    c.is_alphabetic() || c.is_ascii_digit() || c == '_'
+}
+
+fn is_identifier(token: &str) -> bool {
+    token.starts_with(is_identifier_start)
+}
+
+fn find_op(token: &str, ops: &[&'static str]) -> Option<&'static str> {
+    ops.iter().copied().find(|op| *op == token)
+}
+
+/// The two-character operator spelled `first` then `second`, if any.
+fn two_char_op(first: char, second: char) -> Option<&'static str> {
+    let ops = MULTIPLICATIVE_OPS.iter().chain(COMPARISON_OPS).chain(SHIFT_OPS);
+    ops.copied().find(|op| {
+        let mut chars = op.chars();
+        chars.next() == Some(first) && chars.next() == Some(second) && chars.next().is_none()
+    })
+}
+
+/// A lexeme and the character offset where it starts in the source.
+#[derive(Debug, Clone)]
+struct Token {
+    text: String,
+    pos: usize,
+}
+
+impl Token {
+    fn new(text: impl Into<String>, pos: usize) -> Self {
+        Token { text: text.into(), pos }
+    }
+
+    fn is_whitespace(&self) -> bool {
+        self.text.starts_with(char::is_whitespace)
+    }
+}
+
+/// How number literals are written: `"plain"` (`1234.56`), `"us"`
+/// (`1,234.56`) or `"eu"` (`1.234,56`). Number tokens are normalized to the
+/// plain spelling.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+enum NumberFormat {
+    #[default]
+    Plain,
+    Us,
+    Eu,
+}
+
+const NUMBER_FORMATS: &[(&str, NumberFormat)] =
+    &[("plain", NumberFormat::Plain), ("us", NumberFormat::Us), ("eu", NumberFormat::Eu)];
+
+/// The value `name` picks from a table of named choices for `option`, or a
+/// `ValueError` listing the accepted names.
+fn choice<T: Copy>(option: &str, name: &str, table: &[(&str, T)]) -> PyResult<T> {
+    if let Some((_, value)) = table.iter().find(|(n, _)| *n == name) {
+        return Ok(*value);
+    }
+    let quoted: Vec<String> = table.iter().map(|(n, _)| format!("'{}'", n)).collect();
+    let expected = match quoted.split_last() {
+        Some((last, [])) => last.clone(),
+        Some((last, rest)) => format!("{} or {}", rest.join(", "), last),
+        None => String::new(),
+    };
+    Err(PyValueError::new_err(format!("unknown {} '{}'; expected {}", option, name, expected)))
+}
+
+impl NumberFormat {
+    fn parse(name: &str) -> PyResult<Self> {
+        choice("number_format", name, NUMBER_FORMATS)
+    }
+
+    /// The grouping separator and decimal mark, for the localized formats.
+    fn marks(self) -> Option<(char, char)> {
+        match self {
+            NumberFormat::Plain => None,
+            NumberFormat::Us => Some((',', '.')),
+            NumberFormat::Eu => Some(('.', ',')),
+        }
+    }
+}
+
+fn tokenize(expr: &str) -> Vec<Token> {
+    tokenize_with(expr, None, NumberFormat::Plain)
+}
+
+/// Tokenize with `mul_symbol`, if given, read as `*`. When that symbol is
+/// `.`, a dot with a digit on both sides is still a decimal point, so `3.4`
+/// is a number while `(3).(4)` and `x.y` are products.
+///
+/// Under a localized `number_format`, a grouping separator inside a number
+/// is dropped when it has 1 to 3 digits before it (or follows another
+/// group), exactly 3 after it and no decimal mark yet; the `eu` decimal
+/// comma must have a digit on both sides. Elsewhere `,` still separates
+/// arguments, so `f(1,234)` is `f(1234)` in `us` mode but `f(1, 234)` keeps
+/// two arguments, and in `eu` mode `f(1,5)` is `f(1.5)`.
+fn tokenize_with(expr: &str, mul_symbol: Option<char>, number_format: NumberFormat) -> Vec<Token> {
+    let mut tokens = lex_tokens(expr, mul_symbol, number_format);
+    tokens.retain(|token| !token.is_whitespace());
+    tokens
 }
 
-fn tokenize(expr: &str) -> Vec<String> {
+/// `tokenize_with`, keeping each run of whitespace as a token of its own.
+/// Without a `mul_symbol` or localized `number_format` to normalize, the
+/// token texts concatenate back to `expr` exactly.
+fn lex_tokens(expr: &str, mul_symbol: Option<char>, number_format: NumberFormat) -> Vec<Token> {
     let mut tokens = Vec::new();
     let mut current = String::new();
-    for c in expr.chars() {
-        if c.is_whitespace() {
+    let mut start = 0;
+    let mut prev = None;
+    // Whether the number in `current` has had a grouping separator.
+    let mut grouped = false;
+    let dot_decimal = number_format != NumberFormat::Eu;
+    let mut chars = expr.chars().enumerate().peekable();
+    while let Some((i, c)) = chars.next() {
+        let before = prev.replace(c);
+        let in_number = current.starts_with(|d: char| d.is_ascii_digit() || d == '.');
+        if let Some((group, decimal)) = number_format.marks().filter(|_| in_number) {
+            let ahead: Vec<char> = chars.clone().take(4).map(|(_, a)| a).collect();
+            let digits_ahead = ahead.iter().take_while(|a| a.is_ascii_digit()).count();
+            let fraction = current.contains('.');
+            if c == group
+                && !fraction
+                && (grouped || current.len() <= 3)
+                && digits_ahead == 3
+                && before.is_some_and(|b| b.is_ascii_digit())
+            {
+                grouped = true;
+                continue;
+            }
+            if c == decimal && !dot_decimal && !fraction && digits_ahead > 0 {
+                current.push('.');
+                continue;
+            }
+        }
+        if Some(c) == mul_symbol {
+            let after = chars.peek().map(|&(_, next)| next);
+            let decimal_point = c == '.'
+                && before.is_some_and(|b| b.is_ascii_digit())
+                && after.is_some_and(|a| a.is_ascii_digit());
+            if !decimal_point {
+                if !current.is_empty() {
+                    tokens.push(Token::new(std::mem::take(&mut current), start));
+                }
+                tokens.push(Token::new("*", i));
+                continue;
+            }
+        }
+        // A hex literal takes every letter, digit and point up to its end,
+        // and a sign after its `p`, so `parse_hex_float` sees it whole.
+        if current == "0" && (c == 'x' || c == 'X') {
+            current.push(c);
+            while let Some(&(_, next)) = chars.peek() {
+                let sign = (next == '+' || next == '-') && current.ends_with(['p', 'P']);
+                if !(is_identifier_continue(next) || next == '.' || sign) {
+                    break;
+                }
+                current.push(next);
+                chars.next();
+            }
+            prev = current.chars().last();
             continue;
-        } else if c.is_ascii_digit() || c == '.' {
+        }
+        // An exponent, signed or not, belongs to the number before it; one
+        // without digits (`5e`, `5e+`) is kept as a malformed number.
+        if in_number && (c == 'e' || c == 'E') {
             current.push(c);
-        } else {
-            if !current.is_empty() {
-                tokens.push(current.clone());
-                current.clear();
+            if let Some(&(_, sign)) = chars.peek().filter(|&&(_, s)| s == '+' || s == '-') {
+                current.push(sign);
+                chars.next();
+            }
+            while let Some(&(_, digit)) = chars.peek().filter(|(_, d)| d.is_ascii_digit()) {
+                current.push(digit);
+                chars.next();
             }
-            tokens.push(c.to_string());
+            prev = current.chars().last();
+            continue;
+        }
+        let in_identifier = is_identifier(&current);
+        if in_identifier && is_identifier_continue(c) {
+            current.push(c);
+            continue;
+        }
+        if !in_identifier && (c.is_ascii_digit() || c == '.' && dot_decimal) {
+            if current.is_empty() {
+                start = i;
+                grouped = false;
+            }
+            current.push(c);
+            continue;
+        }
+        if !current.is_empty() {
+            tokens.push(Token::new(std::mem::take(&mut current), start));
+        }
+        if is_identifier_start(c) || c.is_ascii_digit() || c == '.' && dot_decimal {
+            start = i;
+            grouped = false;
+            current.push(c);
+        } else if let Some(op) = chars.peek().and_then(|&(_, next)| two_char_op(c, next)) {
+            chars.next();
+            tokens.push(Token::new(op, i));
+        } else if !c.is_whitespace() {
+            tokens.push(Token::new(c, i));
+        } else if let Some(last) = tokens.last_mut().filter(|last| last.is_whitespace()) {
+            last.text.push(c);
+        } else {
+            tokens.push(Token::new(c, i));
         }
     }
     if !current.is_empty() {
-        tokens.push(current);
+        tokens.push(Token::new(current, start));
     }
     tokens
 }
 
-fn parse_expression(tokens: &[String]) -> (Expr, usize) {
-    parse_add_sub(tokens, 0)
+/// The error for a number token with more than one decimal point (`1.2.3`),
+/// no digits (a lone `.`) or an exponent without digits (`5e`, `5e+`). As in
+/// Python, the point may lead or trail the digits, so `.5` is `0.5`, `1+.5`
+/// is `1.5` and `5.` is `5.0`, and the exponent marker may be `e` or `E`
+/// after any mantissa, so `5E3` is `5000.0` and `5e+0` is `5.0`.
+///
+/// A hex literal is malformed unless `parse_hex_float` reads it.
+fn malformed_number(token: &Token) -> Option<String> {
+    if !token.text.starts_with(|c: char| c.is_ascii_digit() || c == '.') {
+        return None;
+    }
+    if is_hex_literal(&token.text) {
+        return parse_hex_float(&token.text)
+            .is_none()
+            .then(|| format!("malformed hex literal '{}' at position {}", token.text, token.pos));
+    }
+    let (mantissa, exponent) = match token.text.split_once(['e', 'E']) {
+        Some((mantissa, exponent)) => (mantissa, Some(exponent)),
+        None => (token.text.as_str(), None),
+    };
+    let exponent_digits = exponent.map(|e| e.trim_start_matches(['+', '-']));
+    if exponent_digits.is_some_and(|digits| digits.is_empty()) {
+        Some(format!(
+            "malformed number '{}' (the exponent has no digits) at position {}",
+            token.text, token.pos
+        ))
+    } else if mantissa.matches('.').count() > 1
+        || exponent_digits.is_some_and(|digits| !digits.bytes().all(|b| b.is_ascii_digit()))
+    {
+        Some(format!("malformed number '{}' at position {}", token.text, token.pos))
+    } else if !mantissa.contains(|c: char| c.is_ascii_digit()) {
+        Some(format!("number '{}' has no digits at position {}", token.text, token.pos))
+    } else {
+        None
+    }
 }
 
-fn parse_add_sub(tokens: &[String], mut pos: usize) -> (Expr, usize) {
-    let (mut node, mut p) = parse_mul_div(tokens, pos);
-    pos = p;
-    while pos < tokens.len() {
-        let op = tokens[pos].chars().next().unwrap();
-        if op == '+' || op == '-' {
-            let (rhs, np) = parse_mul_div(tokens, pos + 1);
-            node = Expr::Binary { op, left: Box::new(node), right: Box::new(rhs) };
-            pos = np;
-        } else {
-            break;
+fn is_hex_literal(text: &str) -> bool {
+    text.starts_with("0x") || text.starts_with("0X")
+}
+
+/// Read a C99-style hex literal: `0x`, hex digits with at most one point
+/// and at least one digit, then an optional `p` and decimal power of two,
+/// so `0x1.8p3` is `12.0`, `0x1p-1` is `0.5` and `0xff` is `255.0`. As in
+/// Python's `float.fromhex`, the exponent may be left off a fraction (`0x1.8`
+/// is `1.5`). The digits must fit in 64 bits; the value is then rounded
+/// once to the nearest `f64`, or overflows to `inf`.
+fn parse_hex_float(text: &str) -> Option<f64> {
+    let body = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X"))?;
+    let (mantissa, exponent) = match body.split_once(['p', 'P']) {
+        Some((mantissa, exponent)) => (mantissa, exponent.parse::<i32>().ok()?),
+        None => (body, 0),
+    };
+    let (whole, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+    let digits: Vec<u32> =
+        whole.chars().chain(fraction.chars()).map(|c| c.to_digit(16)).collect::<Option<_>>()?;
+    if digits.is_empty() {
+        return None;
+    }
+    let bits = digits.iter().try_fold(0u64, |bits, &digit| {
+        (bits >> 60 == 0).then(|| bits << 4 | u64::from(digit))
+    })?;
+    // Scale by halves so an intermediate stays normal and only the last
+    // step can round into the subnormal range.
+    let mut power = i64::from(exponent) - 4 * fraction.len() as i64;
+    let mut value = bits as f64;
+    while power > 1000 && value.is_finite() {
+        value *= 2f64.powi(1000);
+        power -= 1000;
+    }
+    while power < -1000 && value != 0.0 {
+        value *= 2f64.powi(-1000);
+        power += 1000;
+    }
+    Some(value * 2f64.powi(power.clamp(-1100, 1100) as i32))
+}
+
+/// The value of a number literal, decimal or hex.
+fn parse_number(text: &str) -> Option<f64> {
+    if is_hex_literal(text) {
+        parse_hex_float(text)
+    } else {
+        text.parse().ok()
+    }
+}
+
+/// Check parenthesis balance up front so mismatches get a targeted message
+/// rather than surfacing as a trailing token or an out-of-range index.
+fn check_parens(source: &str) -> Result<(), String> {
+    let mut depth = 0usize;
+    for (i, c) in source.chars().enumerate() {
+        match c {
+            '(' => depth += 1,
+            ')' if depth == 0 => return Err(format!("unmatched ')' at position {}", i)),
+            ')' => depth -= 1,
+            _ => {}
         }
     }
-    (node, pos)
+    if depth > 0 {
+        return Err(format!("missing ')' — expected {} more", depth));
+    }
+    Ok(())
 }
 
-fn parse_mul_div(tokens: &[String], mut pos: usize) -> (Expr, usize) {
-    let (mut node, mut p) = parse_unary(tokens, pos);
-    pos = p;
-    while pos < tokens.len() {
-        let op = tokens[pos].chars().next().unwrap();
-        if op == '*' || op == '/' {
-            let (rhs, np) = parse_unary(tokens, pos + 1);
-            node = Expr::Binary { op, left: Box::new(node), right: Box::new(rhs) };
-            pos = np;
-        } else {
+/// Fast path for a flat chain of literals joined by operators from a single
+/// precedence level (e.g. `1+2-3+...`). Folding left to right matches what
+/// `simplify` does to the equivalent left-leaning tree, so the result is the
+/// same, but no tree is built. Returns `None` for anything else.
+fn fold_constant_chain(tokens: &[Token]) -> Option<f64> {
+    if tokens.len().is_multiple_of(2) || is_identifier(&tokens[0].text) {
+        return None;
+    }
+    let mut total = parse_number(&tokens[0].text)?;
+    let level = if find_op(&tokens.get(1)?.text, ADDITIVE_OPS).is_some() {
+        ADDITIVE_OPS
+    } else {
+        MULTIPLICATIVE_OPS
+    };
+    for pair in tokens[1..].chunks(2) {
+        let op = find_op(&pair[0].text, level)?;
+        if is_identifier(&pair[1].text) {
+            return None;
+        }
+        total = apply_binary(op, total, parse_number(&pair[1].text)?);
+    }
+    Some(total)
+}
+
+/// How an infix operator groups with a neighbour of the same binding power.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Assoc {
+    Left,
+    Right,
+    /// Comparisons: `a < b < c` is `(a < b) and (b < c)`.
+    Chain,
+}
+
+impl Assoc {
+    fn name(self) -> &'static str {
+        match self {
+            Assoc::Left => "left",
+            Assoc::Right => "right",
+            Assoc::Chain => "chain",
+        }
+    }
+}
+
+/// One row of the infix binding-power table; higher powers bind tighter.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct InfixOp {
+    op: &'static str,
+    power: u16,
+    assoc: Assoc,
+}
+
+const AND_POWER: u16 = 1;
+const COMPARISON_POWER: u16 = 2;
+/// Arithmetic levels are offset by this, so every arithmetic operator binds
+/// tighter than the comparisons and `and` whatever its level.
+const ARITHMETIC_BASE: u16 = COMPARISON_POWER;
+
+/// The infix binding-power table the parser runs on. Arithmetic operators
+/// have levels that may be overridden: the defaults are `<< >>` at 5, `+ -`
+/// at 10, `* / //` at 20 and `^` at 30, spaced so an override can slot an
+/// operator between two levels. Comparisons and `and` have fixed powers below every
+/// arithmetic level.
+#[derive(Debug, Clone, PartialEq)]
+struct Precedence {
+    infix: Vec<InfixOp>,
+    /// Give each sign its own node rather than collapsing a run of them; see
+    /// `parse_prefix`.
+    keep_signs: bool,
+}
+
+impl Default for Precedence {
+    fn default() -> Self {
+        let groups: [(&[&'static str], u16, Assoc); 6] = [
+            (AND_OPS, AND_POWER, Assoc::Left),
+            (COMPARISON_OPS, COMPARISON_POWER, Assoc::Chain),
+            (SHIFT_OPS, ARITHMETIC_BASE + 5, Assoc::Left),
+            (ADDITIVE_OPS, ARITHMETIC_BASE + 10, Assoc::Left),
+            (MULTIPLICATIVE_OPS, ARITHMETIC_BASE + 20, Assoc::Left),
+            (POWER_OPS, ARITHMETIC_BASE + 30, Assoc::Right),
+        ];
+        let infix = groups.iter().flat_map(|&(ops, power, assoc)| {
+            ops.iter().map(move |&op| InfixOp { op, power, assoc })
+        });
+        Precedence { infix: infix.collect(), keep_signs: false }
+    }
+}
+
+impl Precedence {
+    fn from_dict(dict: &Bound<'_, PyDict>) -> PyResult<Self> {
+        let mut levels = Vec::with_capacity(dict.len());
+        for (key, value) in dict.iter() {
+            levels.push((key.extract::<String>()?, value.extract::<u8>()?));
+        }
+        Precedence::with_levels(&levels).map_err(PyValueError::new_err)
+    }
+
+    /// Apply `(operator, level)` overrides to the arithmetic defaults. Levels
+    /// must be positive, and operators sharing a level must also share
+    /// associativity (`^` groups right, the rest left), since a level has to
+    /// group one way.
+    fn with_levels(levels: &[(String, u8)]) -> Result<Self, String> {
+        let mut precedence = Precedence::default();
+        for (key, level) in levels {
+            let mut arithmetic = precedence.infix.iter_mut().filter(|e| e.power > ARITHMETIC_BASE);
+            let Some(entry) = arithmetic.find(|e| e.op == key) else {
+                let known: Vec<&str> = Precedence::default()
+                    .infix
+                    .iter()
+                    .filter(|e| e.power > ARITHMETIC_BASE)
+                    .map(|e| e.op)
+                    .collect();
+                return Err(format!(
+                    "unknown operator '{}' in precedence; expected one of {}",
+                    key,
+                    known.join(", ")
+                ));
+            };
+            if *level == 0 {
+                return Err(format!("precedence level for '{}' must be at least 1", key));
+            }
+            entry.power = ARITHMETIC_BASE + u16::from(*level);
+        }
+        for (i, a) in precedence.infix.iter().enumerate() {
+            for b in &precedence.infix[i + 1..] {
+                if a.power == b.power && a.assoc != b.assoc {
+                    return Err(format!(
+                        "'{}' and '{}' share precedence level {} but associate differently",
+                        a.op,
+                        b.op,
+                        a.power - ARITHMETIC_BASE
+                    ));
+                }
+            }
+        }
+        Ok(precedence)
+    }
+
+    fn infix(&self, token: &str) -> Option<InfixOp> {
+        self.infix.iter().copied().find(|e| e.op == token)
+    }
+
+    /// A prefix operator binds tighter than every left-associative operator,
+    /// so its operand takes in only right-associative ones (`-2^2` is
+    /// `-(2^2)`, but `-a*b` is `(-a)*b`).
+    fn prefix_power(&self) -> u16 {
+        let grouping = self.infix.iter().filter(|e| e.assoc != Assoc::Right);
+        grouping.map(|e| e.power).max().unwrap_or(0) + 1
+    }
+}
+
+fn is_right_assoc(op: &str) -> bool {
+    POWER_OPS.contains(&op)
+}
+
+/// Deepest nesting of call parentheses (those right after an identifier).
+fn call_depth(tokens: &[Token]) -> usize {
+    let mut open: Vec<bool> = Vec::new();
+    let (mut depth, mut max) = (0usize, 0usize);
+    for (i, token) in tokens.iter().enumerate() {
+        match token.text.as_str() {
+            "(" => {
+                let is_call = i > 0 && is_identifier(&tokens[i - 1].text);
+                open.push(is_call);
+                if is_call {
+                    depth += 1;
+                    max = max.max(depth);
+                }
+            }
+            ")" if open.pop() == Some(true) => depth -= 1,
+            _ => {}
+        }
+    }
+    max
+}
+
+/// The deepest `nesting_depth` the parser accepts. Each level is a few
+/// stack frames of the recursive parser, and later passes recurse on the
+/// tree as deeply, so this keeps the whole pipeline well inside the stack
+/// of a Python thread, even in a debug build.
+const MAX_NESTING: usize = 1000;
+
+/// How deeply the parser recurses on `tokens`: each open parenthesis, plus
+/// within it each sign and right-associative operand still open, so
+/// `-(2^-(x))` is 5 deep. A run of signs is one level, as it collapses,
+/// unless `prec.keep_signs` gives each its own node. Like `call_depth` it is
+/// counted on the tokens, so it can be checked before parsing.
+fn nesting_depth(tokens: &[Token], prec: &Precedence) -> usize {
+    // Signs and right operands open at the innermost parenthesis, and at
+    // each enclosing one.
+    let (mut open, mut enclosing) = (0usize, Vec::new());
+    let (mut depth, mut max) = (0usize, 0usize);
+    let mut after_sign = false;
+    for (i, token) in tokens.iter().enumerate() {
+        let text = token.text.as_str();
+        let starts_operand = i == 0 || {
+            let prev = tokens[i - 1].text.as_str();
+            prev == "(" || prev == "," || prec.infix(prev).is_some()
+        };
+        let sign = starts_operand && find_op(text, UNARY_OPS).is_some();
+        let right_assoc = prec.infix(text).map(|infix| infix.assoc == Assoc::Right);
+        match text {
+            "(" => {
+                enclosing.push(std::mem::take(&mut open));
+                depth += 1;
+            }
+            ")" if !enclosing.is_empty() => {
+                depth -= 1 + open;
+                open = enclosing.pop().unwrap_or(0);
+            }
+            _ if sign && (prec.keep_signs || !after_sign) || right_assoc == Some(true) => {
+                open += 1;
+                depth += 1;
+            }
+            "," => depth -= std::mem::take(&mut open),
+            _ if right_assoc.is_some() && !sign => depth -= std::mem::take(&mut open),
+            _ => {}
+        }
+        after_sign = sign;
+        max = max.max(depth);
+    }
+    max
+}
+
+/// Refuse `tokens` nested past `MAX_NESTING`; see `nesting_depth`.
+fn check_nesting(tokens: &[Token], prec: &Precedence) -> Result<(), String> {
+    let depth = nesting_depth(tokens, prec);
+    if depth > MAX_NESTING {
+        return Err(format!(
+            "input nests {} levels deep, above the limit of {}",
+            depth, MAX_NESTING
+        ));
+    }
+    Ok(())
+}
+
+/// Parser results carry the position just past the parsed node. Each node
+/// the parser builds also pushes its `Span` onto the `spans` it is given, in
+/// the order `post_order` visits the finished tree.
+type ParseResult = Result<(Expr, usize), String>;
+
+/// Character range `start..end` of a node in the source. A parenthesized
+/// node's span doesn't include its parentheses.
+type Span = (usize, usize);
+
+/// The span of `tokens[first..end]`.
+fn token_span(tokens: &[Token], first: usize, end: usize) -> Span {
+    let last = &tokens[end - 1];
+    (tokens[first].pos, last.pos + last.text.chars().count())
+}
+
+fn parse_expression(tokens: &[Token], prec: &Precedence, spans: &mut Vec<Span>) -> ParseResult {
+    parse_bp(tokens, 0, 0, prec, spans)
+}
+
+/// Pratt parser: read a prefix expression, then keep absorbing infix
+/// operators that bind with at least `min_power`. A left-associative
+/// operator parses its right side one power up, so equal powers group to the
+/// left; a right-associative one stays at its own power.
+///
+/// Comparisons chain like Python's: `a < b < c` means `(a < b) and (b < c)`,
+/// with `b` duplicated into both comparisons, rather than the left-associative
+/// `(a < b) < c`, which would compare the `0`/`1` result of `a < b` with `c`.
+/// Write the parentheses explicitly to get that reading.
+fn parse_bp(
+    tokens: &[Token],
+    pos: usize,
+    min_power: u16,
+    prec: &Precedence,
+    spans: &mut Vec<Span>,
+) -> ParseResult {
+    let start = pos;
+    let (mut lhs, mut pos) = parse_prefix(tokens, pos, prec, spans)?;
+    // Right operand of the previous comparison, and its spans, while a chain
+    // is open.
+    let mut chain_tail: Option<(Expr, Vec<Span>)> = None;
+    loop {
+        if is_postfix_percent(tokens, pos) {
+            let hundred = Box::new(Expr::Number(100.0));
+            lhs = Expr::Binary { op: "/", left: Box::new(lhs), right: hundred };
+            pos += 1;
+            spans.push(token_span(tokens, pos - 1, pos));
+            spans.push(token_span(tokens, start, pos));
+            continue;
+        }
+        let Some(infix) = tokens.get(pos).and_then(|t| prec.infix(&t.text)) else {
             break;
+        };
+        let InfixOp { op, power, assoc } = infix;
+        if power < min_power {
+            break;
+        }
+        let next_power = if assoc == Assoc::Right { power } else { power + 1 };
+        let mark = spans.len();
+        let (rhs, np) = parse_bp(tokens, pos + 1, next_power, prec, spans)?;
+        pos = np;
+        let span = token_span(tokens, start, pos);
+        if assoc != Assoc::Chain {
+            chain_tail = None;
+            lhs = Expr::Binary { op, left: Box::new(lhs), right: Box::new(rhs) };
+            spans.push(span);
+            continue;
+        }
+        lhs = extend_chain(lhs, op, rhs, &mut chain_tail, spans, mark, span);
+    }
+    Ok((lhs, pos))
+}
+
+/// Add the comparison `op rhs` to `lhs`, the chain so far, as `parse_bp`
+/// reads it; `rhs`'s spans start at `spans[mark]`. Kept out of `parse_bp`
+/// so its locals don't weigh on every level of the parser's recursion.
+fn extend_chain(
+    lhs: Expr,
+    op: &'static str,
+    rhs: Expr,
+    chain_tail: &mut Option<(Expr, Vec<Span>)>,
+    spans: &mut Vec<Span>,
+    mark: usize,
+    span: Span,
+) -> Expr {
+    let rhs_spans = spans[mark..].to_vec();
+    match chain_tail.replace((rhs.clone(), rhs_spans)) {
+        Some((tail, tail_spans)) => {
+            // The duplicated operand's spans go ahead of `rhs`'s, where a
+            // post-order walk of the new comparison meets them.
+            let tail_start = tail_spans.last().map_or(span.0, |tail| tail.0);
+            spans.splice(mark..mark, tail_spans);
+            spans.push((tail_start, span.1));
+            spans.push(span);
+            Expr::Binary {
+                op: AND_OPS[0],
+                left: Box::new(lhs),
+                right: Box::new(Expr::Binary { op, left: Box::new(tail), right: Box::new(rhs) }),
+            }
+        }
+        None => {
+            spans.push(span);
+            Expr::Binary { op, left: Box::new(lhs), right: Box::new(rhs) }
         }
     }
-    (node, pos)
 }
 
-fn parse_unary(tokens: &[String], mut pos: usize) -> (Expr, usize) {
-    if pos < tokens.len() {
-        let op = tokens[pos].chars().next().unwrap();
-        if op == '+' || op == '-' {
-            let (expr, p) = parse_unary(tokens, pos + 1);
-            return (Expr::Unary { op, expr: Box::new(expr) }, p);
+/// `%` is a binary modulo when an operand follows it (`7 % 3`) and a postfix
+/// percent otherwise: at the end, or before `)`, `,` or an operator, so
+/// `50% + 10` adds 10 to 0.5. A percent binds tighter than any operator and
+/// takes the operand just before it (`2^50%` is `2^(50%)`), and `x%` is
+/// parsed as `x / 100`. A sign starts the operand only when it is spaced
+/// as one, apart from the `%` and against what follows it: `7 % -3` is the
+/// modulo, -2 as `%` takes the divisor's sign, while `50% - 3` and `7%-3`
+/// are a percent then a subtraction.
+fn is_postfix_percent(tokens: &[Token], pos: usize) -> bool {
+    let Some(percent) = tokens.get(pos).filter(|t| t.text == "%") else {
+        return false;
+    };
+    let operand_at = |at: usize| {
+        tokens.get(at).is_some_and(|next| {
+            next.text == "("
+                || next.text.starts_with(|c: char| c.is_ascii_digit() || c == '.')
+                || is_identifier(&next.text) && !WORD_OPS.contains(&next.text.as_str())
+        })
+    };
+    let signed_operand = match (tokens.get(pos + 1), tokens.get(pos + 2)) {
+        (Some(sign), Some(next)) => {
+            find_op(&sign.text, UNARY_OPS).is_some()
+                && sign.pos > percent.pos + 1
+                && next.pos == sign.pos + 1
+                && operand_at(pos + 2)
+        }
+        _ => false,
+    };
+    !operand_at(pos + 1) && !signed_operand
+}
+
+/// Every operand is parsed through here, so running out of tokens is reported
+/// against the operator that wanted one (e.g. the `+` in `1+`).
+///
+/// The only operators that may directly follow another are the signs, which
+/// start the operand: `1 + -2`, `2 ^ -1` and `1 ++ 2` (`1 + (+2)`) are legal.
+/// A run of signs collapses to at most one `-`, unless `keep_signs` is set.
+/// Any other operator there, as in `3 */ 4` or `x and and y`, is an error
+/// naming both operators.
+fn parse_prefix(
+    tokens: &[Token],
+    pos: usize,
+    prec: &Precedence,
+    spans: &mut Vec<Span>,
+) -> ParseResult {
+    let Some(token) = tokens.get(pos) else {
+        return Err(match pos.checked_sub(1).and_then(|p| tokens.get(p)) {
+            Some(prev) => format!(
+                "expected expression after '{}' at position {}",
+                prev.text, prev.pos
+            ),
+            None => "expected expression".to_string(),
+        });
+    };
+    let is_operator = |text: &str| prec.infix(text).is_some();
+    if find_op(&token.text, UNARY_OPS).is_none() && is_operator(&token.text) {
+        if let Some(prev) = pos.checked_sub(1).map(|p| &tokens[p]) {
+            if is_operator(&prev.text) {
+                return Err(format!(
+                    "operator '{}' cannot follow '{}' at position {}",
+                    token.text, prev.text, token.pos
+                ));
+            }
+        }
+    }
+    if let Some(op) = find_op(&token.text, UNARY_OPS).filter(|_| prec.keep_signs) {
+        let (expr, p) = parse_bp(tokens, pos + 1, prec.prefix_power(), prec, spans)?;
+        spans.push(token_span(tokens, pos, p));
+        return Ok((Expr::Unary { op, expr: Box::new(expr) }, p));
+    }
+    if find_op(&token.text, UNARY_OPS).is_some() {
+        // Collapse a run of signs by parity, so `- - x` is just `x` and
+        // `- + - - x` is a single `-x`; unary plus never reaches the tree.
+        let mut end = pos;
+        let mut negate = false;
+        while let Some(op) = tokens.get(end).and_then(|t| find_op(&t.text, UNARY_OPS)) {
+            negate ^= op == "-";
+            end += 1;
         }
+        let (expr, p) = parse_bp(tokens, end, prec.prefix_power(), prec, spans)?;
+        if negate {
+            spans.push(token_span(tokens, pos, p));
+            return Ok((Expr::Unary { op: "-", expr: Box::new(expr) }, p));
+        }
+        return Ok((expr, p));
     }
-    parse_primary(tokens, pos)
+    parse_primary(tokens, pos, prec, spans)
 }
 
-fn parse_primary(tokens: &[String], pos: usize) -> (Expr, usize) {
+fn parse_primary(
+    tokens: &[Token],
+    pos: usize,
+    prec: &Precedence,
+    spans: &mut Vec<Span>,
+) -> ParseResult {
     let token = &tokens[pos];
-    if token == "(" {
-        let (expr, mut p) = parse_expression(&tokens[pos + 1..]);
+    if token.text == "(" {
+        // `()` is only meaningful as an empty argument list.
+        if tokens.get(pos + 1).map(|t| t.text.as_str()) == Some(")") {
+            return Err(format!("empty parentheses at position {}", token.pos));
+        }
+        let (expr, mut p) = parse_expression(&tokens[pos + 1..], prec, spans)?;
         p += pos + 1;
-        if tokens[p] != ")" {
-            panic!("expected )");
+        if tokens.get(p).map(|t| t.text.as_str()) != Some(")") {
+            return Err("expected )".to_string());
         }
-        return (expr, p + 1);
+        return Ok((expr, p + 1));
+    }
+    if is_identifier(&token.text) && !WORD_OPS.contains(&token.text.as_str()) {
+        if tokens.get(pos + 1).map(|t| t.text.as_str()) == Some("(") {
+            if token.text == PIECEWISE {
+                return parse_piecewise(tokens, pos, prec, spans);
+            }
+            return parse_call(tokens, pos, prec, spans);
+        }
+        spans.push(token_span(tokens, pos, pos + 1));
+        return Ok((Expr::Variable(token.text.clone()), pos + 1));
+    }
+    if let Some(message) = malformed_number(token) {
+        return Err(message);
     }
-    let value: f64 = token.parse().unwrap();
-    (Expr::Number(value), pos + 1)
+    let value = parse_number(&token.text)
+        .ok_or_else(|| format!("unexpected token '{}' at position {}", token.text, token.pos))?;
+    spans.push(token_span(tokens, pos, pos + 1));
+    Ok((Expr::Number(value), pos + 1))
 }
 
-fn simplify(expr: Expr) -> Expr {
-    match expr {
-        Expr::Binary { op, left, right } => {
-            let left = simplify(*left);
-            let right = simplify(*right);
-            if let (Expr::Number(a), Expr::Number(b)) = (&left, &right) {
-                let res = match op {
-                    '+' => a + b,
-                    '-' => a - b,
-                    '*' => a * b,
-                    '/' => a / b,
-                    _ => unreachable!(),
-                };
-                Expr::Number(res)
-            } else {
-                Expr::Binary { op, left: Box::new(left), right: Box::new(right) }
-            }
+/// The name read as `parse_piecewise` sugar rather than as a call.
+const PIECEWISE: &str = "piecewise";
+
+/// Parse `piecewise((cond, value), ..., default)`, starting at the name, as
+/// nested `if`s: `piecewise((c1, v1), (c2, v2), d)` is
+/// `if(c1, v1, if(c2, v2, d))`. It is the first value whose condition is
+/// nonzero, else the default, and folds, evaluates and compiles as those
+/// `if`s do, so it stays symbolic only as far as its conditions do. At least
+/// one pair is required before the default.
+fn parse_piecewise(
+    tokens: &[Token],
+    pos: usize,
+    prec: &Precedence,
+    spans: &mut Vec<Span>,
+) -> ParseResult {
+    let text = |p: usize| tokens.get(p).map(|t| t.text.as_str());
+    let expected = |p: usize| {
+        let at = tokens.get(p).map_or(tokens[pos].pos, |t| t.pos);
+        format!("piecewise expects (condition, value) pairs then a default at position {}", at)
+    };
+    let mut pairs = Vec::new();
+    let mut p = pos + 2;
+    while text(p) == Some("(") {
+        let (start, mark) = (p, spans.len());
+        let (cond, np) = parse_bp(tokens, p + 1, 0, prec, spans)?;
+        if text(np) != Some(",") {
+            // The default, parenthesized: parse it again as a whole.
+            spans.truncate(mark);
+            break;
         }
-        Expr::Unary { op, expr } => {
-            let expr = simplify(*expr);
-            if let Expr::Number(n) = &expr {
-                let res = match op {
-                    '+' => *n,
-                    '-' => -*n,
-                    _ => unreachable!(),
-                };
-                Expr::Number(res)
-            } else {
-                Expr::Unary { op, expr: Box::new(expr) }
+        let (value, np) = parse_bp(tokens, np + 1, 0, prec, spans)?;
+        if text(np) != Some(")") {
+            return Err(expected(np));
+        }
+        if text(np + 1) != Some(",") {
+            return Err(expected(np + 1));
+        }
+        pairs.push((start, cond, value));
+        p = np + 2;
+    }
+    if pairs.is_empty() {
+        return Err(expected(p));
+    }
+    let (mut expr, np) = parse_bp(tokens, p, 0, prec, spans)?;
+    if text(np) != Some(")") {
+        return Err(expected(np));
+    }
+    let end = np + 1;
+    while let Some((start, cond, value)) = pairs.pop() {
+        let start = if pairs.is_empty() { pos } else { start };
+        expr = Expr::Call { name: "if".to_string(), args: vec![cond, value, expr] };
+        spans.push(token_span(tokens, start, end));
+    }
+    Ok((expr, end))
+}
+
+/// Parse `name(arg, ...)` where `tokens[pos]` is the name and the next token
+/// is the opening parenthesis.
+fn parse_call(
+    tokens: &[Token],
+    pos: usize,
+    prec: &Precedence,
+    spans: &mut Vec<Span>,
+) -> ParseResult {
+    let name = &tokens[pos];
+    let mut args = Vec::new();
+    let mut p = pos + 2;
+    if tokens.get(p).map(|t| t.text.as_str()) == Some(")") {
+        p += 1;
+    } else {
+        loop {
+            let (arg, np) = parse_bp(tokens, p, 0, prec, spans)?;
+            args.push(arg);
+            match tokens.get(np).map(|t| t.text.as_str()) {
+                Some(",") => p = np + 1,
+                Some(")") => {
+                    p = np + 1;
+                    break;
+                }
+                _ => return Err(format!("expected ',' or ')' in call to '{}'", name.text)),
             }
         }
-        other => other,
     }
+    check_arity(&name.text, args.len(), name.pos)?;
+    spans.push(token_span(tokens, pos, p));
+    Ok((Expr::Call { name: name.text.clone(), args }, p))
 }
 
-fn to_py<'p>(py: Python<'p>, expr: Expr) -> PyObject {
-    match expr {
-        Expr::Number(n) => {
-            let dict = PyDict::new(py);
-            dict.set_item("type", "Number").unwrap();
-            dict.set_item("value", n).unwrap();
-            dict.into()
+/// Reject a builtin call with the wrong number of arguments; other names
+/// take any number.
+fn check_arity(name: &str, argc: usize, pos: usize) -> Result<(), String> {
+    match FUNCTIONS.iter().find(|(n, _, _)| *n == name) {
+        Some(&(_, min, max)) => match arity_error(name, min, max, argc) {
+            Some(message) => Err(format!("{} at position {}", message, pos)),
+            None => Ok(()),
+        },
+        None => Ok(()),
+    }
+}
+
+/// The message for `argc` arguments to `name`, which takes `min` to `max`
+/// (`usize::MAX` for no limit), or None if that count is fine.
+fn arity_error(name: &str, min: usize, max: usize, argc: usize) -> Option<String> {
+    if (min..=max).contains(&argc) {
+        return None;
+    }
+    let expected = if min == max {
+        format!("{}", min)
+    } else if max == usize::MAX {
+        format!("at least {}", min)
+    } else {
+        format!("{} to {}", min, max)
+    };
+    Some(format!("{} expects {} argument(s), got {}", name, expected, argc))
+}
+
+/// Settings that change how builtins fold.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FoldOptions {
+    /// Round halves to even (`round(2.5) == 2`) instead of the default of
+    /// rounding halves away from zero (`round(2.5) == 3`, `round(-2.5) == -3`).
+    banker_rounding: bool,
+    /// Trig functions take their argument, and inverse trig functions give
+    /// their result, in degrees rather than radians.
+    degrees: bool,
+    /// Merge like terms of `+`/`-` chains; see `LIKE_TERMS`.
+    like_terms: bool,
+    /// Multiply constant factors through sums; see `distribute`.
+    distribute: bool,
+}
+
+/// Evaluate a builtin on numeric arguments; an error for names that are not
+/// builtins, for arguments `gcd` and `lcm` reject and outside the domains of
+/// `asin`, `acos`, `acosh` and `atanh`. Arity was validated when the call was parsed.
+fn apply_function(name: &str, args: &[f64], fold: &FoldOptions) -> Result<f64, String> {
+    let round = |x: f64| if fold.banker_rounding { x.round_ties_even() } else { x.round() };
+    let angle = |x: f64| if fold.degrees { x.to_radians() } else { x };
+    let from_angle = |x: f64| if fold.degrees { x.to_degrees() } else { x };
+    Ok(match (name, args) {
+        ("floor", [x]) => x.floor(),
+        ("ceil", [x]) => x.ceil(),
+        ("trunc", [x]) => x.trunc(),
+        ("sin", [x]) => angle(*x).sin(),
+        ("cos", [x]) => angle(*x).cos(),
+        ("tan", [x]) => angle(*x).tan(),
+        ("asin", [x]) if x.abs() <= 1.0 => from_angle(x.asin()),
+        ("acos", [x]) if x.abs() <= 1.0 => from_angle(x.acos()),
+        ("asin" | "acos", [x]) => return Err(format!("{} domain error: |{}| > 1", name, x)),
+        ("atan", [x]) => from_angle(x.atan()),
+        ("atan2", [y, x]) => from_angle(y.atan2(*x)),
+        ("sinh", [x]) => x.sinh(),
+        ("cosh", [x]) => x.cosh(),
+        ("tanh", [x]) => x.tanh(),
+        ("asinh", [x]) => x.asinh(),
+        ("acosh", [x]) if *x >= 1.0 => x.acosh(),
+        ("acosh", [x]) => return Err(format!("acosh domain error: {} < 1", x)),
+        ("atanh", [x]) if x.abs() < 1.0 => x.atanh(),
+        ("atanh", [x]) => return Err(format!("atanh domain error: |{}| >= 1", x)),
+        ("round", [x]) => round(*x),
+        // `round(x, n)` keeps `n` decimal places; a negative `n` rounds to tens,
+        // hundreds, ...
+        ("round", [x, digits]) => {
+            let scale = 10f64.powi(digits.trunc() as i32);
+            round(x * scale) / scale
         }
-        Expr::Unary { op, expr } => {
-            let dict = PyDict::new(py);
-            dict.set_item("type", "Unary").unwrap();
-            dict.set_item("op", op.to_string()).unwrap();
-            dict.set_item("expr", to_py(py, *expr)).unwrap();
-            dict.into()
+        ("max", _) => args.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+        ("min", _) => args.iter().copied().fold(f64::INFINITY, f64::min),
+        ("gcd" | "lcm", _) => return gcd_lcm(name, args),
+        // `approx(a, b, tol)` compares with an absolute tolerance.
+        ("approx", [a, b, tol]) => truth((a - b).abs() <= *tol),
+        ("if", [cond, then, otherwise]) => {
+            if *cond != 0.0 {
+                *then
+            } else {
+                *otherwise
+            }
         }
-        Expr::Binary { op, left, right } => {
-            let dict = PyDict::new(py);
-            dict.set_item("type", "Binary").unwrap();
-            dict.set_item("op", op.to_string()).unwrap();
-            dict.set_item("left", to_py(py, *left)).unwrap();
-            dict.set_item("right", to_py(py, *right)).unwrap();
-            dict.into()
+        _ => return Err(format!("unknown function '{}'", name)),
+    })
+}
+
+/// `x` as an `i64` if it is integral and in range.
+fn as_i64(x: f64) -> Option<i64> {
+    (x.fract() == 0.0 && x.abs() < I64_BOUND).then_some(x as i64)
+}
+
+/// Fold `gcd` or `lcm` over 64-bit integer arguments with the Euclidean
+/// algorithm. Results are nonnegative, `gcd(0, 0)` and any `lcm` with a zero
+/// argument are 0, and an `lcm` beyond the `i64` range is an error.
+fn gcd_lcm(name: &str, args: &[f64]) -> Result<f64, String> {
+    fn gcd(mut a: u64, mut b: u64) -> u64 {
+        while b != 0 {
+            (a, b) = (b, a % b);
         }
+        a
     }
+    let mut acc: Option<u64> = None;
+    for &arg in args {
+        let value = as_i64(arg)
+            .ok_or_else(|| format!("{} arguments must be 64-bit integers, got {}", name, arg))?
+            .unsigned_abs();
+        acc = Some(match acc {
+            None => value,
+            Some(acc) if name == "gcd" => gcd(acc, value),
+            Some(0) => 0,
+            Some(_) if value == 0 => 0,
+            Some(acc) => (acc / gcd(acc, value))
+                .checked_mul(value)
+                .filter(|&lcm| lcm <= i64::MAX as u64)
+                .ok_or_else(|| {
+                    let args: Vec<String> = args.iter().map(f64::to_string).collect();
+                    format!("lcm({}) overflows a 64-bit integer", args.join(", "))
+                })?,
+        });
+    }
+    Ok(acc.unwrap_or(0) as f64)
 }
 
-#[pyfunction]
-fn parse_infix(expression: &str) -> PyObject {
-    let tokens = tokenize(expression);
-    let (expr, pos) = parse_expression(&tokens);
-    if pos != tokens.len() {
-        panic!("unexpected tokens at end");
+fn truth(value: bool) -> f64 {
+    if value {
+        1.0
+    } else {
+        0.0
     }
-    let simplified = simplify(expr);
-    Python::with_gil(|py| to_py(py, simplified))
 }
 
-#[pymodule]
-fn infix_parser(_py: Python, m: &PyModule) -> PyResult<()> {
-    m.add_function(wrap_pyfunction!(parse_infix, m)?)?;
-    Ok(())
+/// Shift `a` by `b` bits as 64-bit integers. Both must be integral, the
+/// amount must be in `0..=63`, and a left shift must not carry bits out of
+/// the `i64` range; anything else is an error rather than a wrapped or
+/// truncated result. `>>` is an arithmetic shift, so `-5 >> 1` is `-3`.
+fn apply_shift(op: &str, a: f64, b: f64) -> Result<f64, String> {
+    let integer = |operand: f64| {
+        as_i64(operand)
+            .ok_or_else(|| format!("shift operands must be 64-bit integers, got {}", operand))
+    };
+    shift_i64(op, integer(a)?, integer(b)?).map(|shifted| shifted as f64)
+}
+
+/// The checked shift behind `apply_shift`, on operands already known to be
+/// integers.
+fn shift_i64(op: &str, value: i64, amount: i64) -> Result<i64, String> {
+    if !(0..64).contains(&amount) {
+        return Err(format!(
+            "shift amount {} is out of range for a 64-bit integer (expected 0 to 63)",
+            amount
+        ));
+    }
+    if op == ">>" {
+        return Ok(value >> amount);
+    }
+    let shifted = value << amount;
+    if shifted >> amount != value {
+        return Err(format!("{} << {} overflows a 64-bit integer", value, amount));
+    }
+    Ok(shifted)
+}
+
+/// `apply_binary`, plus the operators that can fail on constant operands.
+fn try_apply_binary(op: &str, a: f64, b: f64) -> Result<f64, String> {
+    if SHIFT_OPS.contains(&op) {
+        apply_shift(op, a, b)
+    } else {
+        Ok(apply_binary(op, a, b))
+    }
 }
+
+fn apply_binary(op: &str, a: f64, b: f64) -> f64 {
+    match op {
+        "and" => truth(a != 0.0 && b != 0.0),
+        "<" => truth(a < b),
+        "<=" => truth(a <= b),
+        ">" => truth(a > b),
+        ">=" => truth(a >= b),
+        "==" => truth(a == b),
+        "!=" => truth(a != b),
+        "+" => a + b,
+        "-" => a - b,
+        "*" => a * b,
+        "/" => a / b,
+        "//" | "div" => (a / b).floor(),
+        // Python's modulo: the result takes the sign of the divisor.
+        "%" | "mod" => {
+            let r = a % b;
+            if r != 0.0 && (r < 0.0) != (b < 0.0) {
+                r + b
+            } else {
+                r
+            }
+        }
+        "rem" => a % b,
+        "^" => a.powf(b),
+        _ => unreachable!(),
+    }
+}
+
+/// Cancel structurally identical symbolic operands: `x - x` and `x + (-x)`
+/// become `0`, and `x / x` becomes `1`. The division rule assumes `x != 0`;
+/// for `x = 0` the unsimplified form would have produced `NaN`.
+fn cancel(op: &str, left: &Expr, right: &Expr) -> Option<Expr> {
+    match op {
+        "-" if left == right => Some(Expr::Number(0.0)),
+        "/" if left == right => Some(Expr::Number(1.0)),
+        "+" => match (left, right) {
+            (other, Expr::Unary { op: "-", expr }) | (Expr::Unary { op: "-", expr }, other)
+                if **expr == *other =>
+            {
+                Some(Expr::Number(0.0))
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// A constant times a sum or difference, on either side, as the sum of the
+/// products with the constant first: `2*(x+3)` and `(x+3)*2` become
+/// `2*x + 2*3`, which the caller folds to `2*x + 6`. A term that already
+/// has a constant factor takes the product, so `2*(3*y)` gives `6*y`.
+/// Applied bottom-up this reaches nested groups: `2*(x + 3*(y+1))` ends as
+/// `2*x + (6*y + 6)`.
+fn distribute(op: &str, left: &Expr, right: &Expr) -> Option<Expr> {
+    let (c, sum) = match (op, left, right) {
+        ("*", Expr::Number(c), sum) | ("*", sum, Expr::Number(c)) => (*c, sum),
+        _ => return None,
+    };
+    let Expr::Binary { op: sum_op @ ("+" | "-"), left, right } = sum else {
+        return None;
+    };
+    let times = |term: &Expr| match term {
+        Expr::Binary { op: "*", left, right } => match (&**left, &**right) {
+            (Expr::Number(k), rest) | (rest, Expr::Number(k)) => {
+                binary("*", Expr::Number(c * k), rest.clone())
+            }
+            _ => binary("*", Expr::Number(c), term.clone()),
+        },
+        _ => binary("*", Expr::Number(c), term.clone()),
+    };
+    Some(binary(sum_op, times(left), times(right)))
+}
+
+/// Powers with a trivial symbolic side: `x^0` and `1^x` become `1`, and
+/// `x^1` becomes `x`. These hold for every `f64`, including `NaN` and the
+/// infinities, and agree with the constant folds, which follow `powf`:
+/// `0^0` is `1`, `0^n` is `0` for `n > 0`, and `0` to a negative power is
+/// `inf`. `0^x` with symbolic `x` is left alone, since its value depends on
+/// the sign of `x`.
+fn power_identity(op: &str, left: &Expr, right: &Expr) -> Option<Expr> {
+    match (op, left, right) {
+        ("^", _, Expr::Number(n)) if *n == 0.0 => Some(Expr::Number(1.0)),
+        ("^", Expr::Number(n), _) if *n == 1.0 => Some(Expr::Number(1.0)),
+        ("^", base, Expr::Number(n)) if *n == 1.0 => Some(base.clone()),
+        _ => None,
+    }
+}
+
+/// A factor of `1`: `1*x`, `x*1` and `x/1` become `x`, which holds for every
+/// `f64`. Comparisons fold to `1` or `0`, so this is what lets a selector such
+/// as `(3 > 0) * x` reduce to `x`. A `0` factor is kept, as `0 * x` is `nan`
+/// for an infinite `x`.
+fn unit_factor(op: &str, left: &Expr, right: &Expr) -> Option<Expr> {
+    match (op, left, right) {
+        ("*", Expr::Number(n), other) | ("*" | "/", other, Expr::Number(n)) if *n == 1.0 => {
+            Some(other.clone())
+        }
+        _ => None,
+    }
+}
+
+/// How `merge_chain` combines the operands of a two-operator chain, such as
+/// `*`/`/` or `+`/`-`, that share a part.
+struct ChainRule {
+    /// The chain's operator and its inverse.
+    ops: [&'static str; 2],
+    /// The part compared across the chain and its constant weight, or None
+    /// for an operand that never merges.
+    split: fn(&Expr) -> Option<(&Expr, f64)>,
+    /// An operand from its part and a weight other than 0.
+    join: fn(&Expr, f64) -> Expr,
+    /// The value of a chain whose operands all cancelled.
+    empty: f64,
+    /// How an operand on the inverse side is written when it leads the chain.
+    lead: fn(Expr) -> Expr,
+}
+
+/// `x^2 * x^3` is `x^5`: the part is the base and the weight the exponent.
+/// `x^3` is `(x, 3)` and any other factor, including `x^y`, is itself to
+/// the first power. Numbers were already folded with each other.
+const POWERS: ChainRule = ChainRule {
+    ops: ["*", "/"],
+    split: |expr| match expr {
+        Expr::Number(_) => None,
+        Expr::Binary { op: "^", left, right } => match &**right {
+            Expr::Number(n) => Some((left, *n)),
+            _ => Some((expr, 1.0)),
+        },
+        other => Some((other, 1.0)),
+    },
+    join: |base, exponent| {
+        if exponent == 1.0 {
+            base.clone()
+        } else {
+            Expr::Binary {
+                op: "^",
+                left: Box::new(base.clone()),
+                right: Box::new(Expr::Number(exponent)),
+            }
+        }
+    },
+    empty: 1.0,
+    lead: |factor| Expr::Binary {
+        op: "/",
+        left: Box::new(Expr::Number(1.0)),
+        right: Box::new(factor),
+    },
+};
+
+/// `3*x + 2*x` is `5*x`: the part is the symbolic factor and the weight its
+/// coefficient. `x` has coefficient 1, `3*x` and `x*3` have 3 and `-x` has
+/// -1; numbers are constant terms and never merge. A merged coefficient of
+/// -1 is written as a negation.
+const LIKE_TERMS: ChainRule = ChainRule {
+    ops: ["+", "-"],
+    split: |expr| match expr {
+        Expr::Number(_) => None,
+        Expr::Binary { op: "*", left, right } => match (&**left, &**right) {
+            (Expr::Number(n), term) | (term, Expr::Number(n)) => Some((term, *n)),
+            _ => Some((expr, 1.0)),
+        },
+        Expr::Unary { op: "-", expr } => {
+            let (term, coefficient) = (LIKE_TERMS.split)(expr)?;
+            Some((term, -coefficient))
+        }
+        other => Some((other, 1.0)),
+    },
+    join: |term, coefficient| {
+        if coefficient == 1.0 {
+            term.clone()
+        } else if coefficient == -1.0 {
+            Expr::Unary { op: "-", expr: Box::new(term.clone()) }
+        } else {
+            Expr::Binary {
+                op: "*",
+                left: Box::new(Expr::Number(coefficient)),
+                right: Box::new(term.clone()),
+            }
+        }
+    },
+    empty: 0.0,
+    lead: |term| Expr::Unary { op: "-", expr: Box::new(term) },
+};
+
+/// Merge `right` into an operand of the `rule` chain `left` that has a
+/// structurally equal part, summing their weights: with `POWERS`,
+/// `x^2 * y * x^3` becomes `x^5 * y` and `x^a / x^b` becomes `x^(a-b)`.
+/// The merged operand keeps the place and the side of the inverse operator
+/// of its first occurrence, and is dropped when the weights cancel. For
+/// powers that assumes a nonzero base, as does `x / x^3` becoming `x^-2`.
+/// The left chain was merged when it was simplified, so only `right` can
+/// match; None means it didn't and the chain is left as written.
+fn merge_chain(
+    rule: &ChainRule,
+    op: &str,
+    left: &Expr,
+    right: &Expr,
+    fold: &FoldOptions,
+) -> Option<Expr> {
+    let [forward, inverse] = rule.ops;
+    if op != forward && op != inverse {
+        return None;
+    }
+    let (part, weight) = (rule.split)(right)?;
+    let matches = |operand: &Expr| (rule.split)(operand).is_some_and(|(p, _)| p == part);
+    // Look for a match before collecting the chain, so that the common case
+    // of no match allocates nothing.
+    let mut node = left;
+    let found = loop {
+        match node {
+            Expr::Binary { op: o, left, right } if *o == forward || *o == inverse => {
+                if matches(right) {
+                    break true;
+                }
+                node = left;
+            }
+            _ => break matches(node),
+        }
+    };
+    if !found {
+        return None;
+    }
+    let mut operands = Vec::new();
+    let mut node = left;
+    while let Expr::Binary { op: o, left, right } = node {
+        if *o != forward && *o != inverse {
+            break;
+        }
+        operands.push((&**right, *o == inverse));
+        node = left;
+    }
+    operands.push((node, false));
+    operands.reverse();
+    let index = operands.iter().position(|(operand, _)| matches(operand))?;
+    let (first, inverted) = operands[index];
+    let sign = |inverts: bool| if inverts { -1.0 } else { 1.0 };
+    // The summed weight, relative to the side the first occurrence is on.
+    let total = (rule.split)(first)?.1 + sign(inverted) * sign(op == inverse) * weight;
+    let mut merged = (total != 0.0).then(|| (rule.join)(part, total));
+    let mut rebuilt: Option<Expr> = None;
+    for (i, (operand, inverts)) in operands.into_iter().enumerate() {
+        let operand = if i != index {
+            operand.clone()
+        } else if let Some(merged) = merged.take() {
+            merged
+        } else {
+            continue;
+        };
+        // Neighbours that were apart may now fold, so each new node is
+        // simplified as it is built.
+        rebuilt = Some(match rebuilt {
+            None if inverts => simplify_node((rule.lead)(operand), fold),
+            None => operand,
+            Some(acc) => simplify_node(
+                Expr::Binary {
+                    op: if inverts { inverse } else { forward },
+                    left: Box::new(acc),
+                    right: Box::new(operand),
+                },
+                fold,
+            ),
+        });
+    }
+    Some(rebuilt.unwrap_or(Expr::Number(rule.empty)))
+}
+
+/// Normal form for signs: neither `+` nor `-` has a negated or negative right
+/// operand, so `a - -b` becomes `a + b`, and `a + -b` and `a + -2` become
+/// `a - b` and `a - 2`.
+fn absorb_negation(op: &'static str, left: &Expr, right: &Expr) -> Option<Expr> {
+    let flipped = match op {
+        "+" => "-",
+        "-" => "+",
+        _ => return None,
+    };
+    let magnitude = match right {
+        Expr::Unary { op: "-", expr } => (**expr).clone(),
+        Expr::Number(n) if *n < 0.0 => Expr::Number(-n),
+        _ => return None,
+    };
+    Some(Expr::Binary { op: flipped, left: Box::new(left.clone()), right: Box::new(magnitude) })
+}
+
+pub fn simplify(expr: Expr, fold: &FoldOptions) -> Expr {
+    match expr {
+        // A constant condition picks its branch before either is simplified,
+        // so the discarded one is never folded. The condition is simplified
+        // once either way, so nested `if`s cost no more than other calls.
+        Expr::Call { name, mut args } if name == "if" && args.len() == 3 => {
+            let cond = simplify(std::mem::replace(&mut args[0], Expr::Number(0.0)), fold);
+            if let Expr::Number(cond) = cond {
+                return simplify(args.swap_remove(if cond != 0.0 { 1 } else { 2 }), fold);
+            }
+            args[0] = cond;
+            for branch in &mut args[1..] {
+                *branch = simplify(std::mem::replace(branch, Expr::Number(0.0)), fold);
+            }
+            simplify_node(Expr::Call { name, args }, fold)
+        }
+        expr => simplify_node(map_children(expr, &mut |child| simplify(child, fold)), fold),
+    }
+}
+
+/// Apply the folding rules to a single node whose children are already
+/// simplified.
+pub fn simplify_node(expr: Expr, fold: &FoldOptions) -> Expr {
+    match expr {
+        Expr::Call { name, args } => {
+            let values: Option<Vec<f64>> = args
+                .iter()
+                .map(|arg| match arg {
+                    Expr::Number(n) => Some(*n),
+                    _ => None,
+                })
+                .collect();
+            // A call the builtin rejects (`gcd(1.5)`) stays unfolded;
+            // `parse_infix` reports it, and evaluation reports it too.
+            match values.and_then(|values| apply_function(&name, &values, fold).ok()) {
+                Some(value) => Expr::Number(value),
+                None => Expr::Call { name, args },
+            }
+        }
+        Expr::Binary { op, left, right } => {
+            // An invalid constant shift is left unfolded so that evaluating
+            // it reports the error.
+            let folded = match (&*left, &*right) {
+                (Expr::Number(a), Expr::Number(b)) => try_apply_binary(op, *a, *b).ok(),
+                _ => None,
+            };
+            if let Some(value) = folded {
+                Expr::Number(value)
+            } else if op == "and" && *left == Expr::Number(0.0) {
+                // Evaluation short-circuits `0 and y` to 0 whatever `y` is.
+                Expr::Number(0.0)
+            } else if let Some(flipped) = absorb_negation(op, &left, &right) {
+                simplify_node(flipped, fold)
+            } else if let Some(cancelled) = cancel(op, &left, &right) {
+                cancelled
+            } else if let Some(reduced) = power_identity(op, &left, &right) {
+                reduced
+            } else if let Some(reduced) = unit_factor(op, &left, &right) {
+                reduced
+            } else if let Some(Expr::Binary { op, left, right }) =
+                fold.distribute.then(|| distribute(op, &left, &right)).flatten()
+            {
+                let (left, right) = (simplify_node(*left, fold), simplify_node(*right, fold));
+                let sum = Expr::Binary { op, left: Box::new(left), right: Box::new(right) };
+                simplify_node(sum, fold)
+            } else if let Some(merged) = merge_chain(&POWERS, op, &left, &right, fold) {
+                merged
+            } else if let Some(merged) = fold
+                .like_terms
+                .then(|| merge_chain(&LIKE_TERMS, op, &left, &right, fold))
+                .flatten()
+            {
+                merged
+            } else {
+                Expr::Binary { op, left, right }
+            }
+        }
+        // Unary plus is a no-op, so drop it even around symbolic operands.
+        Expr::Unary { op: "+", expr } => *expr,
+        Expr::Unary { op, expr } => {
+            if let Expr::Number(n) = &*expr {
+                let res = match op {
+                    "-" => -*n,
+                    _ => unreachable!(),
+                };
+                Expr::Number(res)
+            } else {
+                Expr::Unary { op, expr }
+            }
+        }
+        other => other,
+    }
+}
+
+/// Split source on `;` into statements, where `name = expr` assigns.
+fn parse_program(source: &str) -> Result<Vec<Statement>, String> {
+    check_parens(source)?;
+    let tokens = tokenize(source);
+    let mut statements = Vec::new();
+    for stmt in tokens.split(|t| t.text == ";") {
+        if stmt.is_empty() {
+            continue;
+        }
+        if stmt.len() > 1 && stmt[1].text == "=" && is_identifier(&stmt[0].text) {
+            if stmt.len() == 2 {
+                return Err(format!("expected expression after '=' at position {}", stmt[1].pos));
+            }
+            let expr = parse_statement_expr(&stmt[2..])?;
+            statements.push(Statement::Assign(stmt[0].text.clone(), expr));
+        } else {
+            statements.push(Statement::Expr(parse_statement_expr(stmt)?));
+        }
+    }
+    Ok(statements)
+}
+
+fn parse_statement_expr(tokens: &[Token]) -> Result<Expr, String> {
+    parse_statement_with(tokens, &Precedence::default())
+}
+
+fn parse_statement_with(tokens: &[Token], prec: &Precedence) -> Result<Expr, String> {
+    Ok(parse_spanned(tokens, prec)?.0)
+}
+
+/// Parse a whole expression along with the span of each node, listed in
+/// `post_order`.
+fn parse_spanned(tokens: &[Token], prec: &Precedence) -> Result<(Expr, Vec<Span>), String> {
+    check_nesting(tokens, prec)?;
+    let mut spans = Vec::new();
+    let (expr, pos) = parse_expression(tokens, prec, &mut spans)?;
+    if let Some(token) = tokens.get(pos) {
+        return Err(format!("unexpected token '{}' at position {}", token.text, token.pos));
+    }
+    Ok((expr, spans))
+}
+
+fn depth(expr: &Expr) -> usize {
+    1 + children(expr).into_iter().map(depth).max().unwrap_or(0)
+}
+
+pub fn node_count(expr: &Expr) -> usize {
+    1 + children(expr).into_iter().map(node_count).sum::<usize>()
+}
+
+/// Parse a standalone expression without simplifying it.
+pub fn parse_source(source: &str) -> Result<Expr, String> {
+    check_parens(source)?;
+    parse_statement_expr(&tokenize(source))
+}
+
+const COMPARISON_PRECEDENCE: u8 = 2;
+const UNARY_PRECEDENCE: u8 = 6;
+
+fn binary_precedence(op: &str) -> u8 {
+    if AND_OPS.contains(&op) {
+        1
+    } else if COMPARISON_OPS.contains(&op) {
+        COMPARISON_PRECEDENCE
+    } else if SHIFT_OPS.contains(&op) {
+        3
+    } else if ADDITIVE_OPS.contains(&op) {
+        4
+    } else if MULTIPLICATIVE_OPS.contains(&op) {
+        5
+    } else {
+        7
+    }
+}
+
+fn expr_precedence(expr: &Expr) -> u8 {
+    match expr {
+        Expr::Binary { op, .. } => binary_precedence(op),
+        Expr::Unary { .. } => UNARY_PRECEDENCE,
+        Expr::Number(n) if n.is_sign_negative() && !n.is_nan() => UNARY_PRECEDENCE,
+        _ => 8,
+    }
+}
+
+/// Render `expr` as infix with spaces around binary operators and only the
+/// parentheses needed to reproduce the same tree when re-parsed, under the
+/// default precedence. Most operators are left-associative, so an
+/// equal-precedence right operand keeps parens; for `^` it is the left one,
+/// and comparisons chain instead, so a comparison operand always keeps them.
+/// Since the parser collapses a run of signs, a negated sign keeps its parens
+/// too (`-(-x)`), as does a negative number under a sign or as a `^` base.
+/// Special numbers are spelled as `allow_special` reads them back.
+fn to_infix(expr: &Expr) -> String {
+    match expr {
+        Expr::Number(n) => infix_number(*n),
+        Expr::Variable(name) => name.clone(),
+        Expr::Call { name, args } => {
+            let args: Vec<String> = args.iter().map(to_infix).collect();
+            format!("{}({})", name, args.join(", "))
+        }
+        Expr::Unary { op, expr: inner } => {
+            if expr_precedence(inner) <= UNARY_PRECEDENCE {
+                format!("{}({})", op, to_infix(inner))
+            } else {
+                format!("{}{}", op, to_infix(inner))
+            }
+        }
+        Expr::Binary { op, left, right } => {
+            let mut lhs = to_infix(left);
+            if operand_needs_parens(op, left, false) {
+                lhs = format!("({})", lhs);
+            }
+            let mut rhs = to_infix(right);
+            if operand_needs_parens(op, right, true) {
+                rhs = format!("({})", rhs);
+            }
+            format!("{} {} {}", lhs, op, rhs)
+        }
+    }
+}
+
+fn infix_number(n: f64) -> String {
+    if n.is_nan() {
+        "nan".to_string()
+    } else if n.is_infinite() {
+        if n < 0.0 { "-inf" } else { "inf" }.to_string()
+    } else {
+        n.to_string()
+    }
+}
+
+/// Whether the left or `right` operand of `op` needs parentheses to
+/// re-parse into the same tree; see `to_infix`.
+fn operand_needs_parens(op: &str, operand: &Expr, right: bool) -> bool {
+    let prec = binary_precedence(op);
+    let operand_prec = expr_precedence(operand);
+    let right_assoc = is_right_assoc(op);
+    if !right {
+        return operand_prec < prec
+            || (operand_prec == prec && (right_assoc || prec == COMPARISON_PRECEDENCE));
+    }
+    // A signed operand after `%` reads as a percent followed by a
+    // subtraction unless spaced just so; see `is_postfix_percent`.
+    let signed = operand_prec == UNARY_PRECEDENCE && op == "%";
+    operand_prec < prec || (operand_prec == prec && !right_assoc) || signed
+}
+
+/// Layout options for `format`.
+#[derive(Debug, Clone, Copy)]
+struct Style {
+    /// Spaces around binary operators and after argument commas.
+    spaces: bool,
+    /// Reproduce the parentheses the source wrote, needed or not.
+    keep_parens: bool,
+    /// Round numbers to at most this many decimal places.
+    precision: Option<usize>,
+}
+
+impl Default for Style {
+    fn default() -> Self {
+        Style { spaces: true, keep_parens: false, precision: None }
+    }
+}
+
+impl Style {
+    fn from_dict(dict: &Bound<'_, PyDict>) -> PyResult<Self> {
+        let mut style = Style::default();
+        for (key, value) in dict.iter() {
+            let key: String = key.extract()?;
+            match key.as_str() {
+                "spaces" => style.spaces = value.extract()?,
+                "keep_parens" => style.keep_parens = value.extract()?,
+                "precision" => style.precision = value.extract()?,
+                _ => return Err(PyValueError::new_err(format!("unknown style '{}'", key))),
+            }
+        }
+        Ok(style)
+    }
+}
+
+/// How many pairs of parentheses directly enclose each span in `source`,
+/// not counting those of a call's argument list.
+fn paren_layers(source: &str, spans: &[Span]) -> Vec<usize> {
+    let chars: Vec<char> = source.chars().collect();
+    let before = |end: usize| chars[..end].iter().rposition(|c| !c.is_whitespace());
+    let after = |start: usize| chars[start..].iter().position(|c| !c.is_whitespace());
+    spans
+        .iter()
+        .map(|&(mut start, mut end)| {
+            let mut layers = 0;
+            while let (Some(open), Some(close)) = (before(start), after(end)) {
+                let close = end + close;
+                if chars[open] != '(' || chars[close] != ')' {
+                    break;
+                }
+                let word_start = chars[..open]
+                    .iter()
+                    .rposition(|c| !is_identifier_continue(*c))
+                    .map_or(0, |i| i + 1);
+                let word: String = chars[word_start..open].iter().collect();
+                if is_identifier(&word) && !WORD_OPS.contains(&word.as_str()) {
+                    break;
+                }
+                layers += 1;
+                (start, end) = (open, close + 1);
+            }
+            layers
+        })
+        .collect()
+}
+
+/// Render `expr` under `style`, taking each node's source parenthesis count
+/// from `layers` in post-order. Returns the text and whether it is wrapped
+/// in parentheses.
+fn write_styled(
+    expr: &Expr,
+    style: &Style,
+    layers: &mut std::slice::Iter<usize>,
+) -> (String, bool) {
+    let wrap = |text: String, needed: bool| {
+        if needed {
+            format!("({})", text)
+        } else {
+            text
+        }
+    };
+    let text = match expr {
+        Expr::Number(n) => match style.precision {
+            Some(digits) => {
+                let text = format!("{:.*}", digits, n);
+                if text.contains('.') {
+                    text.trim_end_matches('0').trim_end_matches('.').to_string()
+                } else {
+                    text
+                }
+            }
+            None => infix_number(*n),
+        },
+        Expr::Variable(name) => name.clone(),
+        Expr::Call { name, args } => {
+            let args: Vec<String> =
+                args.iter().map(|arg| write_styled(arg, style, layers).0).collect();
+            format!("{}({})", name, args.join(if style.spaces { ", " } else { "," }))
+        }
+        Expr::Unary { op, expr: inner } => {
+            let (text, wrapped) = write_styled(inner, style, layers);
+            format!("{}{}", op, wrap(text, !wrapped && expr_precedence(inner) <= UNARY_PRECEDENCE))
+        }
+        Expr::Binary { op, left, right } => {
+            let (lhs, left_wrapped) = write_styled(left, style, layers);
+            let lhs = wrap(lhs, !left_wrapped && operand_needs_parens(op, left, false));
+            let (rhs, right_wrapped) = write_styled(right, style, layers);
+            let rhs = wrap(rhs, !right_wrapped && operand_needs_parens(op, right, true));
+            // Word operators always need their spaces.
+            if style.spaces || is_identifier(op) {
+                format!("{} {} {}", lhs, op, rhs)
+            } else {
+                format!("{}{}{}", lhs, op, rhs)
+            }
+        }
+    };
+    let kept = layers.next().copied().unwrap_or(0);
+    if style.keep_parens && kept > 0 {
+        ("(".repeat(kept) + &text + &")".repeat(kept), true)
+    } else {
+        (text, false)
+    }
+}
+
+/// How many of the source parenthesis pairs counted in `layers` (post-order,
+/// as for `write_styled`) `to_infix` would drop; `needed` says whether the
+/// parent requires one pair around `expr`.
+fn redundant_layers(expr: &Expr, needed: bool, layers: &mut std::slice::Iter<usize>) -> usize {
+    let inner = match expr {
+        Expr::Number(_) | Expr::Variable(_) => 0,
+        Expr::Call { args, .. } => {
+            args.iter().map(|arg| redundant_layers(arg, false, layers)).sum()
+        }
+        Expr::Unary { expr: operand, .. } => {
+            redundant_layers(operand, expr_precedence(operand) <= UNARY_PRECEDENCE, layers)
+        }
+        Expr::Binary { op, left, right } => {
+            redundant_layers(left, operand_needs_parens(op, left, false), layers)
+                + redundant_layers(right, operand_needs_parens(op, right, true), layers)
+        }
+    };
+    let kept = layers.next().copied().unwrap_or(0);
+    inner + kept.saturating_sub(usize::from(needed))
+}
+
+/// Push every node of `expr` onto `out`, each after its children.
+fn post_order<'e>(expr: &'e Expr, out: &mut Vec<&'e Expr>) {
+    for child in children(expr) {
+        post_order(child, out);
+    }
+    out.push(expr);
+}
+
+/// Direct subexpressions of `expr`, left to right.
+fn children(expr: &Expr) -> Vec<&Expr> {
+    match expr {
+        Expr::Number(_) | Expr::Variable(_) => vec![],
+        Expr::Unary { expr, .. } => vec![expr],
+        Expr::Binary { left, right, .. } => vec![left, right],
+        Expr::Call { args, .. } => args.iter().collect(),
+    }
+}
+
+/// Rebuild `expr` with `f` applied to each direct child.
+fn map_children(expr: Expr, f: &mut impl FnMut(Expr) -> Expr) -> Expr {
+    match expr {
+        // Results are written back into the existing boxes, so a node whose
+        // children come back unchanged is rebuilt without allocating.
+        Expr::Unary { op, mut expr } => {
+            *expr = f(*expr);
+            Expr::Unary { op, expr }
+        }
+        Expr::Binary { op, mut left, mut right } => {
+            *left = f(*left);
+            *right = f(*right);
+            Expr::Binary { op, left, right }
+        }
+        Expr::Call { name, args } => Expr::Call { name, args: args.into_iter().map(f).collect() },
+        other => other,
+    }
+}
+
+/// `map_children` for a fallible `f`, stopping at the first error.
+fn try_map_children<E>(
+    expr: Expr,
+    f: &mut impl FnMut(Expr) -> Result<Expr, E>,
+) -> Result<Expr, E> {
+    Ok(match expr {
+        Expr::Unary { op, mut expr } => {
+            *expr = f(*expr)?;
+            Expr::Unary { op, expr }
+        }
+        Expr::Binary { op, mut left, mut right } => {
+            *left = f(*left)?;
+            *right = f(*right)?;
+            Expr::Binary { op, left, right }
+        }
+        Expr::Call { name, args } => {
+            Expr::Call { name, args: args.into_iter().map(f).collect::<Result<_, _>>()? }
+        }
+        other => other,
+    })
+}
+
+/// Short human-readable label for a single node, e.g. `Binary(+)`.
+fn node_label(expr: &Expr) -> String {
+    match expr {
+        Expr::Number(n) => format!("Number({})", n),
+        Expr::Variable(name) => format!("Variable({})", name),
+        Expr::Unary { op, .. } => format!("Unary({})", op),
+        Expr::Binary { op, .. } => format!("Binary({})", op),
+        Expr::Call { name, .. } => format!("Call({})", name),
+    }
+}
+
+/// Emit `expr` as DOT statements, numbering nodes in pre-order so identical
+/// values still get distinct ids. Returns the id assigned to `expr`.
+fn write_dot(expr: &Expr, next_id: &mut usize, out: &mut String) -> usize {
+    let id = *next_id;
+    *next_id += 1;
+    out.push_str(&format!("    n{} [label=\"{}\"];\n", id, node_label(expr)));
+    for child in children(expr) {
+        let child_id = write_dot(child, next_id, out);
+        out.push_str(&format!("    n{} -> n{};\n", id, child_id));
+    }
+    id
+}
+
+/// Append the children of a node as `├─`/`└─` lines, with `prefix` carrying
+/// the `│` rails of the ancestors that still have siblings below.
+fn write_tree_children(expr: &Expr, prefix: &str, out: &mut String) {
+    let kids = children(expr);
+    for (i, child) in kids.iter().enumerate() {
+        let last = i + 1 == kids.len();
+        let branch = if last { "└─ " } else { "├─ " };
+        out.push_str(&format!("{}{}{}\n", prefix, branch, node_label(child)));
+        let rail = if last { "   " } else { "│  " };
+        write_tree_children(child, &format!("{}{}", prefix, rail), out);
+    }
+}
+
+/// Push every `Number` leaf of `expr` onto `out`, left to right.
+fn collect_literals(expr: &Expr, out: &mut Vec<f64>) {
+    match expr {
+        Expr::Number(n) => out.push(*n),
+        _ => children(expr).into_iter().for_each(|child| collect_literals(child, out)),
+    }
+}
+
+/// Push each distinct variable name in `expr` onto `out`, in first-use order.
+fn collect_variables(expr: &Expr, out: &mut Vec<String>) {
+    match expr {
+        Expr::Variable(name) if !out.contains(name) => out.push(name.clone()),
+        _ => children(expr).into_iter().for_each(|child| collect_variables(child, out)),
+    }
+}
+
+/// SplitMix64, enough to spread sample points without pulling in a crate.
+struct SampleRng(u64);
+
+impl SampleRng {
+    fn next_f64(&mut self) -> f64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+        (z >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Whether two results agree to a relative tolerance of `1e-9`. Equal
+/// infinities agree, and so do two `NaN`s, since both sides being undefined
+/// at a point is consistent with equivalence.
+fn close_enough(a: f64, b: f64) -> bool {
+    if a.is_nan() || b.is_nan() {
+        return a.is_nan() && b.is_nan();
+    }
+    a == b || (a - b).abs() <= 1e-9 * a.abs().max(b.abs()).max(1.0)
+}
+
+/// Ops whose chains `balance` may regroup.
+const ASSOCIATIVE_OPS: &[&str] = &["+", "*"];
+
+/// Push the operands of the `op` chain rooted at `expr` onto `out`, left to
+/// right. The left spine is walked in a loop so a long left-leaning chain
+/// doesn't recurse once per term.
+fn flatten_chain(expr: Expr, op: &str, out: &mut Vec<Expr>) {
+    let mut rights = Vec::new();
+    let mut node = expr;
+    loop {
+        match node {
+            Expr::Binary { op: o, left, right } if o == op => {
+                rights.push(*right);
+                node = *left;
+            }
+            other => {
+                out.push(other);
+                break;
+            }
+        }
+    }
+    for right in rights.into_iter().rev() {
+        flatten_chain(right, op, out);
+    }
+}
+
+pub fn build_balanced(op: &'static str, mut operands: Vec<Expr>) -> Expr {
+    if operands.len() == 1 {
+        return operands.pop().unwrap();
+    }
+    let right = operands.split_off(operands.len() / 2);
+    Expr::Binary {
+        op,
+        left: Box::new(build_balanced(op, operands)),
+        right: Box::new(build_balanced(op, right)),
+    }
+}
+
+/// Regroup every chain of `+` or of `*` into a balanced tree over the same
+/// operands in the same order, so an n-term chain has height O(log n) rather
+/// than O(n). Only these ops are regrouped: `a - b - c` and `a / b / c` would
+/// change meaning. Regrouping is exact for integers, but float sums and
+/// products may round differently in the last place.
+fn balance(expr: Expr) -> Expr {
+    match expr {
+        Expr::Binary { op, .. } if ASSOCIATIVE_OPS.contains(&op) => {
+            let mut operands = Vec::new();
+            flatten_chain(expr, op, &mut operands);
+            build_balanced(op, operands.into_iter().map(balance).collect())
+        }
+        other => map_children(other, &mut balance),
+    }
+}
+
+/// Regroup every `+` and `*` chain of `expr` to lean left, keeping the order
+/// of its operands, so groupings that differ only by associativity become
+/// the same tree.
+fn lean_left(expr: Expr) -> Expr {
+    match expr {
+        Expr::Binary { op, .. } if ASSOCIATIVE_OPS.contains(&op) => {
+            let mut operands = Vec::new();
+            flatten_chain(expr, op, &mut operands);
+            let operands = operands.into_iter().map(lean_left);
+            operands.reduce(|acc, operand| binary(op, acc, operand)).unwrap()
+        }
+        other => map_children(other, &mut lean_left),
+    }
+}
+
+/// Binary operators whose operands `commute` may reorder without changing
+/// the value.
+const COMMUTATIVE_OPS: &[&str] = &["+", "*", "==", "!="];
+
+/// Put the operands of every commutative operator in a canonical order:
+/// `+` and `*` chains are flattened, sorted by their S-expression text and
+/// rebuilt leaning left, so `b + (c + a)` and `(a + b) + c` give the same
+/// tree, while `a - b` and `b - a` stay apart.
+fn commute(expr: Expr) -> Expr {
+    match expr {
+        Expr::Binary { op, .. } if COMMUTATIVE_OPS.contains(&op) => {
+            let mut operands = Vec::new();
+            if ASSOCIATIVE_OPS.contains(&op) {
+                flatten_chain(expr, op, &mut operands);
+            } else if let Expr::Binary { left, right, .. } = expr {
+                operands.extend([*left, *right]);
+            }
+            let mut operands: Vec<(String, Expr)> = operands
+                .into_iter()
+                .map(commute)
+                .map(|operand| (to_sexpr_string(&operand), operand))
+                .collect();
+            operands.sort_by(|a, b| a.0.cmp(&b.0));
+            let operands = operands.into_iter().map(|(_, operand)| operand);
+            operands.reduce(|acc, operand| binary(op, acc, operand)).unwrap()
+        }
+        other => map_children(other, &mut commute),
+    }
+}
+
+/// 64-bit FNV-1a, spelled out because `DefaultHasher` makes no promise of
+/// the same output across Rust releases.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Append `expr` in postfix order. Unary operators get their own tokens,
+/// `neg` and `pos`, so they can't be confused with binary `-` and `+`; a call
+/// is written `name/argc` after its arguments, e.g. `x 2 round/2`.
+fn write_rpn(expr: &Expr, out: &mut Vec<String>) {
+    for child in children(expr) {
+        write_rpn(child, out);
+    }
+    out.push(match expr {
+        Expr::Number(n) => n.to_string(),
+        Expr::Variable(name) => name.clone(),
+        Expr::Unary { op: "-", .. } => "neg".to_string(),
+        Expr::Unary { .. } => "pos".to_string(),
+        Expr::Binary { op, .. } => op.to_string(),
+        Expr::Call { name, args } => format!("{}/{}", name, args.len()),
+    });
+}
+
+/// Build a tree from postfix tokens as written by `write_rpn`. Positions in
+/// errors count tokens, not characters.
+fn read_rpn(tokens: &[&str]) -> Result<Expr, String> {
+    let binary_ops =
+        [AND_OPS, COMPARISON_OPS, SHIFT_OPS, ADDITIVE_OPS, MULTIPLICATIVE_OPS, POWER_OPS];
+    let mut stack: Vec<Expr> = Vec::new();
+    for (pos, &token) in tokens.iter().enumerate() {
+        let mut operands = |arity: usize| {
+            if stack.len() < arity {
+                return Err(format!(
+                    "'{}' at token {} needs {} operand(s), found {}",
+                    token,
+                    pos,
+                    arity,
+                    stack.len()
+                ));
+            }
+            Ok(stack.split_off(stack.len() - arity))
+        };
+        let node = if let Some(op) = binary_ops.iter().find_map(|ops| find_op(token, ops)) {
+            let mut pair = operands(2)?;
+            let right = Box::new(pair.pop().unwrap());
+            let left = Box::new(pair.pop().unwrap());
+            Expr::Binary { op, left, right }
+        } else if token == "neg" || token == "pos" {
+            let op = if token == "neg" { "-" } else { "+" };
+            Expr::Unary { op, expr: Box::new(operands(1)?.pop().unwrap()) }
+        } else if let Some((name, argc)) = token.split_once('/').filter(|(n, _)| is_identifier(n))
+        {
+            let argc: usize = argc
+                .parse()
+                .map_err(|_| format!("bad argument count in '{}' at token {}", token, pos))?;
+            check_arity(name, argc, pos)?;
+            Expr::Call { name: name.to_string(), args: operands(argc)? }
+        } else if is_identifier(token) {
+            Expr::Variable(token.to_string())
+        } else {
+            let value = parse_number(token)
+                .ok_or_else(|| format!("unexpected token '{}' at token {}", token, pos))?;
+            Expr::Number(value)
+        };
+        stack.push(node);
+    }
+    match stack.len() {
+        1 => Ok(stack.pop().unwrap()),
+        0 => Err("expected expression".to_string()),
+        n => Err(format!("{} values left on the stack; expected 1", n)),
+    }
+}
+
+/// Render `expr` as an S-expression: `(op operand ...)` for operators,
+/// including a one-operand `(- x)` for unary minus, and `(name arg ...)` for
+/// calls.
+fn to_sexpr_string(expr: &Expr) -> String {
+    let head = match expr {
+        Expr::Number(n) => return n.to_string(),
+        Expr::Variable(name) => return name.clone(),
+        Expr::Unary { op, .. } | Expr::Binary { op, .. } => op.to_string(),
+        Expr::Call { name, .. } => name.clone(),
+    };
+    let parts: Vec<String> =
+        std::iter::once(head).chain(children(expr).into_iter().map(to_sexpr_string)).collect();
+    format!("({})", parts.join(" "))
+}
+
+/// Replace each variable that `lookup` knows with its numeric value.
+fn bind_constants(expr: Expr, lookup: &dyn Fn(&str) -> Option<f64>) -> Expr {
+    match expr {
+        Expr::Variable(name) => match lookup(&name) {
+            Some(value) => Expr::Number(value),
+            None => Expr::Variable(name),
+        },
+        other => map_children(other, &mut |child| bind_constants(child, lookup)),
+    }
+}
+
+/// Hash of a node's own content combined with its children's hashes.
+fn node_hash(expr: &Expr, child_hashes: &[u64]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    match expr {
+        Expr::Number(n) => (0u8, n.to_bits()).hash(&mut hasher),
+        Expr::Variable(name) => (1u8, name).hash(&mut hasher),
+        Expr::Unary { op, .. } => (2u8, op).hash(&mut hasher),
+        Expr::Binary { op, .. } => (3u8, op).hash(&mut hasher),
+        Expr::Call { name, .. } => (4u8, name).hash(&mut hasher),
+    }
+    child_hashes.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn structural_hash(expr: &Expr) -> u64 {
+    let child_hashes: Vec<u64> = children(expr).into_iter().map(structural_hash).collect();
+    node_hash(expr, &child_hashes)
+}
+
+/// Hash every subtree bottom-up in a single pass, comparing against
+/// `target_hash` and confirming candidates structurally so a hash collision
+/// can't produce a false match. Returns `expr`'s hash and whether it matched.
+fn find_subtree(expr: &Expr, target: &Expr, target_hash: u64) -> (u64, bool) {
+    let mut child_hashes = Vec::new();
+    for child in children(expr) {
+        let (hash, found) = find_subtree(child, target, target_hash);
+        if found {
+            return (hash, true);
+        }
+        child_hashes.push(hash);
+    }
+    let hash = node_hash(expr, &child_hashes);
+    (hash, hash == target_hash && expr == target)
+}
+
+/// Substitute every subtree structurally equal to `target` with `replacement`.
+fn replace_subtree(expr: Expr, target: &Expr, replacement: &Expr) -> Expr {
+    if expr == *target {
+        return replacement.clone();
+    }
+    map_children(expr, &mut |child| replace_subtree(child, target, replacement))
+}
+
+fn lookup(env: &[(String, f64)], name: &str) -> Option<f64> {
+    env.iter().find(|(n, _)| n == name).map(|(_, v)| *v)
+}
+
+fn evaluate(expr: &Expr, env: &[(String, f64)]) -> Result<f64, String> {
+    evaluate_observed(expr, env, &mut |_, _| {})
+}
+
+/// `evaluate`, passing each node and its value to `observe` in post-order.
+/// The right side of a short-circuited `and` is never evaluated, so it isn't
+/// observed.
+fn evaluate_observed(
+    expr: &Expr,
+    env: &[(String, f64)],
+    observe: &mut dyn FnMut(&Expr, f64),
+) -> Result<f64, String> {
+    let value = match expr {
+        Expr::Number(n) => Ok(*n),
+        Expr::Variable(name) => {
+            lookup(env, name).ok_or_else(|| format!("undefined variable '{}'", name))
+        }
+        Expr::Unary { op, expr } => {
+            let v = evaluate_observed(expr, env, observe)?;
+            Ok(if *op == "-" { -v } else { v })
+        }
+        Expr::Binary { op, left, right } => {
+            let a = evaluate_observed(left, env, observe)?;
+            // Short-circuit like Python, so the right side may reference
+            // variables that are only defined when the left side holds.
+            if *op == "and" && a == 0.0 {
+                Ok(0.0)
+            } else {
+                let b = evaluate_observed(right, env, observe)?;
+                try_apply_binary(op, a, b)
+            }
+        }
+        // Only the taken branch is evaluated, so the other may divide by
+        // zero or use unbound variables.
+        Expr::Call { name, args } if name == "if" => {
+            let cond = evaluate_observed(&args[0], env, observe)?;
+            evaluate_observed(if cond != 0.0 { &args[1] } else { &args[2] }, env, observe)
+        }
+        Expr::Call { name, args } => {
+            let args = args
+                .iter()
+                .map(|arg| evaluate_observed(arg, env, observe))
+                .collect::<Result<Vec<_>, _>>()?;
+            apply_function(name, &args, &FoldOptions::default())
+        }
+    }?;
+    observe(expr, value);
+    Ok(value)
+}
+
+/// Apply a function from Python's `operator` module, for protocols `Bound`
+/// has no direct method for.
+fn call_operator<'py>(
+    py: Python<'py>,
+    name: &str,
+    args: impl IntoPy<Py<PyTuple>>,
+) -> PyResult<Bound<'py, PyAny>> {
+    py.import_bound("operator")?.getattr(name)?.call1(args)
+}
+
+/// The call `mark_float_literals` wraps literals in. The tokenizer never
+/// produces a name with a space, so no source can call or shadow it.
+const FLOAT_LITERAL: &str = "float literal";
+
+/// Wrap each integral literal spelled with a point or exponent, such as
+/// `2.0`, `5e3` or `0x1p4`, in a `FLOAT_LITERAL` call so `evaluate_with`
+/// keeps it a Python `float`; only literals written with neither, including
+/// hex ones like `0xff`, are read as `int`s.
+fn mark_float_literals(tokens: Vec<Token>) -> Vec<Token> {
+    let mut out = Vec::with_capacity(tokens.len());
+    for token in tokens {
+        let integral = parse_number(&token.text).is_some_and(|v| v.fract() == 0.0);
+        let marks: &[char] =
+            if is_hex_literal(&token.text) { &['.', 'p', 'P'] } else { &['.', 'e', 'E'] };
+        if integral && token.text.contains(marks) {
+            let pos = token.pos;
+            out.push(Token::new(FLOAT_LITERAL, pos));
+            out.push(Token::new("(", pos));
+            out.push(token);
+            out.push(Token::new(")", pos));
+        } else {
+            out.push(token);
+        }
+    }
+    out
+}
+
+/// Undo `mark_float_literals` on a parsed tree.
+fn unmark_float_literals(expr: Expr) -> Expr {
+    match expr {
+        Expr::Call { name, mut args } if name == FLOAT_LITERAL => args.remove(0),
+        other => map_children(other, &mut unmark_float_literals),
+    }
+}
+
+/// Magnitude bound of the `i64` range (2^63); integral literals below it
+/// convert to a Python `int` without loss.
+const I64_BOUND: f64 = 9_223_372_036_854_775_808.0;
+
+/// How `evaluate_object` maps the tree onto Python values.
+#[derive(Debug, Clone, Copy, Default)]
+struct ObjectEval {
+    /// Integral literals become Python `int`s, so arithmetic on `int`
+    /// variables stays in arbitrary-precision integers.
+    int_literals: bool,
+    /// Results on `int`/`Fraction` operands stay exact; see `evaluate_with`.
+    auto_exact: bool,
+    division: Division,
+    /// Value for variables missing from `vars`, instead of raising.
+    undefined_default: Option<f64>,
+    /// When to give up, and the `timeout_ms` it came from for the message.
+    deadline: Option<(Instant, u64)>,
+}
+
+/// The `ObjectEval::deadline` for a `timeout_ms` given now.
+fn deadline(timeout_ms: Option<u64>) -> Option<(Instant, u64)> {
+    timeout_ms.map(|ms| (Instant::now() + Duration::from_millis(ms), ms))
+}
+
+/// The result type of `/` in `evaluate_with`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+enum Division {
+    /// Python's own `/`: `int`s give a `float` (`6/3` is `2.0`), and types
+    /// such as `Fraction` or `Decimal` keep their own semantics.
+    #[default]
+    Float,
+    /// An `int` when both operands are `int`s and the division has no
+    /// remainder (`6/3` is `2`), otherwise Python's `/` (`7/3` is `2.333...`).
+    IntWhenExact,
+    /// Both operands are converted with `float()` first, so even `Fraction`
+    /// operands give a `float`.
+    AlwaysFloat,
+    /// `int`s and `Fraction`s divide exactly: `7/3` is `Fraction(7, 3)` and
+    /// `6/3` is `2`. Any other operand gives Python's `/`.
+    Fraction,
+}
+
+const DIVISIONS: &[(&str, Division)] = &[
+    ("float", Division::Float),
+    ("int_when_exact", Division::IntWhenExact),
+    ("always_float", Division::AlwaysFloat),
+    ("fraction", Division::Fraction),
+];
+
+impl Division {
+    fn parse(name: &str) -> PyResult<Self> {
+        choice("division", name, DIVISIONS)
+    }
+
+    /// The mode once `auto_exact` is applied: it divides as `Fraction`, so
+    /// only that mode and the default `Float` combine with it.
+    fn with_auto_exact(self, auto_exact: bool) -> Result<Self, Division> {
+        match (self, auto_exact) {
+            (Division::Float | Division::Fraction, true) => Ok(Division::Fraction),
+            (conflicting, true) => Err(conflicting),
+            (division, false) => Ok(division),
+        }
+    }
+}
+
+/// When `Evaluator.evaluate` returns an `int` rather than a `float`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+enum IntPolicy {
+    /// Always a `float`.
+    #[default]
+    Never,
+    /// An `int` whenever the result is integral and within the `i64` range.
+    Integral,
+    /// As `Integral`, but only when the source has no `/` (including a
+    /// percent, which divides by 100), so `2*3` is `6` while `6/2` is `3.0`.
+    NoDivision,
+}
+
+impl IntPolicy {
+    fn parse(name: &str) -> PyResult<Self> {
+        match name {
+            "never" => Ok(IntPolicy::Never),
+            "integral" => Ok(IntPolicy::Integral),
+            "no_division" => Ok(IntPolicy::NoDivision),
+            _ => Err(PyValueError::new_err(format!(
+                "unknown int_policy '{}'; expected 'never', 'integral' or 'no_division'",
+                name
+            ))),
+        }
+    }
+
+    /// Whether an integral result of the parsed `expr` may be an `int`.
+    fn allows_int(self, expr: &Expr) -> bool {
+        match self {
+            IntPolicy::Never => false,
+            IntPolicy::Integral => true,
+            IntPolicy::NoDivision => !has_division(expr),
+        }
+    }
+}
+
+fn has_division(expr: &Expr) -> bool {
+    matches!(expr, Expr::Binary { op: "/", .. }) || children(expr).into_iter().any(has_division)
+}
+
+/// `a / b` with the result type `division` selects.
+fn divide<'py>(
+    py: Python<'py>,
+    a: Bound<'py, PyAny>,
+    b: Bound<'py, PyAny>,
+    division: Division,
+) -> PyResult<Bound<'py, PyAny>> {
+    match division {
+        Division::Float => a.div(b),
+        Division::IntWhenExact => {
+            let ints = a.is_instance_of::<PyLong>() && b.is_instance_of::<PyLong>();
+            if ints && !b.eq(0)? && call_operator(py, "mod", (&a, &b))?.eq(0)? {
+                call_operator(py, "floordiv", (a, b))
+            } else {
+                a.div(b)
+            }
+        }
+        Division::AlwaysFloat => {
+            // A complex quotient stays complex.
+            if a.is_instance_of::<PyComplex>() || b.is_instance_of::<PyComplex>() {
+                return a.div(b);
+            }
+            let float = py.get_type_bound::<PyFloat>();
+            float.call1((a,))?.div(float.call1((b,))?)
+        }
+        Division::Fraction => {
+            let fraction = py.import_bound("fractions")?.getattr("Fraction")?;
+            if is_rational(&a, &fraction)? && is_rational(&b, &fraction)? {
+                normalize_fraction(fraction.call1((a,))?.div(b)?, &fraction)
+            } else {
+                a.div(b)
+            }
+        }
+    }
+}
+
+/// The type a `Decimal` operand is mixed with when Python has no arithmetic
+/// between the two, i.e. `Fraction` or `float`.
+fn decimal_mix(a: &Bound<'_, PyAny>, b: &Bound<'_, PyAny>) -> PyResult<Option<&'static str>> {
+    let py = a.py();
+    let decimal = py.import_bound("decimal")?.getattr("Decimal")?;
+    let other = match (a.is_instance(&decimal)?, b.is_instance(&decimal)?) {
+        (true, false) => b,
+        (false, true) => a,
+        _ => return Ok(None),
+    };
+    if other.is_instance_of::<PyFloat>() {
+        return Ok(Some("float"));
+    }
+    let fraction = py.import_bound("fractions")?.getattr("Fraction")?;
+    Ok(other.is_instance(&fraction)?.then_some("Fraction"))
+}
+
+fn is_rational(value: &Bound<'_, PyAny>, fraction: &Bound<'_, PyAny>) -> PyResult<bool> {
+    Ok(value.is_instance_of::<PyLong>() || value.is_instance(fraction)?)
+}
+
+/// `auto_exact` results: a `Fraction` with denominator 1 becomes its `int`.
+fn normalize_fraction<'py>(
+    value: Bound<'py, PyAny>,
+    fraction: &Bound<'py, PyAny>,
+) -> PyResult<Bound<'py, PyAny>> {
+    if value.is_instance(fraction)? && value.getattr("denominator")?.eq(1)? {
+        value.getattr("numerator")
+    } else {
+        Ok(value)
+    }
+}
+
+/// Evaluate with Python objects as values, delegating each operator to the
+/// operands' own protocol so NumPy arrays broadcast and mix with scalars.
+fn evaluate_object<'py>(
+    expr: &Expr,
+    vars: &Bound<'py, PyDict>,
+    mode: ObjectEval,
+) -> PyResult<Bound<'py, PyAny>> {
+    let py = vars.py();
+    check_deadline(mode)?;
+    match expr {
+        Expr::Number(n) if mode.int_literals && n.fract() == 0.0 && n.abs() < I64_BOUND => {
+            Ok((*n as i64).into_py(py).into_bound(py))
+        }
+        Expr::Number(n) => Ok(n.into_py(py).into_bound(py)),
+        Expr::Variable(name) => match (vars.get_item(name)?, mode.undefined_default) {
+            (Some(value), _) => Ok(value),
+            (None, Some(default)) => Ok(default.into_py(py).into_bound(py)),
+            (None, None) => {
+                Err(EvaluationError::new_err(format!("undefined variable '{}'", name)))
+            }
+        },
+        Expr::Unary { op, expr } => {
+            let v = evaluate_object(expr, vars, mode)?;
+            if *op == "-" {
+                call_operator(py, "neg", (v,))
+            } else {
+                Ok(v)
+            }
+        }
+        Expr::Binary { op: "and", left, right } => logical_and(left, right, vars, mode),
+        Expr::Binary { op, left, right } => {
+            let a = evaluate_object(left, vars, mode)?;
+            let b = evaluate_object(right, vars, mode)?;
+            let complex = a.is_instance_of::<PyComplex>() || b.is_instance_of::<PyComplex>();
+            if complex && !COMPLEX_OPS.contains(op) {
+                return Err(EvaluationError::new_err(format!(
+                    "'{}' is not defined for complex values",
+                    op
+                )));
+            }
+            // `always_float` converts the operands of `/` itself.
+            let converted = *op == "/" && mode.division == Division::AlwaysFloat;
+            let arithmetic = !COMPARISON_OPS.contains(op) && !AND_OPS.contains(op) && !converted;
+            if let Some(other) = if arithmetic { decimal_mix(&a, &b)? } else { None } {
+                return Err(EvaluationError::new_err(format!(
+                    "'{}' cannot mix Decimal and {}; convert one operand first",
+                    op, other
+                )));
+            }
+            if *op == "/" {
+                return divide(py, a, b, mode.division);
+            }
+            if *op == "^" && a.is_instance_of::<PyLong>() && b.is_instance_of::<PyLong>() {
+                if let Some(exponent) = (!b.lt(0)?).then(|| b.extract::<u64>().ok()) {
+                    return int_pow(py, a, b, exponent, mode);
+                }
+            }
+            if mode.auto_exact {
+                let fraction = py.import_bound("fractions")?.getattr("Fraction")?;
+                if is_rational(&a, &fraction)? && is_rational(&b, &fraction)? {
+                    let value = apply_object_binary(py, op, a, b)?;
+                    return normalize_fraction(value, &fraction);
+                }
+            }
+            apply_object_binary(py, op, a, b)
+        }
+        Expr::Call { name, args } if name == FLOAT_LITERAL => {
+            evaluate_object(&args[0], vars, ObjectEval { int_literals: false, ..mode })
+        }
+        // The taken branch is returned as is, whatever its type.
+        Expr::Call { name, args } if name == "if" => {
+            let cond = evaluate_object(&args[0], vars, mode)?.is_truthy()?;
+            evaluate_object(if cond { &args[1] } else { &args[2] }, vars, mode)
+        }
+        // Other builtins only have real scalar implementations.
+        Expr::Call { name, args } => {
+            let mut values = Vec::with_capacity(args.len());
+            for arg in args {
+                let value = evaluate_object(arg, vars, mode)?;
+                if value.is_instance_of::<PyComplex>() {
+                    return Err(EvaluationError::new_err(format!(
+                        "{}() is not defined for complex values",
+                        name
+                    )));
+                }
+                values.push(value.extract::<f64>()?);
+            }
+            let value = apply_function(name, &values, &FoldOptions::default())
+                .map_err(EvaluationError::new_err)?;
+            Ok(value.into_py(py).into_bound(py))
+        }
+    }
+}
+
+/// `left and right` as the `f64` evaluator reads it: `right` is only
+/// evaluated when `left` is nonzero, so `0 and y` needs no `y`, and the
+/// result is whether both are nonzero, as a `bool` like the comparisons
+/// give. When an operand isn't a number, such as a NumPy array, both are
+/// compared with 0 and combined by `&` instead, elementwise for arrays.
+fn logical_and<'py>(
+    left: &Expr,
+    right: &Expr,
+    vars: &Bound<'py, PyDict>,
+    mode: ObjectEval,
+) -> PyResult<Bound<'py, PyAny>> {
+    let py = vars.py();
+    let number = py.import_bound("numbers")?.getattr("Number")?;
+    let truth = |operand: &Bound<'py, PyAny>| -> PyResult<Option<bool>> {
+        if operand.is_instance_of::<PyComplex>() {
+            return Err(EvaluationError::new_err("'and' is not defined for complex values"));
+        }
+        if operand.is_instance(&number)? {
+            Ok(Some(operand.is_truthy()?))
+        } else {
+            Ok(None)
+        }
+    };
+    let a = evaluate_object(left, vars, mode)?;
+    let a_truth = truth(&a)?;
+    if a_truth == Some(false) {
+        return Ok(false.into_py(py).into_bound(py));
+    }
+    let b = evaluate_object(right, vars, mode)?;
+    match (a_truth, truth(&b)?) {
+        (Some(_), Some(b_truth)) => Ok(b_truth.into_py(py).into_bound(py)),
+        _ => {
+            let zero = 0.into_py(py);
+            let a = a.rich_compare(&zero, CompareOp::Ne)?;
+            call_operator(py, "and_", (a, b.rich_compare(&zero, CompareOp::Ne)?))
+        }
+    }
+}
+
+fn check_deadline(mode: ObjectEval) -> PyResult<()> {
+    match mode.deadline {
+        Some((deadline, ms)) if Instant::now() > deadline => {
+            Err(PyTimeoutError::new_err(format!("evaluation exceeded timeout_ms={}", ms)))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// The most bits an `int` power may produce in `evaluate_object`; CPython
+/// takes about a fifth of a second for `9 ** 10**6`, which has 3.2 million.
+const MAX_POW_BITS: u64 = 1 << 22;
+
+/// `(bits - 1) * exponent` bits, a lower bound on the size of a power of an
+/// integer with `base_bits` bits, or an error once that passes
+/// `MAX_POW_BITS`. Bases 0, 1 and -1 have one bit or none, so any power of
+/// them passes.
+fn check_pow_bits(base_bits: u64, exponent: u64) -> Result<(), String> {
+    let bits = base_bits.saturating_sub(1).saturating_mul(exponent);
+    if bits > MAX_POW_BITS {
+        return Err(format!(
+            "'^' result would have at least {} bits, above the limit of {}",
+            bits, MAX_POW_BITS
+        ));
+    }
+    Ok(())
+}
+
+/// `a ^ b` for `int`s with `b >= 0`, where `exponent` is `b` if it fits in
+/// a `u64`. `LimitError` refuses results past `MAX_POW_BITS`. Under a
+/// deadline the power is taken by repeated squaring, checking the clock
+/// between multiplications rather than only once the whole `pow` is done.
+fn int_pow<'py>(
+    py: Python<'py>,
+    a: Bound<'py, PyAny>,
+    b: Bound<'py, PyAny>,
+    exponent: Option<u64>,
+    mode: ObjectEval,
+) -> PyResult<Bound<'py, PyAny>> {
+    let base_bits: u64 = a.call_method0("bit_length")?.extract()?;
+    check_pow_bits(base_bits, exponent.unwrap_or(u64::MAX)).map_err(LimitError::new_err)?;
+    let Some(mut rest) = exponent.filter(|_| mode.deadline.is_some()) else {
+        return a.pow(b, py.None());
+    };
+    let one: PyObject = 1.into_py(py);
+    let mut result = one.into_bound(py);
+    let mut square = a;
+    while rest > 0 {
+        if rest & 1 == 1 {
+            result = result.mul(&square)?;
+        }
+        rest >>= 1;
+        if rest > 0 {
+            square = square.mul(&square)?;
+        }
+        check_deadline(mode)?;
+    }
+    Ok(result)
+}
+
+/// The binary operators Python defines on `complex` operands.
+const COMPLEX_OPS: &[&str] = &["+", "-", "*", "/", "^", "==", "!="];
+
+fn apply_object_binary<'py>(
+    py: Python<'py>,
+    op: &str,
+    a: Bound<'py, PyAny>,
+    b: Bound<'py, PyAny>,
+) -> PyResult<Bound<'py, PyAny>> {
+    match op {
+        "+" => a.add(b),
+        "-" => a.sub(b),
+        "*" => a.mul(b),
+        "/" => a.div(b),
+        "//" | "div" => call_operator(py, "floordiv", (a, b)),
+        "%" | "mod" => call_operator(py, "mod", (a, b)),
+        // Python has no truncating remainder, so adjust the modulus back
+        // toward the sign of the dividend.
+        "rem" => {
+            let r = call_operator(py, "mod", (&a, &b))?;
+            let zero = 0.into_py(py);
+            if !r.eq(&zero)? && r.lt(&zero)? != a.lt(&zero)? {
+                r.sub(b)
+            } else {
+                Ok(r)
+            }
+        }
+        "^" => a.pow(b, py.None()),
+        "<<" | ">>" => shift_object(py, op, a, b),
+        "<" => a.rich_compare(b, CompareOp::Lt),
+        "<=" => a.rich_compare(b, CompareOp::Le),
+        ">" => a.rich_compare(b, CompareOp::Gt),
+        ">=" => a.rich_compare(b, CompareOp::Ge),
+        "==" => a.rich_compare(b, CompareOp::Eq),
+        "!=" => a.rich_compare(b, CompareOp::Ne),
+        _ => unreachable!(),
+    }
+}
+
+/// `<<` or `>>` with the checks of `apply_shift` on scalar operands, so
+/// they must be 64-bit integers (an integral `float` counts) and the result
+/// must be one too; it is an `int` unless an operand is a `float`. Other
+/// operands, such as NumPy arrays, shift by their own protocol.
+fn shift_object<'py>(
+    py: Python<'py>,
+    op: &str,
+    a: Bound<'py, PyAny>,
+    b: Bound<'py, PyAny>,
+) -> PyResult<Bound<'py, PyAny>> {
+    let number = py.import_bound("numbers")?.getattr("Number")?;
+    if !a.is_instance(&number)? || !b.is_instance(&number)? {
+        let name = if op == "<<" { "lshift" } else { "rshift" };
+        return call_operator(py, name, (a, b));
+    }
+    let integer = |operand: &Bound<'py, PyAny>| -> PyResult<i64> {
+        let exact = match call_operator(py, "index", (operand,)) {
+            Ok(index) => index.extract::<i64>().ok(),
+            Err(_) => operand.extract::<f64>().ok().and_then(as_i64),
+        };
+        exact.ok_or_else(|| {
+            EvaluationError::new_err(format!(
+                "shift operands must be 64-bit integers, got {}",
+                operand
+            ))
+        })
+    };
+    let shifted = shift_i64(op, integer(&a)?, integer(&b)?).map_err(EvaluationError::new_err)?;
+    Ok(if a.is_instance_of::<PyFloat>() || b.is_instance_of::<PyFloat>() {
+        (shifted as f64).into_py(py).into_bound(py)
+    } else {
+        shifted.into_py(py).into_bound(py)
+    })
+}
+
+/// What `evaluate_with` does with an integer literal it cannot hold exactly.
+#[derive(Debug, Clone, Copy, Default)]
+enum IntOverflow {
+    /// Use the nearest `float` and emit a `RuntimeWarning`.
+    #[default]
+    Float,
+    /// Raise `ParseError`.
+    Error,
+}
+
+impl IntOverflow {
+    fn parse(name: &str) -> PyResult<Self> {
+        match name {
+            "float" => Ok(IntOverflow::Float),
+            "error" => Ok(IntOverflow::Error),
+            _ => Err(PyValueError::new_err(format!(
+                "unknown int_overflow '{}'; expected 'float' or 'error'",
+                name
+            ))),
+        }
+    }
+}
+
+/// The first all-digit literal whose value doesn't survive the trip through
+/// `f64` or lies outside the `i64` range, e.g. `99999999999999999999999`.
+fn inexact_int_literal(tokens: &[Token]) -> Option<&Token> {
+    tokens.iter().find(|token| {
+        if !token.text.bytes().all(|b| b.is_ascii_digit()) {
+            return false;
+        }
+        let value: f64 = token.text.parse().unwrap_or(f64::INFINITY);
+        let digits = token.text.trim_start_matches('0');
+        let digits = if digits.is_empty() { "0" } else { digits };
+        value >= I64_BOUND || format!("{:.0}", value) != digits
+    })
+}
+
+/// Convention for the `"type"` strings emitted by `to_py`.
+#[derive(Debug, Clone, Copy, Default)]
+enum Naming {
+    /// `Number`, `Variable`, `Unary`, `Binary`, `Call`
+    #[default]
+    Default,
+    /// `number`, `variable`, `unary_op`, `binary_op`, `call`
+    Snake,
+}
+
+impl Naming {
+    fn parse(name: &str) -> PyResult<Self> {
+        match name {
+            "default" => Ok(Naming::Default),
+            "snake" => Ok(Naming::Snake),
+            _ => Err(PyValueError::new_err(format!(
+                "unknown naming '{}'; expected 'default' or 'snake'",
+                name
+            ))),
+        }
+    }
+
+    fn type_name(self, expr: &Expr) -> &'static str {
+        match (self, expr) {
+            (Naming::Default, Expr::Number(_)) => "Number",
+            (Naming::Default, Expr::Variable(_)) => "Variable",
+            (Naming::Default, Expr::Unary { .. }) => "Unary",
+            (Naming::Default, Expr::Binary { .. }) => "Binary",
+            (Naming::Default, Expr::Call { .. }) => "Call",
+            (Naming::Snake, Expr::Number(_)) => "number",
+            (Naming::Snake, Expr::Variable(_)) => "variable",
+            (Naming::Snake, Expr::Unary { .. }) => "unary_op",
+            (Naming::Snake, Expr::Binary { .. }) => "binary_op",
+            (Naming::Snake, Expr::Call { .. }) => "call",
+        }
+    }
+}
+
+fn to_py(py: Python<'_>, expr: Expr, naming: Naming) -> PyObject {
+    to_py_annotated(py, expr, naming, None)
+}
+
+/// `to_py`, adding to each operator node the `"precedence"` (binding power,
+/// higher binds tighter) and `"assoc"` it has under `precedence`, if given.
+fn to_py_annotated(
+    py: Python<'_>,
+    expr: Expr,
+    naming: Naming,
+    precedence: Option<&Precedence>,
+) -> PyObject {
+    let to_py = |expr: Expr| to_py_annotated(py, expr, naming, precedence);
+    let type_name = naming.type_name(&expr);
+    match expr {
+        Expr::Number(n) => {
+            let dict = PyDict::new_bound(py);
+            dict.set_item("type", type_name).unwrap();
+            dict.set_item("value", n).unwrap();
+            dict.into_py(py)
+        }
+        Expr::Variable(name) => {
+            let dict = PyDict::new_bound(py);
+            dict.set_item("type", type_name).unwrap();
+            dict.set_item("name", name).unwrap();
+            dict.into_py(py)
+        }
+        Expr::Unary { op, expr } => {
+            let dict = PyDict::new_bound(py);
+            dict.set_item("type", type_name).unwrap();
+            dict.set_item("op", op.to_string()).unwrap();
+            dict.set_item("expr", to_py(*expr)).unwrap();
+            if let Some(precedence) = precedence {
+                dict.set_item("precedence", precedence.prefix_power()).unwrap();
+                dict.set_item("assoc", Assoc::Right.name()).unwrap();
+            }
+            dict.into_py(py)
+        }
+        Expr::Binary { op, left, right } => {
+            let dict = PyDict::new_bound(py);
+            dict.set_item("type", type_name).unwrap();
+            dict.set_item("op", op.to_string()).unwrap();
+            dict.set_item("left", to_py(*left)).unwrap();
+            dict.set_item("right", to_py(*right)).unwrap();
+            if let Some(entry) = precedence.and_then(|precedence| precedence.infix(op)) {
+                dict.set_item("precedence", entry.power).unwrap();
+                dict.set_item("assoc", entry.assoc.name()).unwrap();
+            }
+            dict.into_py(py)
+        }
+        Expr::Call { name, args } => {
+            let dict = PyDict::new_bound(py);
+            dict.set_item("type", type_name).unwrap();
+            dict.set_item("name", name).unwrap();
+            let args: Vec<PyObject> = args.into_iter().map(to_py).collect();
+            dict.set_item("args", args).unwrap();
+            dict.into_py(py)
+        }
+    }
+}
+
+/// Settings shared by the `parse_infix` family.
+#[derive(Debug, Clone, Default)]
+struct ParseOptions {
+    limits: Limits,
+    physical_constants: bool,
+    /// Caller-supplied bindings; these shadow `PHYSICAL_CONSTANTS`.
+    constants: HashMap<String, f64>,
+    fold: FoldOptions,
+    /// Rebalance `+`/`*` chains after simplifying; see `balance`.
+    balance: bool,
+    precedence: Precedence,
+    /// Extra spelling of `*`; see `tokenize_with`.
+    mul_symbol: Option<char>,
+    number_format: NumberFormat,
+    /// Bind `SPECIAL_VALUES`; after `constants`, before `PHYSICAL_CONSTANTS`.
+    allow_special: bool,
+    /// The largest number literal the source may contain.
+    max_literal: Option<f64>,
+    /// The only variable names the source may use, if restricted.
+    allowed_vars: Option<Vec<String>>,
+    /// Return the tree as parsed, with nothing folded; see `parse_infix`.
+    preserve: bool,
+}
+
+impl ParseOptions {
+    fn constant(&self, name: &str) -> Option<f64> {
+        let special = || SPECIAL_VALUES.iter().find(|(n, _)| *n == name).map(|(_, v)| *v);
+        self.constants
+            .get(name)
+            .copied()
+            .or_else(|| if self.allow_special { special() } else { None })
+            .or_else(|| if self.physical_constants { physical_constant(name) } else { None })
+    }
+}
+
+fn parse_with_options(expression: &str, options: &ParseOptions) -> PyResult<Expr> {
+    Ok(parse_stages(expression, options, false)?.1)
+}
+
+/// `parse_with_options`, also returning the tree as parsed, before constants
+/// are bound and anything is folded, when `keep_raw` is set. Keeping it
+/// skips the constant-chain fast path, which builds no tree.
+fn parse_stages(
+    expression: &str,
+    options: &ParseOptions,
+    keep_raw: bool,
+) -> Result<(Option<Expr>, Expr), Failure> {
+    let limits = &options.limits;
+    check_parens(expression).map_err(Failure::Parse)?;
+    let tokens = tokenize_with(expression, options.mul_symbol, options.number_format);
+    if let Some(message) = tokens.iter().find_map(malformed_number) {
+        return Err(Failure::Tokenize(message));
+    }
+    if let Some(max) = options.max_literal {
+        for token in &tokens {
+            let value = parse_number(&token.text);
+            if let Some(value) = value.filter(|&v| v > max && !is_identifier(&token.text)) {
+                return Err(Failure::Limit(format!(
+                    "max_literal exceeded: literal {} > {} at position {}",
+                    value, max, token.pos
+                )));
+            }
+        }
+    }
+    check_limit("max_tokens", limits.max_tokens, tokens.len())?;
+    check_limit("max_call_depth", limits.max_call_depth, call_depth(&tokens))?;
+    check_nesting(&tokens, &options.precedence).map_err(Failure::Limit)?;
+    // The fast path folds left to right, which assumes the default levels.
+    let fast_path = options.precedence == Precedence::default() && !keep_raw && !options.preserve;
+    if let Some(total) = fold_constant_chain(&tokens).filter(|_| fast_path) {
+        // The tree this skips would be a left-leaning chain of `terms` leaves.
+        let terms = tokens.len() / 2 + 1;
+        check_limit("max_depth", limits.max_depth, terms)?;
+        check_limit("max_nodes", limits.max_nodes, 2 * terms - 1)?;
+        return Ok((None, Expr::Number(total)));
+    }
+    let expr = parse_statement_with(&tokens, &options.precedence).map_err(Failure::Parse)?;
+    check_limit("max_depth", limits.max_depth, depth(&expr))?;
+    check_limit("max_nodes", limits.max_nodes, node_count(&expr))?;
+    let raw = keep_raw.then(|| expr.clone());
+    let binds =
+        options.physical_constants || options.allow_special || !options.constants.is_empty();
+    let expr = if binds {
+        bind_constants(expr, &|name| options.constant(name))
+    } else {
+        expr
+    };
+    if limits.max_variables.is_some() {
+        let mut variables = Vec::new();
+        collect_variables(&expr, &mut variables);
+        check_limit("max_variables", limits.max_variables, variables.len())?;
+    }
+    if let Some(allowed) = &options.allowed_vars {
+        check_allowed_vars(&expr, allowed)?;
+    }
+    if options.preserve {
+        return Ok((raw, expr));
+    }
+    let expr = simplify(expr, &options.fold);
+    if let Some(message) = rejected_fold(&expr, &options.fold) {
+        return Err(Failure::Evaluation(message));
+    }
+    Ok((raw, if options.balance { balance(expr) } else { expr }))
+}
+
+/// Why the first builtin call or shift left with only constant operands has
+/// them, i.e. folding rejected them, as in `gcd(1.5, 3)`, `atanh(2)` or
+/// `1 << 64`.
+fn rejected_fold(expr: &Expr, fold: &FoldOptions) -> Option<String> {
+    // Only the branch a constant condition takes is ever evaluated.
+    if let Expr::Call { name, args } = expr {
+        if let (true, [Expr::Number(cond), then, otherwise]) = (name == "if", &args[..]) {
+            return rejected_fold(if *cond != 0.0 { then } else { otherwise }, fold);
+        }
+    }
+    if let Expr::Binary { op, left, right } = expr {
+        if let (Expr::Number(a), Expr::Number(b)) = (&**left, &**right) {
+            return try_apply_binary(op, *a, *b).err();
+        }
+    }
+    if let Expr::Call { name, args } = expr {
+        let builtin = FUNCTIONS.iter().any(|(n, _, _)| n == name);
+        let values: Option<Vec<f64>> = args
+            .iter()
+            .map(|arg| match arg {
+                Expr::Number(n) => Some(*n),
+                _ => None,
+            })
+            .collect();
+        if let Some(values) = values.filter(|_| builtin) {
+            return apply_function(name, &values, fold).err();
+        }
+    }
+    children(expr).into_iter().find_map(|child| rejected_fold(child, fold))
+}
+
+/// Raise `ParseError` for the first variable of `expr` not in `allowed`.
+/// Function names are not variables, so they pass regardless.
+fn check_allowed_vars(expr: &Expr, allowed: &[String]) -> Result<(), Failure> {
+    let mut variables = Vec::new();
+    collect_variables(expr, &mut variables);
+    match variables.into_iter().find(|name| !allowed.contains(name)) {
+        Some(name) => Err(Failure::Parse(format!(
+            "variable '{}' is not allowed; allowed_vars is [{}]",
+            name,
+            allowed.join(", ")
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Reject a `mul_symbol` that would change how the rest of the source
+/// splits: identifier and number characters other than `.`, whitespace, and
+/// the grouping and separator punctuation.
+fn check_mul_symbol(symbol: char) -> PyResult<char> {
+    if symbol.is_whitespace() || is_identifier_continue(symbol) || "(),;".contains(symbol) {
+        return Err(PyValueError::new_err(format!(
+            "mul_symbol {:?} would be ambiguous with the rest of the grammar",
+            symbol
+        )));
+    }
+    Ok(symbol)
+}
+
+/// Parse and simplify `expression`. `limits` may hold `max_tokens`,
+/// `max_depth`, `max_nodes`, `max_call_depth` and `max_variables`; the
+/// `LimitError` raised names the one tripped. Whatever the limits, input
+/// nested more than `MAX_NESTING` (1000) levels deep, such as 600 nested
+/// `-(`, raises `LimitError` before it is parsed. A builtin given constant
+/// arguments it rejects, such as `gcd(1.5, 3)` or `atanh(2)`, raises
+/// `EvaluationError`, as does a constant shift out of range, such as
+/// `1 << 64`.
+/// `naming` selects the node `"type"` strings: `"default"` (`Binary`) or
+/// `"snake"` (`binary_op`). `physical_constants` folds the names in
+/// `PHYSICAL_CONSTANTS` to their SI values, and `constants` maps further
+/// identifiers to numbers; any other identifier stays symbolic.
+/// `banker_rounding` makes `round` send halves to even rather than away from
+/// zero. `balance` regroups `+` and `*` chains into balanced trees.
+/// `precedence` maps arithmetic operators to binding levels, overriding the
+/// defaults described on `Precedence`; `{"^": 15}` makes `2*3^2` group as
+/// `(2*3)^2`. `mul_symbol` is a character to read as `*`, such as `·` or
+/// `.`; for `.` the rule on `tokenize_with` keeps `3.4` a number.
+/// `degrees` makes `sin`, `cos` and `tan` take degrees, and `asin`, `acos`,
+/// `atan` and `atan2` return them. `allow_special` reads `inf`, `infinity`
+/// and `nan` as the IEEE values, which then fold by IEEE rules (`inf - inf`
+/// is `nan`, `1/inf` is `0`). `combine_like_terms`
+/// merges symbolic terms that differ only in a constant coefficient, so
+/// `x + x` becomes `2*x` and `3*x + 2*x` becomes `5*x`. `number_format`
+/// reads grouped literals: `"us"` takes `1,234.56` and `"eu"` `1.234,56`;
+/// see `tokenize_with` for how this coexists with argument commas.
+/// `return_both` returns `{"raw": ast, "simplified": ast}` from one parse,
+/// where `raw` is the tree before constants are bound or anything folds.
+/// `max_literal` raises `LimitError` for a number literal above it in the
+/// source; values computed from smaller literals are not limited.
+/// `with_precedence` adds `"precedence"` and `"assoc"` (`"left"`, `"right"`
+/// or `"chain"`) to every `Binary` and `Unary` node, taken from the table
+/// the parse used, so a renderer needs no table of its own. Precedence is the
+/// binding power, higher binding tighter; prefix operators are `"right"`.
+/// `allowed_vars` lists the only variable names the source may use, others
+/// raising `ParseError` with the name; it is checked before simplifying, so
+/// a variable that would cancel out still counts, while names bound by
+/// `constants` or the constant tables are not variables and need no entry.
+/// `distribute` multiplies constants through sums, so `2*(x+3)` becomes
+/// `2*x + 6`; it is off by default since it can grow the tree. Nothing
+/// factors a constant back out, so it combines with `combine_like_terms`,
+/// which then merges the terms it produces: `2*(x+1) + 3*x` is `5*x + 2`
+/// with both set.
+/// `preserve` returns the tree exactly as the source spells it, for display:
+/// nothing folds or reorders, and each sign keeps its own node, so `- -x` and
+/// `+x` are `Unary` nodes and `1 + 2 * 3 - 4` stays three operators. The
+/// source is still fully validated, and `constants` are still bound. The
+/// grammar's own sugar is still read through, so `50%` is `50 / 100` and
+/// `piecewise` is nested `if` calls. It cannot be combined with `balance`,
+/// `combine_like_terms` or `distribute`, which only act on folding.
+#[pyfunction]
+#[pyo3(signature = (expression, **options))]
+fn parse_infix(expression: &str, options: Option<&Bound<'_, PyDict>>) -> PyResult<PyObject> {
+    ParseConfig::new(options)?.parse(expression)
+}
+
+/// Declare a struct of keyword arguments with their defaults, extracted from
+/// a `**kwargs` dict; `NAMES` lists them in declaration order. A keyword the
+/// struct lacks raises `TypeError`, as a Python signature would, listing the
+/// accepted ones.
+macro_rules! keywords {
+    (
+        $(#[$meta:meta])*
+        struct $name:ident for $function:literal {
+            $($field:ident: $ty:ty = $default:expr,)*
+        }
+    ) => {
+        $(#[$meta])*
+        struct $name {
+            $($field: $ty,)*
+        }
+
+        impl $name {
+            const NAMES: &'static [&'static str] = &[$(stringify!($field)),*];
+
+            fn extract(kwargs: Option<&Bound<'_, PyDict>>) -> PyResult<Self> {
+                let mut keywords = $name { $($field: $default,)* };
+                for (key, value) in kwargs.into_iter().flat_map(|kwargs| kwargs.iter()) {
+                    let key: String = key.extract()?;
+                    let argument = |err: PyErr| {
+                        let reason = err.value_bound(value.py()).to_string();
+                        PyTypeError::new_err(format!("argument '{}': {}", key, reason))
+                    };
+                    match key.as_str() {
+                        $(stringify!($field) => {
+                            keywords.$field = value.extract().map_err(argument)?
+                        })*
+                        _ => {
+                            return Err(PyTypeError::new_err(format!(
+                                "{}() got an unexpected keyword argument '{}'; expected {}",
+                                $function,
+                                key,
+                                Self::NAMES.join(", ")
+                            )))
+                        }
+                    }
+                }
+                Ok(keywords)
+            }
+        }
+    };
+}
+
+keywords! {
+    /// The keywords of `parse_infix` and `ParseConfig`, documented on
+    /// `parse_infix`.
+    struct ParseKeywords for "parse_infix" {
+        limits: Option<Py<PyDict>> = None,
+        naming: String = "default".to_string(),
+        physical_constants: bool = false,
+        constants: Option<HashMap<String, f64>> = None,
+        banker_rounding: bool = false,
+        balance: bool = false,
+        precedence: Option<Py<PyDict>> = None,
+        mul_symbol: Option<char> = None,
+        degrees: bool = false,
+        allow_special: bool = false,
+        combine_like_terms: bool = false,
+        number_format: String = "plain".to_string(),
+        return_both: bool = false,
+        max_literal: Option<f64> = None,
+        with_precedence: bool = false,
+        distribute: bool = false,
+        allowed_vars: Option<Vec<String>> = None,
+        preserve: bool = false,
+    }
+}
+
+/// `parse_infix` settings captured once and reused: `ParseConfig(degrees=True)`
+/// takes the same keywords as `parse_infix`, and `.parse(expression)` applies
+/// them.
+#[pyclass]
+struct ParseConfig {
+    options: ParseOptions,
+    naming: Naming,
+    return_both: bool,
+    with_precedence: bool,
+}
+
+#[pymethods]
+impl ParseConfig {
+    #[new]
+    #[pyo3(signature = (**options))]
+    fn new(options: Option<&Bound<'_, PyDict>>) -> PyResult<Self> {
+        let ParseKeywords {
+            limits,
+            naming,
+            physical_constants,
+            constants,
+            banker_rounding,
+            balance,
+            precedence,
+            mul_symbol,
+            degrees,
+            allow_special,
+            combine_like_terms,
+            number_format,
+            return_both,
+            max_literal,
+            with_precedence,
+            distribute,
+            allowed_vars,
+            preserve,
+        } = ParseKeywords::extract(options)?;
+        let limits = Python::with_gil(|py| limits.map(|d| Limits::from_dict(d.bind(py))));
+        let precedence =
+            Python::with_gil(|py| precedence.map(|d| Precedence::from_dict(d.bind(py))));
+        let (limits, precedence) = (limits.transpose()?, precedence.transpose()?);
+        let (limits, precedence) = (limits.unwrap_or_default(), precedence.unwrap_or_default());
+        let format = NumberFormat::parse(&number_format)?;
+        let mul_symbol = mul_symbol.map(check_mul_symbol).transpose()?;
+        if let (Some(symbol), Some((group, decimal))) = (mul_symbol, format.marks()) {
+            if symbol == group || symbol == decimal {
+                return Err(PyValueError::new_err(format!(
+                    "mul_symbol {:?} is a number separator under number_format '{}'",
+                    symbol, number_format
+                )));
+            }
+        }
+        let folding = [
+            ("balance", balance),
+            ("combine_like_terms", combine_like_terms),
+            ("distribute", distribute),
+        ];
+        if let Some((name, _)) = folding.iter().find(|(_, set)| *set).filter(|_| preserve) {
+            return Err(PyValueError::new_err(format!(
+                "preserve disables folding, so it cannot be combined with {}",
+                name
+            )));
+        }
+        let precedence = Precedence { keep_signs: preserve, ..precedence };
+        let options = ParseOptions {
+            limits,
+            physical_constants,
+            constants: constants.unwrap_or_default(),
+            fold: FoldOptions {
+                banker_rounding,
+                degrees,
+                like_terms: combine_like_terms,
+                distribute,
+            },
+            balance,
+            precedence,
+            mul_symbol,
+            number_format: format,
+            allow_special,
+            max_literal,
+            allowed_vars,
+            preserve,
+        };
+        let naming = Naming::parse(&naming)?;
+        Ok(ParseConfig { options, naming, return_both, with_precedence })
+    }
+
+    fn parse(&self, expression: &str) -> PyResult<PyObject> {
+        let (raw, expr) = parse_stages(expression, &self.options, self.return_both)?;
+        let precedence = self.with_precedence.then_some(&self.options.precedence);
+        Python::with_gil(|py| {
+            let to_py = |expr| to_py_annotated(py, expr, self.naming, precedence);
+            match raw {
+                Some(raw) => {
+                    let dict = PyDict::new_bound(py);
+                    dict.set_item("raw", to_py(raw))?;
+                    dict.set_item("simplified", to_py(expr))?;
+                    Ok(dict.into_py(py))
+                }
+                None => Ok(to_py(expr)),
+            }
+        })
+    }
+}
+
+/// Parse each expression, returning `(True, ast)` or `(False, message)` per
+/// item. With `stop_on_error`, the first failure is raised instead, its
+/// message prefixed with the item's index.
+#[pyfunction]
+#[pyo3(signature = (expressions, stop_on_error=false, limits=None))]
+fn parse_infix_batch(
+    py: Python<'_>,
+    expressions: Vec<String>,
+    stop_on_error: bool,
+    limits: Option<&Bound<'_, PyDict>>,
+) -> PyResult<Vec<(bool, PyObject)>> {
+    let options = ParseOptions {
+        limits: limits.map(Limits::from_dict).transpose()?.unwrap_or_default(),
+        ..ParseOptions::default()
+    };
+    let results = parse_batch(&expressions, &options, stop_on_error)?;
+    Ok(results
+        .into_iter()
+        .map(|result| match result {
+            Ok(expr) => (true, to_py(py, expr, Naming::Default)),
+            Err(message) => (false, message.into_py(py)),
+        })
+        .collect())
+}
+
+/// Parse each expression, keeping each failure's message in its place. With
+/// `stop_on_error` the first failure is returned instead, its message
+/// prefixed with the expression's index.
+fn parse_batch(
+    expressions: &[String],
+    options: &ParseOptions,
+    stop_on_error: bool,
+) -> Result<Vec<Result<Expr, String>>, Failure> {
+    let mut results = Vec::with_capacity(expressions.len());
+    for (index, expression) in expressions.iter().enumerate() {
+        match parse_stages(expression, options, false) {
+            Ok((_, expr)) => results.push(Ok(expr)),
+            Err(failure) if stop_on_error => {
+                return Err(failure.map(|message| format!("expression {}: {}", index, message)));
+            }
+            Err(failure) => results.push(Err(failure.message().to_string())),
+        }
+    }
+    Ok(results)
+}
+
+/// Iterator over the lines of a file-like object, parsing one expression per
+/// line as it is read. Returned by `parse_lines`.
+#[pyclass]
+struct LineParser {
+    lines: Py<PyIterator>,
+    line_number: usize,
+}
+
+#[pymethods]
+impl LineParser {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(&mut self, py: Python<'_>) -> PyResult<Option<(usize, bool, PyObject)>> {
+        let mut lines = self.lines.bind(py).clone();
+        for line in &mut lines {
+            self.line_number += 1;
+            let line: String = line?.extract()?;
+            return Ok(Some(match parse_line(&line) {
+                None => continue,
+                Some(Ok(expr)) => (self.line_number, true, to_py(py, expr, Naming::Default)),
+                Some(Err(message)) => (self.line_number, false, message.into_py(py)),
+            }));
+        }
+        Ok(None)
+    }
+}
+
+/// The tree or error message `LineParser` yields for `line`, or None for a
+/// blank line, which it skips.
+fn parse_line(line: &str) -> Option<Result<Expr, String>> {
+    if line.trim().is_empty() {
+        return None;
+    }
+    let parsed = parse_stages(line, &ParseOptions::default(), false);
+    Some(parsed.map(|(_, expr)| expr).map_err(|failure| failure.message().to_string()))
+}
+
+/// Parse the lines of `reader`, any iterable of strings such as an open text
+/// file, lazily: the result yields `(line_number, True, ast)` or
+/// `(line_number, False, message)` per non-blank line, numbering from 1 and
+/// counting blank lines, so a bad line doesn't end the stream.
+#[pyfunction]
+fn parse_lines(reader: &Bound<'_, PyAny>) -> PyResult<LineParser> {
+    Ok(LineParser { lines: reader.iter()?.unbind(), line_number: 0 })
+}
+
+/// The innermost node of the unsimplified tree of `expression` whose source
+/// span contains the character offset `position`, or None if no node does
+/// (e.g. past the end). In `1 + 2*3`, position 5 (the `*`) gives the `2*3`
+/// node and position 4 just the `2`.
+/// A parenthesized node's span stops inside its parentheses, so a position
+/// on a parenthesis picks the enclosing node.
+#[pyfunction]
+fn node_at(py: Python<'_>, expression: &str, position: usize) -> PyResult<Option<PyObject>> {
+    let node = innermost_node(expression, position).map_err(ParseError::new_err)?;
+    Ok(node.map(|node| to_py(py, node, Naming::Default)))
+}
+
+/// The node `node_at` returns, as an `Expr`.
+fn innermost_node(expression: &str, position: usize) -> Result<Option<Expr>, String> {
+    check_parens(expression)?;
+    let (expr, spans) = parse_spanned(&tokenize(expression), &Precedence::default())?;
+    let mut nodes = Vec::new();
+    post_order(&expr, &mut nodes);
+    debug_assert_eq!(nodes.len(), spans.len());
+    // Children come before their parents, so the first of the narrowest
+    // containing spans is the innermost node.
+    let innermost = nodes
+        .into_iter()
+        .zip(spans)
+        .filter(|(_, (start, end))| (*start..*end).contains(&position))
+        .min_by_key(|(_, (start, end))| end - start);
+    Ok(innermost.map(|(node, _)| node.clone()))
+}
+
+/// One simplifying pass over the parsed tree of `expression`, returning the
+/// AST and whether the pass changed anything. A pass folds bottom-up, so
+/// most inputs are already stable after it; callers rewriting in a loop can
+/// stop once `changed` is False.
+#[pyfunction]
+fn simplify_once(py: Python<'_>, expression: &str) -> PyResult<(PyObject, bool)> {
+    let expr = parse_source(expression).map_err(ParseError::new_err)?;
+    let (simplified, changed) = simplify_pass(expr, &FoldOptions::default());
+    Ok((to_py(py, simplified, Naming::Default), changed))
+}
+
+/// One `simplify` pass, and whether it changed `expr`.
+fn simplify_pass(expr: Expr, fold: &FoldOptions) -> (Expr, bool) {
+    let simplified = simplify(expr.clone(), fold);
+    let changed = simplified != expr;
+    (simplified, changed)
+}
+
+/// Most passes `simplify_full` makes before giving up on reaching a fixpoint.
+const MAX_SIMPLIFY_PASSES: usize = 16;
+
+/// Simplify the parsed tree of `expression` pass after pass until one
+/// changes nothing, returning that stable AST. `combine_like_terms` and
+/// `distribute` are as for `parse_infix`; these are where one pass can
+/// expose work for the next. A tree still changing after
+/// `MAX_SIMPLIFY_PASSES` raises `LimitError` rather than looping on rules
+/// that undo each other.
+#[pyfunction]
+#[pyo3(signature = (expression, combine_like_terms=false, distribute=false))]
+fn simplify_full(
+    py: Python<'_>,
+    expression: &str,
+    combine_like_terms: bool,
+    distribute: bool,
+) -> PyResult<PyObject> {
+    let fold = FoldOptions { like_terms: combine_like_terms, distribute, ..FoldOptions::default() };
+    let expr = parse_source(expression).map_err(ParseError::new_err)?;
+    let (expr, _) = settle(expr, &fold).map_err(LimitError::new_err)?;
+    Ok(to_py(py, expr, Naming::Default))
+}
+
+/// Simplify `expr` until a pass changes nothing, returning the stable tree
+/// and how many passes changed it.
+fn settle(mut expr: Expr, fold: &FoldOptions) -> Result<(Expr, usize), String> {
+    for passes in 0..MAX_SIMPLIFY_PASSES {
+        let (simplified, changed) = simplify_pass(expr, fold);
+        if !changed {
+            return Ok((simplified, passes));
+        }
+        expr = simplified;
+    }
+    Err(format!("simplify_full did not settle within {} passes", MAX_SIMPLIFY_PASSES))
+}
+
+/// The character offset a parse error message ends with ("... at position
+/// N"), or None for errors not tied to one place, such as a missing `)`.
+fn error_position(message: &str) -> Option<usize> {
+    message.rsplit_once("at position ")?.1.parse().ok()
+}
+
+/// Parse `expression` without raising for bad input: the result is
+/// `{"ok": True, "ast": {...}}`, or `{"ok": False, "error": message,
+/// "position": N}` where `position` is None when the error has no single
+/// location. Only errors other than `ParseError` propagate.
+#[pyfunction]
+fn try_parse(py: Python<'_>, expression: &str) -> PyResult<PyObject> {
+    let result = PyDict::new_bound(py);
+    match parse_with_options(expression, &ParseOptions::default()) {
+        Ok(expr) => {
+            result.set_item("ok", true)?;
+            result.set_item("ast", to_py(py, expr, Naming::Default))?;
+        }
+        Err(err) if err.is_instance_of::<ParseError>(py) => {
+            let message = err.value_bound(py).to_string();
+            let position = error_position(&message);
+            result.set_item("ok", false)?;
+            result.set_item("error", message)?;
+            result.set_item("position", position)?;
+        }
+        Err(err) => return Err(err),
+    }
+    Ok(result.into_any().unbind())
+}
+
+/// Evaluate `expression` with `vars` bound to arbitrary Python values. Array
+/// operands (e.g. NumPy) are combined elementwise by their own operators.
+/// The tree is not simplified first, since cancelling `x - x` to `0` would
+/// turn an array result into a scalar. `timeout_ms` is as for
+/// `evaluate_with`.
+#[pyfunction]
+#[pyo3(signature = (expression, vars, timeout_ms=None))]
+fn evaluate_array(
+    expression: &str,
+    vars: &Bound<'_, PyDict>,
+    timeout_ms: Option<u64>,
+) -> PyResult<PyObject> {
+    let mode = ObjectEval { deadline: deadline(timeout_ms), ..ObjectEval::default() };
+    let expr = parse_source(expression).map_err(ParseError::new_err)?;
+    Ok(evaluate_object(&expr, vars, mode)?.unbind())
+}
+
+keywords! {
+    /// The keywords of `evaluate_with`, documented there.
+    struct EvaluateKeywords for "evaluate_with" {
+        int_overflow: String = "float".to_string(),
+        auto_exact: bool = false,
+        undefined_default: Option<f64> = None,
+        division: String = "float".to_string(),
+        timeout_ms: Option<u64> = None,
+        symbolic_fallback: bool = false,
+    }
+}
+
+/// Evaluate `expression` with `vars` bound to Python numbers, keeping `int`
+/// values exact: `+`, `-`, `*` and `//` on `int`s and integral literals
+/// produce a Python `int` of any size, while `/` or any `float` operand gives
+/// a `float` as in Python. Builtin calls always return `float`.
+///
+/// The spelling of a literal picks its type: one with neither a decimal
+/// point nor an exponent is an `int`, and the rest are `float`s, so under
+/// `division="fraction"` `3/2` is `Fraction(3, 2)` but `3.0/2` and `3e0/2`
+/// are `1.5`. `int` literals are exact within the `i64` range. A larger one,
+/// such as `99999999999999999999999`, falls back to the nearest `float` with
+/// a `RuntimeWarning` by default, or raises `ParseError` with
+/// `int_overflow="error"`.
+///
+/// With `auto_exact`, `/` picks the most natural exact type:
+///
+/// | operands                 | result                               |
+/// |--------------------------|--------------------------------------|
+/// | `int` or `Fraction` only | `int` if integral, else `Fraction`   |
+/// | any `float`              | `float`                              |
+///
+/// so `1/2` is `Fraction(1, 2)`, `4/2` is `2` and `1.0/2` is `0.5`. Other
+/// operators on exact operands also turn an integral `Fraction` result into
+/// an `int`, so `1/2 + 1/2` is `1`.
+///
+/// A `complex` variable promotes as in Python: any `complex` operand of
+/// `+ - * / ^` makes the result `complex`, so `x*x` with `x = 1j` is
+/// `(-1+0j)`, and a real result of complex arithmetic stays `complex`. The
+/// ordering comparisons, `//`, `%`, shifts, `and` and the builtins are not
+/// defined on complex values and raise `EvaluationError`.
+///
+/// Without `auto_exact`, `division` picks the type of `/` alone:
+///
+/// | `division`         | `6/3` | `7/3`            |
+/// |--------------------|-------|------------------|
+/// | `"float"`          | `2.0` | `2.333...`       |
+/// | `"int_when_exact"` | `2`   | `2.333...`       |
+/// | `"always_float"`   | `2.0` | `2.333...`       |
+/// | `"fraction"`       | `2`   | `Fraction(7, 3)` |
+///
+/// `"float"` is Python's `/`, so a `Fraction` or `Decimal` operand keeps its
+/// type, whereas `"always_float"` converts both operands with `float()`
+/// first. `auto_exact` divides as `"fraction"`, so it only combines with
+/// that mode or the default.
+///
+/// `<<` and `>>` are limited to 64-bit integers as when folding, so
+/// `1 << 64`, `1 << -1` and `2.5 << 1` raise `EvaluationError`.
+///
+/// `and` gives `True` or `False` as a comparison does, and doesn't evaluate
+/// its right side once the left is 0, so `0 and y` needs no `y`.
+///
+/// `Fraction` and `Decimal` variables are used as given, never converted to
+/// `float`, so `x*3` with `x = Fraction(1, 3)` is `Fraction(1, 1)`. Either
+/// combines with `int`s in its own type, and a `Fraction` with a `float`
+/// gives a `float`. Python has no arithmetic between a `Decimal` and a
+/// `Fraction` or `float`, so mixing them raises `EvaluationError` rather than
+/// guessing a precision; comparisons between them are allowed. Only
+/// `division="always_float"` and the builtins, which work on `float`s,
+/// convert either type.
+///
+/// `timeout_ms` bounds the time from the call, parsing included, raising
+/// `TimeoutError` once it is exceeded. The clock is checked at each node
+/// boundary, before the node is evaluated, so a huge sum, such as 65536
+/// terms of `x` nested in pairs, stops at the first node past the deadline.
+/// A single node that runs long is only interrupted by an `int` power,
+/// which also checks between its multiplications, so `x^y` with
+/// `y = 10**6` stops in time; a builtin call finishes first. An `int` power
+/// that would have more than `MAX_POW_BITS` (about 4 million) bits, such as
+/// `x^x^x` with `x = 9`, raises `LimitError` with or without a timeout.
+///
+/// A variable missing from `vars` raises `EvaluationError` unless
+/// `undefined_default` is given, in which case it takes that value.
+///
+/// `symbolic_fallback` changes the result to an `(is_numeric, result)` pair
+/// for callers holding partial bindings: `(True, value)` when every variable
+/// is bound, and `(False, infix)` with the simplified expression, such as
+/// `"x + 1"`, when some are not. `undefined_default` binds every variable,
+/// so with it the pair is always numeric.
+#[pyfunction]
+#[pyo3(signature = (expression, vars, **options))]
+fn evaluate_with(
+    expression: &str,
+    vars: &Bound<'_, PyDict>,
+    options: Option<&Bound<'_, PyDict>>,
+) -> PyResult<PyObject> {
+    let EvaluateKeywords {
+        int_overflow,
+        auto_exact,
+        undefined_default,
+        division,
+        timeout_ms,
+        symbolic_fallback,
+    } = EvaluateKeywords::extract(options)?;
+    let deadline = deadline(timeout_ms);
+    let int_overflow = IntOverflow::parse(&int_overflow)?;
+    let division = Division::parse(&division)?
+        .with_auto_exact(auto_exact)
+        .map_err(|_| {
+            PyValueError::new_err(format!(
+                "division '{}' conflicts with auto_exact, which divides as 'fraction'",
+                division
+            ))
+        })?;
+    check_parens(expression).map_err(ParseError::new_err)?;
+    let tokens = tokenize(expression);
+    check_nesting(&tokens, &Precedence::default()).map_err(LimitError::new_err)?;
+    if let Some(token) = inexact_int_literal(&tokens) {
+        let problem = format!(
+            "integer literal '{}' at position {} cannot be represented exactly",
+            token.text, token.pos
+        );
+        match int_overflow {
+            IntOverflow::Error => return Err(ParseError::new_err(problem)),
+            IntOverflow::Float => {
+                let py = vars.py();
+                let category = py.get_type_bound::<PyRuntimeWarning>();
+                let message = format!("{}; rounding to the nearest float", problem);
+                PyErr::warn_bound(py, &category, &message, 1)?;
+            }
+        }
+    }
+    let expr = parse_statement_expr(&mark_float_literals(tokens)).map_err(ParseError::new_err)?;
+    let py = vars.py();
+    if symbolic_fallback && undefined_default.is_none() {
+        let mut names = Vec::new();
+        collect_variables(&expr, &mut names);
+        for name in names {
+            if !vars.contains(name)? {
+                return Ok((false, symbolic_form(expr)).into_py(py));
+            }
+        }
+    }
+    let mode =
+        ObjectEval { int_literals: true, auto_exact, division, undefined_default, deadline };
+    let value = evaluate_object(&expr, vars, mode)?.unbind();
+    Ok(if symbolic_fallback { (true, value).into_py(py) } else { value })
+}
+
+/// The simplified infix `symbolic_fallback` returns for a tree parsed from
+/// `mark_float_literals` tokens.
+fn symbolic_form(expr: Expr) -> String {
+    to_infix(&simplify(unmark_float_literals(expr), &FoldOptions::default()))
+}
+
+/// Evaluate the unsimplified tree of `expression` with `vars` bound, returning
+/// one `{"node": infix, "value": float}` dict per evaluated subexpression in
+/// post-order, so `2*(3+4)` ends with `3 + 4` at 7 and then the whole
+/// product at 14. The last entry is the result.
+#[pyfunction]
+fn evaluate_trace(
+    py: Python<'_>,
+    expression: &str,
+    vars: HashMap<String, f64>,
+) -> PyResult<Vec<PyObject>> {
+    let expr = parse_source(expression).map_err(ParseError::new_err)?;
+    let env: Vec<(String, f64)> = vars.into_iter().collect();
+    let mut steps = Vec::new();
+    evaluate_observed(&expr, &env, &mut |node, value| steps.push((to_infix(node), value)))
+        .map_err(EvaluationError::new_err)?;
+    steps
+        .into_iter()
+        .map(|(node, value)| {
+            let step = PyDict::new_bound(py);
+            step.set_item("node", node)?;
+            step.set_item("value", value)?;
+            Ok(step.into_any().unbind())
+        })
+        .collect()
+}
+
+/// Replace every occurrence of `target` in `expression` with `replacement`
+/// and return the result as infix. Matching is structural-exact on the
+/// unsimplified trees, so `x + 1` does not match `1 + x`.
+#[pyfunction]
+fn replace(expression: &str, target: &str, replacement: &str) -> PyResult<String> {
+    let expr = parse_source(expression).map_err(ParseError::new_err)?;
+    let target = parse_source(target).map_err(ParseError::new_err)?;
+    let replacement = parse_source(replacement).map_err(ParseError::new_err)?;
+    Ok(to_infix(&replace_subtree(expr, &target, &replacement)))
+}
+
+/// Rename the variable `old` to `new` throughout the unsimplified tree of
+/// `expression` and return the result as infix; function names, including
+/// a call named `old`, are left alone. `new` must be a valid variable name,
+/// and one that already appears as another variable raises `ValueError`,
+/// since the rename would merge two variables.
+#[pyfunction]
+fn rename_variable(expression: &str, old: &str, new: &str) -> PyResult<String> {
+    let expr = parse_source(expression).map_err(ParseError::new_err)?;
+    let renamed = renamed_variable(expr, old, new).map_err(PyValueError::new_err)?;
+    Ok(to_infix(&renamed))
+}
+
+fn renamed_variable(expr: Expr, old: &str, new: &str) -> Result<Expr, String> {
+    if !is_identifier(new) || WORD_OPS.contains(&new) {
+        return Err(format!("invalid variable name '{}'", new));
+    }
+    if old != new {
+        let mut names = Vec::new();
+        collect_variables(&expr, &mut names);
+        if names.iter().any(|name| name == new) {
+            return Err(format!(
+                "renaming '{}' to '{}' would merge it with the existing variable '{}'",
+                old, new, new
+            ));
+        }
+    }
+    let target = Expr::Variable(old.to_string());
+    Ok(replace_subtree(expr, &target, &Expr::Variable(new.to_string())))
+}
+
+/// Operator or function name at the root of the unsimplified tree, or `None`
+/// for a bare number or variable.
+#[pyfunction]
+fn root_op(expression: &str) -> PyResult<Option<String>> {
+    let expr = parse_source(expression).map_err(ParseError::new_err)?;
+    Ok(root_name(&expr).map(str::to_string))
+}
+
+fn root_name(expr: &Expr) -> Option<&str> {
+    match expr {
+        Expr::Unary { op, .. } | Expr::Binary { op, .. } => Some(op),
+        Expr::Call { name, .. } => Some(name),
+        Expr::Number(_) | Expr::Variable(_) => None,
+    }
+}
+
+/// Graphviz DOT rendering of the unsimplified parse tree, suitable for
+/// `dot -Tpng`.
+#[pyfunction]
+fn to_dot(expression: &str) -> PyResult<String> {
+    let expr = parse_source(expression).map_err(ParseError::new_err)?;
+    Ok(dot_graph(&expr))
+}
+
+fn dot_graph(expr: &Expr) -> String {
+    let mut out = String::from("digraph AST {\n");
+    write_dot(expr, &mut 0, &mut out);
+    out.push_str("}\n");
+    out
+}
+
+/// Add `expr` and its subtree to the `nodes` and `(parent, child, label)`
+/// `edges` of `to_edge_list`, numbering nodes in pre-order.
+fn collect_edges<'e>(
+    expr: &'e Expr,
+    nodes: &mut Vec<&'e Expr>,
+    edges: &mut Vec<(usize, usize, String)>,
+) {
+    let id = nodes.len();
+    nodes.push(expr);
+    let labels: Vec<String> = match expr {
+        Expr::Unary { .. } => vec!["expr".to_string()],
+        Expr::Binary { .. } => vec!["left".to_string(), "right".to_string()],
+        Expr::Call { args, .. } => (0..args.len()).map(|i| format!("arg{}", i)).collect(),
+        Expr::Number(_) | Expr::Variable(_) => Vec::new(),
+    };
+    for (child, label) in children(expr).into_iter().zip(labels) {
+        // The child takes the next id, so its edge can precede its subtree's.
+        edges.push((id, nodes.len(), label));
+        collect_edges(child, nodes, edges);
+    }
+}
+
+/// The unsimplified tree of `expression` as flat lists, for loading into
+/// graph tools: `{"nodes": [...], "edges": [[parent, child, label], ...]}`.
+/// Nodes are numbered from 0 in pre-order, so the root is 0, and each has
+/// `id` and `type` plus its `value`, `name` or `op` as in the nested AST.
+/// Edge labels are `left` and `right` for binary operators, `expr` for
+/// unary ones and `arg0`, `arg1`, ... for call arguments.
+#[pyfunction]
+fn to_edge_list(py: Python<'_>, expression: &str) -> PyResult<PyObject> {
+    let expr = parse_source(expression).map_err(ParseError::new_err)?;
+    let (mut nodes, mut edges) = (Vec::new(), Vec::new());
+    collect_edges(&expr, &mut nodes, &mut edges);
+    let mut node_dicts = Vec::with_capacity(nodes.len());
+    for (id, expr) in nodes.into_iter().enumerate() {
+        let node = PyDict::new_bound(py);
+        node.set_item("id", id)?;
+        node.set_item("type", Naming::Default.type_name(expr))?;
+        match expr {
+            Expr::Number(n) => node.set_item("value", n)?,
+            Expr::Variable(name) | Expr::Call { name, .. } => node.set_item("name", name)?,
+            Expr::Unary { op, .. } | Expr::Binary { op, .. } => node.set_item("op", op)?,
+        }
+        node_dicts.push(node);
+    }
+    let edges = edges.into_iter().map(|(parent, child, label)| {
+        PyList::new_bound(py, [parent.into_py(py), child.into_py(py), label.into_py(py)])
+    });
+    let dict = PyDict::new_bound(py);
+    dict.set_item("nodes", node_dicts)?;
+    dict.set_item("edges", edges.collect::<Vec<_>>())?;
+    Ok(dict.into_py(py))
+}
+
+/// Indented tree of `expression` for reading in a terminal, one node per
+/// line:
+///
+/// ```text
+/// Binary(+)
+/// ├─ Number(1)
+/// └─ Binary(*)
+///    ├─ Number(2)
+///    └─ Variable(x)
+/// ```
+///
+/// Shows the tree as parsed unless `simplified` is set.
+#[pyfunction]
+#[pyo3(signature = (expression, simplified=false))]
+fn pretty_tree(expression: &str, simplified: bool) -> PyResult<String> {
+    let mut expr = parse_source(expression).map_err(ParseError::new_err)?;
+    if simplified {
+        expr = simplify(expr, &FoldOptions::default());
+    }
+    Ok(tree_text(&expr))
+}
+
+fn tree_text(expr: &Expr) -> String {
+    let mut out = format!("{}\n", node_label(expr));
+    write_tree_children(expr, "", &mut out);
+    out
+}
+
+/// Pretty-print the unsimplified tree of `expression`. `style` keys:
+/// `spaces` (default True) puts spaces around operators, `keep_parens`
+/// (default False) keeps parentheses the source wrote even where the
+/// precedence makes them redundant (otherwise only needed ones are written,
+/// as in `to_infix`), and `precision` rounds numbers to at most that many
+/// decimal places (trailing zeros dropped).
+#[pyfunction]
+#[pyo3(signature = (expression, style=None))]
+fn format(expression: &str, style: Option<&Bound<'_, PyDict>>) -> PyResult<String> {
+    let style = style.map(Style::from_dict).transpose()?.unwrap_or_default();
+    styled_source(expression, &style).map_err(ParseError::new_err)
+}
+
+/// The text `format` returns for `expression` laid out in `style`.
+fn styled_source(expression: &str, style: &Style) -> Result<String, String> {
+    check_parens(expression)?;
+    let (expr, spans) = parse_spanned(&tokenize(expression), &Precedence::default())?;
+    let layers = if style.keep_parens {
+        paren_layers(expression, &spans)
+    } else {
+        Vec::new()
+    };
+    Ok(write_styled(&expr, style, &mut layers.iter()).0)
+}
+
+/// How many parentheses in `expression` could be removed without changing
+/// its meaning, counting `(` and `)` separately, so `((1+2))*3` has 2. A
+/// pair is needed exactly where `to_infix` would write one; those of a
+/// call's argument list are never counted.
+#[pyfunction]
+fn redundant_parens(expression: &str) -> PyResult<usize> {
+    redundant_paren_count(expression).map_err(ParseError::new_err)
+}
+
+fn redundant_paren_count(expression: &str) -> Result<usize, String> {
+    check_parens(expression)?;
+    let (expr, spans) = parse_spanned(&tokenize(expression), &Precedence::default())?;
+    let layers = paren_layers(expression, &spans);
+    Ok(2 * redundant_layers(&expr, false, &mut layers.iter()))
+}
+
+/// Deepest nesting of parentheses in the source of `expression`, call
+/// parentheses included, so `((1))` is 2 although its tree is one node and
+/// `1+2` is 0. Unbalanced input raises `ParseError`.
+#[pyfunction]
+fn paren_depth(expression: &str) -> PyResult<usize> {
+    check_parens(expression).map_err(ParseError::new_err)?;
+    Ok(max_paren_depth(&tokenize(expression)))
+}
+
+/// Deepest nesting of `(` in balanced `tokens`.
+fn max_paren_depth(tokens: &[Token]) -> usize {
+    let (mut depth, mut max) = (0usize, 0usize);
+    for token in tokens {
+        match token.text.as_str() {
+            "(" => {
+                depth += 1;
+                max = max.max(depth);
+            }
+            ")" => depth -= 1,
+            _ => {}
+        }
+    }
+    max
+}
+
+/// The token texts of `expression` as the parser reads them. With
+/// `whitespace`, runs of whitespace are kept as tokens too, so
+/// `"".join(lex(s, whitespace=True)) == s`.
+#[pyfunction]
+#[pyo3(signature = (expression, whitespace=false))]
+fn lex(expression: &str, whitespace: bool) -> Vec<String> {
+    let tokens = if whitespace {
+        lex_tokens(expression, None, NumberFormat::Plain)
+    } else {
+        tokenize(expression)
+    };
+    tokens.into_iter().map(|token| token.text).collect()
+}
+
+/// Reverse Polish tokens for the unsimplified tree of `expression`; see
+/// `write_rpn` for the unary and call tokens.
+#[pyfunction]
+fn to_rpn(expression: &str) -> PyResult<Vec<String>> {
+    let expr = parse_source(expression).map_err(ParseError::new_err)?;
+    let mut out = Vec::new();
+    write_rpn(&expr, &mut out);
+    Ok(out)
+}
+
+/// `to_rpn` joined with single spaces, e.g. `"3 4 2 * +"`.
+#[pyfunction]
+fn to_rpn_string(expression: &str) -> PyResult<String> {
+    Ok(to_rpn(expression)?.join(" "))
+}
+
+/// Parse and simplify postfix `tokens`, the inverse of `to_rpn`: binary
+/// operators are spelled as in infix, `neg` and `pos` are the unary signs
+/// and `name/argc` calls `name` on the preceding `argc` values.
+#[pyfunction]
+fn parse_rpn(py: Python<'_>, tokens: Vec<String>) -> PyResult<PyObject> {
+    let tokens: Vec<&str> = tokens.iter().map(String::as_str).collect();
+    let expr = read_rpn(&tokens).map_err(ParseError::new_err)?;
+    Ok(to_py(py, simplify(expr, &FoldOptions::default()), Naming::Default))
+}
+
+/// `parse_rpn` on a whitespace-separated string, e.g. `"3 4 + 2 *"`.
+#[pyfunction]
+fn parse_rpn_string(py: Python<'_>, source: &str) -> PyResult<PyObject> {
+    parse_rpn(py, source.split_whitespace().map(str::to_string).collect())
+}
+
+/// Lisp-style rendering of the unsimplified tree, e.g. `(+ 1 (* 2 3))`.
+#[pyfunction]
+fn to_sexpr(expression: &str) -> PyResult<String> {
+    let expr = parse_source(expression).map_err(ParseError::new_err)?;
+    Ok(to_sexpr_string(&expr))
+}
+
+/// Whether `sub` occurs as a subtree of `expression`, comparing unsimplified
+/// trees structurally (so `b*c` is found in `a+(b*c)` but not `c*b`).
+#[pyfunction]
+fn contains(expression: &str, sub: &str) -> PyResult<bool> {
+    let expr = parse_source(expression).map_err(ParseError::new_err)?;
+    let sub = parse_source(sub).map_err(ParseError::new_err)?;
+    Ok(find_subtree(&expr, &sub, structural_hash(&sub)).1)
+}
+
+/// Whether the unsimplified trees of `a` and `b` differ at most in how their
+/// `+` and `*` chains are grouped: `(1+2)+3` and `1+(2+3)` match, while
+/// `(1-2)-3` and `1-(2-3)` do not, since `-` is not associative. Operand
+/// order must agree, so `1+2` and `2+1` do not match either.
+#[pyfunction]
+fn same_under_assoc(a: &str, b: &str) -> PyResult<bool> {
+    let a = parse_source(a).map_err(ParseError::new_err)?;
+    let b = parse_source(b).map_err(ParseError::new_err)?;
+    Ok(lean_left(a) == lean_left(b))
+}
+
+/// A hash of what `expression` means rather than how it is written, for
+/// keying external caches: the tree is simplified, its commutative operands
+/// sorted (see `commute`), and the S-expression of the result hashed with
+/// FNV-1a. So `a+b` and `b + a` agree, as do `1+2` and `3`, while `a-b` and
+/// `b-a` differ. Equivalences the simplifier doesn't
+/// know, such as `2*x` and `x+x` by default, still hash apart.
+///
+/// The value depends only on the canonical form, not on the platform, the
+/// process or the Rust toolchain, so it may be stored. A release that
+/// changes simplification or the canonical ordering can change it, so keys
+/// persisted across an upgrade should be rebuilt.
+#[pyfunction]
+fn semantic_hash(expression: &str) -> PyResult<u64> {
+    let expr = parse_with_options(expression, &ParseOptions::default())?;
+    Ok(fnv1a(to_sexpr_string(&commute(expr)).as_bytes()))
+}
+
+/// Every numeric literal in `expression` in source order, duplicates
+/// included. The tree is not simplified, so `2 + 3` gives `[2, 3]`, and a
+/// negative literal such as `-2` is reported as `2` under its unary minus.
+#[pyfunction]
+fn literals(expression: &str) -> PyResult<Vec<f64>> {
+    let expr = parse_source(expression).map_err(ParseError::new_err)?;
+    let mut out = Vec::new();
+    collect_literals(&expr, &mut out);
+    Ok(out)
+}
+
+/// The infix of every node in the unsimplified tree of `expression`, leaves
+/// and root included, in post-order: each node follows its operands, left to
+/// right, so `1 + 2*3` gives `["1", "2", "3", "2 * 3", "1 + 2 * 3"]`.
+/// Repeated subexpressions appear once per occurrence.
+#[pyfunction]
+fn subexpressions(expression: &str) -> PyResult<Vec<String>> {
+    let expr = parse_source(expression).map_err(ParseError::new_err)?;
+    Ok(subexpression_texts(&expr))
+}
+
+fn subexpression_texts(expr: &Expr) -> Vec<String> {
+    let mut nodes = Vec::new();
+    post_order(expr, &mut nodes);
+    nodes.into_iter().map(to_infix).collect()
+}
+
+/// Size counts gathered by `measure`.
+#[derive(Debug, Default)]
+struct Size {
+    nodes: usize,
+    depth: usize,
+    leaves: usize,
+}
+
+/// Count the nodes and leaves under `expr` in one walk, tracking the depth
+/// of the deepest, with `expr` at `level` (the root at 1).
+fn measure(expr: &Expr, level: usize, size: &mut Size) {
+    size.nodes += 1;
+    size.depth = size.depth.max(level);
+    if matches!(expr, Expr::Number(_) | Expr::Variable(_)) {
+        size.leaves += 1;
+    }
+    for child in children(expr) {
+        measure(child, level + 1, size);
+    }
+}
+
+/// Size metrics of the unsimplified tree of `expression`: `node_count`,
+/// `depth` (1 for a lone leaf), `leaf_count` (numbers and variables) and
+/// `operator_count` (the other nodes: operators and calls).
+#[pyfunction]
+fn ast_size(py: Python<'_>, expression: &str) -> PyResult<PyObject> {
+    let expr = parse_source(expression).map_err(ParseError::new_err)?;
+    let mut size = Size::default();
+    measure(&expr, 1, &mut size);
+    let dict = PyDict::new_bound(py);
+    dict.set_item("node_count", size.nodes)?;
+    dict.set_item("depth", size.depth)?;
+    dict.set_item("leaf_count", size.leaves)?;
+    dict.set_item("operator_count", size.nodes - size.leaves)?;
+    Ok(dict.into_py(py))
+}
+
+/// The highest degree `polynomial` expands to; `(x+1)^1000000` would
+/// otherwise allocate and multiply out a million coefficients.
+const MAX_POLY_DEGREE: usize = 1024;
+
+/// The coefficients of `expr` as a polynomial in `var`, constant term first,
+/// or an error naming the first subexpression that is not one: another
+/// variable, a call on `var`, a division by a non-constant, or a power that
+/// is not a nonnegative integer. A product or power whose degree would pass
+/// `MAX_POLY_DEGREE` is an error too, checked before it is expanded.
+fn polynomial(expr: &Expr, var: &str) -> Result<Vec<f64>, String> {
+    let not_polynomial = || format!("'{}' is not a polynomial in {}", to_infix(expr), var);
+    let check_degree = |degree: f64| {
+        if degree > MAX_POLY_DEGREE as f64 {
+            return Err(format!(
+                "'{}' has degree {} in {}, above the limit of {}",
+                to_infix(expr),
+                degree,
+                var,
+                MAX_POLY_DEGREE
+            ));
+        }
+        Ok(())
+    };
+    let combine = |a: Vec<f64>, b: Vec<f64>, sign: f64| {
+        let mut sum = vec![0.0; a.len().max(b.len())];
+        for (i, c) in a.into_iter().enumerate() {
+            sum[i] += c;
+        }
+        for (i, c) in b.into_iter().enumerate() {
+            sum[i] += sign * c;
+        }
+        sum
+    };
+    let multiply = |a: &[f64], b: &[f64]| {
+        let mut product = vec![0.0; a.len() + b.len() - 1];
+        for (i, x) in a.iter().enumerate() {
+            for (j, y) in b.iter().enumerate() {
+                product[i + j] += x * y;
+            }
+        }
+        product
+    };
+    let mut coeffs = match expr {
+        Expr::Number(n) => vec![*n],
+        Expr::Variable(name) if name == var => vec![0.0, 1.0],
+        // `0 - c` rather than `-c`, so zero coefficients stay `0.0`.
+        Expr::Unary { op: "-", expr } => {
+            polynomial(expr, var)?.into_iter().map(|c| 0.0 - c).collect()
+        }
+        Expr::Binary { op: "+", left, right } => {
+            combine(polynomial(left, var)?, polynomial(right, var)?, 1.0)
+        }
+        Expr::Binary { op: "-", left, right } => {
+            combine(polynomial(left, var)?, polynomial(right, var)?, -1.0)
+        }
+        Expr::Binary { op: "*", left, right } => {
+            let (a, b) = (polynomial(left, var)?, polynomial(right, var)?);
+            check_degree((a.len() + b.len() - 2) as f64)?;
+            multiply(&a, &b)
+        }
+        Expr::Binary { op: "/", left, right: divisor } => match **divisor {
+            Expr::Number(d) if d != 0.0 => {
+                polynomial(left, var)?.into_iter().map(|c| c / d).collect()
+            }
+            _ => return Err(not_polynomial()),
+        },
+        Expr::Binary { op: "^", left, right: exponent } => match **exponent {
+            Expr::Number(n) if n >= 0.0 && n.fract() == 0.0 => {
+                let base = polynomial(left, var)?;
+                if let [constant] = base[..] {
+                    vec![constant.powf(n)]
+                } else {
+                    check_degree((base.len() - 1) as f64 * n)?;
+                    (0..n as u64).fold(vec![1.0], |acc, _| multiply(&acc, &base))
+                }
+            }
+            _ => return Err(not_polynomial()),
+        },
+        _ => return Err(not_polynomial()),
+    };
+    while coeffs.len() > 1 && coeffs.last() == Some(&0.0) {
+        coeffs.pop();
+    }
+    Ok(coeffs)
+}
+
+/// The coefficients `[c0, c1, c2, ...]` of the simplified `expression` as a
+/// polynomial in `var`, constant term first and without trailing zeros, so
+/// `2*x^2 + 3*x + 1` gives `[1, 3, 2]` and `0` gives `[0]`. Products and
+/// integer powers are expanded, up to degree `MAX_POLY_DEGREE`. Anything
+/// else, such as `sin(x)`, `1/x`, a second variable or `x^2000`, raises
+/// `ValueError`.
+#[pyfunction]
+fn poly_coeffs(expression: &str, var: &str) -> PyResult<Vec<f64>> {
+    let expr = parse_with_options(expression, &ParseOptions::default())?;
+    polynomial(&expr, var).map_err(PyValueError::new_err)
+}
+
+/// The roots of `expression = 0` in `var` for a linear or quadratic
+/// polynomial (see `poly_coeffs`), in ascending order with a double root
+/// listed once, so `x^2 - 5*x + 6` gives `[2.0, 3.0]`. Without real roots
+/// the result is empty, unless `complex` is set, in which case the conjugate
+/// pair is returned as `complex` values, negative imaginary part first. A
+/// nonzero constant has no roots; the zero polynomial, whose roots are every
+/// value, and degrees above 2 raise `ValueError`.
+#[pyfunction]
+#[pyo3(signature = (expression, var, complex=false))]
+fn roots(py: Python<'_>, expression: &str, var: &str, complex: bool) -> PyResult<Vec<PyObject>> {
+    let expr = parse_with_options(expression, &ParseOptions::default())?;
+    let coeffs = polynomial(&expr, var).map_err(PyValueError::new_err)?;
+    match solve(&coeffs) {
+        Solution::Real(values) => Ok(values.into_iter().map(|x| x.into_py(py)).collect()),
+        Solution::Conjugate(re, im) if complex => Ok([-im, im]
+            .into_iter()
+            .map(|im| PyComplex::from_doubles_bound(py, re, im).into_py(py))
+            .collect()),
+        Solution::Conjugate(..) => Ok(Vec::new()),
+        Solution::Everywhere => Err(PyValueError::new_err(format!(
+            "'{}' is zero for every value of {}",
+            expression, var
+        ))),
+        Solution::Unsupported(degree) => Err(PyValueError::new_err(format!(
+            "unsupported degree {} for roots; only linear and quadratic are solved",
+            degree
+        ))),
+    }
+}
+
+/// The roots of a polynomial, as `solve` finds them.
+#[derive(Debug, PartialEq)]
+enum Solution {
+    /// The real roots, ascending, a double root once.
+    Real(Vec<f64>),
+    /// The real and (positive) imaginary part of a pair of complex roots.
+    Conjugate(f64, f64),
+    /// The zero polynomial.
+    Everywhere,
+    /// A degree above 2.
+    Unsupported(usize),
+}
+
+/// Solve the polynomial with `coeffs` (constant term first, as from
+/// `polynomial`) for zero.
+fn solve(coeffs: &[f64]) -> Solution {
+    // Adding 0 turns a `-0.0` root, as in `x^2`, into `0.0`.
+    let real = |values: Vec<f64>| Solution::Real(values.into_iter().map(|x| x + 0.0).collect());
+    match coeffs[..] {
+        [0.0] => Solution::Everywhere,
+        [_] => Solution::Real(Vec::new()),
+        [c, b] => real(vec![-c / b]),
+        [c, b, a] => {
+            let discriminant = b * b - 4.0 * a * c;
+            if discriminant == 0.0 {
+                return real(vec![-b / (2.0 * a)]);
+            }
+            if discriminant < 0.0 {
+                let im = (-discriminant).sqrt() / (2.0 * a).abs();
+                return Solution::Conjugate(-b / (2.0 * a) + 0.0, im);
+            }
+            // The form that avoids cancelling `-b` against a root of nearly
+            // the same size.
+            let q = -(b + b.signum() * discriminant.sqrt()) / 2.0;
+            let mut values = vec![q / a, c / q];
+            values.sort_by(f64::total_cmp);
+            real(values)
+        }
+        _ => Solution::Unsupported(coeffs.len() - 1),
+    }
+}
+
+/// The largest exponent `expand` multiplies out; higher powers of a sum stay
+/// as they are rather than growing into hundreds of terms.
+const MAX_EXPAND_POWER: f64 = 16.0;
+
+/// A term `coeff * base^exp * ...` of an expanded sum, each base an
+/// expression `expand` does not distribute into.
+#[derive(Debug, Clone)]
+struct Monomial {
+    coeff: f64,
+    factors: Vec<(Expr, u32)>,
+}
+
+impl Monomial {
+    fn constant(coeff: f64) -> Self {
+        Monomial { coeff, factors: Vec::new() }
+    }
+
+    fn times(&self, other: &Monomial) -> Monomial {
+        let mut factors = self.factors.clone();
+        for (base, exp) in &other.factors {
+            match factors.iter_mut().find(|(b, _)| b == base) {
+                Some((_, e)) => *e += exp,
+                None => factors.push((base.clone(), *exp)),
+            }
+        }
+        Monomial { coeff: self.coeff * other.coeff, factors }
+    }
+
+    /// Whether `self` and `other` differ at most in coefficient and factor
+    /// order.
+    fn is_like(&self, other: &Monomial) -> bool {
+        self.factors.len() == other.factors.len()
+            && self.factors.iter().all(|factor| other.factors.contains(factor))
+    }
+
+    fn into_expr(self) -> Expr {
+        let magnitude = self.coeff.abs();
+        let constant = (magnitude != 1.0 || self.factors.is_empty()).then_some(magnitude);
+        let powers = self.factors.into_iter().map(|(base, exp)| match exp {
+            1 => base,
+            _ => binary("^", base, Expr::Number(exp as f64)),
+        });
+        constant
+            .map(Expr::Number)
+            .into_iter()
+            .chain(powers)
+            .reduce(|product, factor| binary("*", product, factor))
+            .unwrap()
+    }
+}
+
+fn binary(op: &'static str, left: Expr, right: Expr) -> Expr {
+    Expr::Binary { op, left: Box::new(left), right: Box::new(right) }
+}
+
+/// `expr` as a list of terms, distributing `*` over `+` and `-` and
+/// multiplying out integer powers up to `MAX_EXPAND_POWER`.
+fn expand_terms(expr: &Expr) -> Vec<Monomial> {
+    let negate = |terms: Vec<Monomial>| {
+        terms.into_iter().map(|term| Monomial { coeff: -term.coeff, ..term }).collect()
+    };
+    let product = |a: &[Monomial], b: &[Monomial]| {
+        a.iter().flat_map(|x| b.iter().map(move |y| x.times(y))).collect::<Vec<_>>()
+    };
+    match expr {
+        Expr::Number(n) => vec![Monomial::constant(*n)],
+        Expr::Unary { op: "-", expr } => negate(expand_terms(expr)),
+        Expr::Binary { op: "+", left, right } => {
+            let mut terms = expand_terms(left);
+            terms.extend(expand_terms(right));
+            terms
+        }
+        Expr::Binary { op: "-", left, right } => {
+            let mut terms = expand_terms(left);
+            terms.extend(negate(expand_terms(right)));
+            terms
+        }
+        Expr::Binary { op: "*", left, right } => product(&expand_terms(left), &expand_terms(right)),
+        Expr::Binary { op: "/", left, right } if matches!(**right, Expr::Number(d) if d != 0.0) => {
+            let Expr::Number(d) = **right else { unreachable!() };
+            product(&expand_terms(left), &[Monomial::constant(1.0 / d)])
+        }
+        Expr::Binary { op: "^", left, right }
+            if matches!(**right, Expr::Number(n)
+                if n.fract() == 0.0 && (0.0..=MAX_EXPAND_POWER).contains(&n)) =>
+        {
+            let Expr::Number(n) = **right else { unreachable!() };
+            let base = expand_terms(left);
+            (0..n as u32).fold(vec![Monomial::constant(1.0)], |acc, _| product(&acc, &base))
+        }
+        _ => vec![Monomial { coeff: 1.0, factors: vec![(expr.clone(), 1)] }],
+    }
+}
+
+/// Expand the simplified `expression` into a sum of products: `*` is
+/// distributed over `+` and `-`, powers of sums with integer exponents up to
+/// 16 are multiplied out, and like terms are then combined, so `(a+b)^2`
+/// gives `"a ^ 2 + 2 * a * b + b ^ 2"`. Terms keep the order in which they
+/// first appear. Calls, other powers and divisions by non-constants are
+/// kept whole, as factors.
+#[pyfunction]
+fn expand(expression: &str) -> PyResult<String> {
+    let expr = parse_with_options(expression, &ParseOptions::default())?;
+    Ok(expanded_text(&expr))
+}
+
+/// The infix `expand` returns for an already simplified tree.
+fn expanded_text(expr: &Expr) -> String {
+    let mut terms: Vec<Monomial> = Vec::new();
+    for term in expand_terms(expr) {
+        match terms.iter_mut().find(|t| t.is_like(&term)) {
+            Some(like) => like.coeff += term.coeff,
+            None => terms.push(term),
+        }
+    }
+    terms.retain(|term| term.coeff != 0.0);
+    let mut terms = terms.into_iter();
+    let Some(first) = terms.next() else {
+        return "0".to_string();
+    };
+    let lead = if first.coeff < 0.0 {
+        Expr::Unary { op: "-", expr: Box::new(first.into_expr()) }
+    } else {
+        first.into_expr()
+    };
+    let sum = terms.fold(lead, |sum, term| {
+        let op = if term.coeff < 0.0 { "-" } else { "+" };
+        binary(op, sum, term.into_expr())
+    });
+    to_infix(&sum)
+}
+
+/// Whether `a` and `b` agree at `samples` pseudo-random points, each variable
+/// of either expression drawn uniformly from `[-10, 10)`. This is a
+/// probabilistic check, not a proof: agreement at every sample suggests the
+/// expressions are equivalent, while a single disagreement shows they are
+/// not. The generator is seeded identically on every call, so results are
+/// reproducible.
+#[pyfunction]
+#[pyo3(signature = (a, b, samples=100))]
+fn likely_equivalent(a: &str, b: &str, samples: usize) -> PyResult<bool> {
+    let a = parse_source(a).map_err(ParseError::new_err)?;
+    let b = parse_source(b).map_err(ParseError::new_err)?;
+    agree_at_samples(&a, &b, samples).map_err(EvaluationError::new_err)
+}
+
+fn agree_at_samples(a: &Expr, b: &Expr, samples: usize) -> Result<bool, String> {
+    let mut names = Vec::new();
+    collect_variables(a, &mut names);
+    collect_variables(b, &mut names);
+    let mut rng = SampleRng(0);
+    for _ in 0..samples {
+        let env: Vec<(String, f64)> =
+            names.iter().map(|name| (name.clone(), rng.next_f64() * 20.0 - 10.0)).collect();
+        if !close_enough(evaluate(a, &env)?, evaluate(b, &env)?) {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Evaluation with user-supplied folding for individual operators. A hook
+/// registered with `register_operator("+", f)` is called as `f(a, b)`
+/// whenever both operands of a `+` are constants, before the built-in rule:
+/// a number it returns becomes the result, and `None` falls back to the
+/// built-in fold. Operands fold bottom-up, left before right, so hooks see
+/// already-folded (and already-hooked) operands. Exceptions from a hook
+/// propagate unchanged.
+///
+/// `register_function("f", g)` likewise folds calls `f(...)` with constant
+/// arguments to `g(...)`, after checking the argument count against the
+/// registered range; unlike the builtins, such a function may take none.
+/// Neither kind of hook runs on the branch a constant `if` condition
+/// discards.
+#[pyclass]
+#[derive(Default)]
+struct Evaluator {
+    operators: HashMap<String, PyObject>,
+    /// Registered functions with their minimum and maximum arity.
+    functions: HashMap<String, (PyObject, usize, usize)>,
+}
+
+impl Evaluator {
+    fn simplify(&self, py: Python<'_>, expr: Expr) -> PyResult<Expr> {
+        // As in `simplify`, hooks never run on the branch a constant
+        // condition discards.
+        if let Expr::Call { name, mut args } = expr {
+            if name == "if" && args.len() == 3 {
+                let cond = self.simplify(py, std::mem::replace(&mut args[0], Expr::Number(0.0)))?;
+                if let Expr::Number(cond) = cond {
+                    return self.simplify(py, args.swap_remove(if cond != 0.0 { 1 } else { 2 }));
+                }
+                args[0] = cond;
+                for branch in &mut args[1..] {
+                    *branch = self.simplify(py, std::mem::replace(branch, Expr::Number(0.0)))?;
+                }
+                return Ok(simplify_node(Expr::Call { name, args }, &FoldOptions::default()));
+            }
+            return self.simplify_children(py, Expr::Call { name, args });
+        }
+        self.simplify_children(py, expr)
+    }
+
+    /// `simplify` for any node but a two-way `if`: children first, then the
+    /// node's hook or built-in rule.
+    fn simplify_children(&self, py: Python<'_>, expr: Expr) -> PyResult<Expr> {
+        let expr = try_map_children(expr, &mut |child| self.simplify(py, child))?;
+        if let Expr::Call { name, args } = &expr {
+            if let Some((func, min, max)) = self.functions.get(name) {
+                if let Some(message) = arity_error(name, *min, *max, args.len()) {
+                    return Err(ParseError::new_err(message));
+                }
+                let values: Option<Vec<f64>> = args
+                    .iter()
+                    .map(|arg| match arg {
+                        Expr::Number(n) => Some(*n),
+                        _ => None,
+                    })
+                    .collect();
+                return match values {
+                    Some(values) => {
+                        let values = PyTuple::new_bound(py, values);
+                        Ok(Expr::Number(func.call1(py, values)?.extract(py)?))
+                    }
+                    None => Ok(expr),
+                };
+            }
+        }
+        if let Expr::Binary { op, left, right } = &expr {
+            if let (Some(hook), Expr::Number(a), Expr::Number(b)) =
+                (self.operators.get(*op), &**left, &**right)
+            {
+                let result = hook.call1(py, (*a, *b))?;
+                if !result.is_none(py) {
+                    return Ok(Expr::Number(result.extract(py)?));
+                }
+            }
+        }
+        Ok(simplify_node(expr, &FoldOptions::default()))
+    }
+
+    /// Fill in what the simplified `expr` still lacks from `on_unknown`: a
+    /// free variable takes `on_unknown(name)` as its value, and a call to an
+    /// unknown function calls `on_unknown(name)` with the arguments once
+    /// they fold. Only names evaluation would reach are resolved, so the
+    /// branch an `if` doesn't take and the right side of an `and` whose
+    /// left is 0 are skipped. `resolved` caches each name's result.
+    fn resolve(
+        &self,
+        py: Python<'_>,
+        expr: Expr,
+        on_unknown: &Bound<'_, PyAny>,
+        resolved: &mut HashMap<String, PyObject>,
+    ) -> PyResult<Expr> {
+        let lookup = |name: &str, resolved: &mut HashMap<String, PyObject>| {
+            if let Some(value) = resolved.get(name) {
+                return Ok::<_, PyErr>(value.clone_ref(py));
+            }
+            let value = on_unknown.call1((name,))?.unbind();
+            resolved.insert(name.to_string(), value.clone_ref(py));
+            Ok(value)
+        };
+        let folded = |expr: Expr, resolved: &mut HashMap<String, PyObject>| {
+            self.simplify(py, self.resolve(py, expr, on_unknown, resolved)?)
+        };
+        match expr {
+            Expr::Variable(name) => Ok(Expr::Number(lookup(&name, resolved)?.extract(py)?)),
+            Expr::Call { name, mut args } if name == "if" => {
+                let cond = folded(args.remove(0), resolved)?;
+                if let Expr::Number(cond) = cond {
+                    let taken = args.swap_remove(if cond != 0.0 { 0 } else { 1 });
+                    return self.resolve(py, taken, on_unknown, resolved);
+                }
+                let mut resolved_args = vec![cond];
+                for arg in args {
+                    resolved_args.push(self.resolve(py, arg, on_unknown, resolved)?);
+                }
+                Ok(Expr::Call { name, args: resolved_args })
+            }
+            Expr::Binary { op: "and", left, right } => {
+                let left = folded(*left, resolved)?;
+                if left == Expr::Number(0.0) {
+                    return Ok(Expr::Number(0.0));
+                }
+                let right = self.resolve(py, *right, on_unknown, resolved)?;
+                Ok(Expr::Binary { op: "and", left: Box::new(left), right: Box::new(right) })
+            }
+            Expr::Call { name, args }
+                if !self.functions.contains_key(&name)
+                    && !FUNCTIONS.iter().any(|(builtin, ..)| *builtin == name) =>
+            {
+                let mut values = Vec::with_capacity(args.len());
+                for arg in args.iter().cloned() {
+                    match folded(arg, resolved)? {
+                        Expr::Number(n) => values.push(n),
+                        _ => return Ok(Expr::Call { name, args }),
+                    }
+                }
+                let func = lookup(&name, resolved)?;
+                Ok(Expr::Number(func.call1(py, PyTuple::new_bound(py, values))?.extract(py)?))
+            }
+            other => try_map_children(other, &mut |child| {
+                self.resolve(py, child, on_unknown, resolved)
+            }),
+        }
+    }
+}
+
+#[pymethods]
+impl Evaluator {
+    #[new]
+    fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register `func(a, b)` as the fold for binary operator `op`, replacing
+    /// any earlier hook for it.
+    fn register_operator(&mut self, op: &str, func: PyObject) -> PyResult<()> {
+        if Precedence::default().infix(op).is_none() {
+            return Err(PyValueError::new_err(format!("unknown binary operator '{}'", op)));
+        }
+        self.operators.insert(op.to_string(), func);
+        Ok(())
+    }
+
+    /// Register `func` as the function `name`, taking `min_args` to
+    /// `max_args` arguments (any number from `min_args` if None), replacing
+    /// any earlier registration. Builtin names cannot be registered.
+    #[pyo3(signature = (name, func, min_args=0, max_args=None))]
+    fn register_function(
+        &mut self,
+        name: &str,
+        func: PyObject,
+        min_args: usize,
+        max_args: Option<usize>,
+    ) -> PyResult<()> {
+        if FUNCTIONS.iter().any(|(builtin, ..)| *builtin == name) || name == PIECEWISE {
+            return Err(PyValueError::new_err(format!("'{}' is a builtin function", name)));
+        }
+        if !is_identifier(name) || WORD_OPS.contains(&name) {
+            return Err(PyValueError::new_err(format!("invalid function name '{}'", name)));
+        }
+        let max_args = max_args.unwrap_or(usize::MAX);
+        if min_args > max_args {
+            return Err(PyValueError::new_err(format!(
+                "min_args {} exceeds max_args {}",
+                min_args, max_args
+            )));
+        }
+        self.functions.insert(name.to_string(), (func, min_args, max_args));
+        Ok(())
+    }
+
+    /// Parse and simplify `expression`, applying the registered hooks.
+    fn parse(&self, py: Python<'_>, expression: &str) -> PyResult<PyObject> {
+        let expr = parse_source(expression).map_err(ParseError::new_err)?;
+        Ok(to_py(py, self.simplify(py, expr)?, Naming::Default))
+    }
+
+    /// Evaluate `expression` with `vars` bound, applying the registered hooks.
+    /// `int_policy` picks when an integral result comes back as an `int`:
+    /// `"never"` (the default), `"integral"` (always), or `"no_division"`
+    /// (only if the source has no `/`, so `2*3` is `6` but `6/2` is `3.0`).
+    ///
+    /// `on_unknown` resolves names that neither `vars` nor the registered and
+    /// builtin functions cover, and only when evaluation reaches them:
+    /// `on_unknown(name)` gives a variable's number, or for an unknown
+    /// function a callable taking the arguments. It is called once per name
+    /// per evaluation, and an exception it raises propagates unchanged.
+    ///
+    /// `allowed_vars` restricts the variable names as for `parse_infix`,
+    /// raising `ParseError` before anything is bound or resolved.
+    #[pyo3(signature = (
+        expression,
+        vars=None,
+        int_policy="never",
+        on_unknown=None,
+        allowed_vars=None,
+    ))]
+    fn evaluate(
+        &self,
+        py: Python<'_>,
+        expression: &str,
+        vars: Option<HashMap<String, f64>>,
+        int_policy: &str,
+        on_unknown: Option<&Bound<'_, PyAny>>,
+        allowed_vars: Option<Vec<String>>,
+    ) -> PyResult<PyObject> {
+        let int_policy = IntPolicy::parse(int_policy)?;
+        let vars = vars.unwrap_or_default();
+        let expr = parse_source(expression).map_err(ParseError::new_err)?;
+        if let Some(allowed) = &allowed_vars {
+            check_allowed_vars(&expr, allowed)?;
+        }
+        let ints = int_policy.allows_int(&expr);
+        let mut expr = self.simplify(py, bind_constants(expr, &|name| vars.get(name).copied()))?;
+        if let Some(on_unknown) = on_unknown {
+            expr = self.simplify(py, self.resolve(py, expr, on_unknown, &mut HashMap::new())?)?;
+        }
+        // Anything left unfolded has a missing variable, an unknown function
+        // or an invalid shift or builtin argument; evaluating it reports which.
+        let value = evaluate(&expr, &[]).map_err(EvaluationError::new_err)?;
+        Ok(match as_i64(value).filter(|_| ints) {
+            Some(int) => int.into_py(py),
+            None => value.into_py(py),
+        })
+    }
+}
+
+/// One step of a `Compiled` program, which runs on a stack of `f64`s.
+#[derive(Debug, Clone)]
+enum Instr {
+    Push(f64),
+    /// Push the value bound to `Compiled::variables[slot]`.
+    Load(usize),
+    Neg,
+    /// Pop the right then the left operand and push the result.
+    Binary(&'static str),
+    /// Pop `argc` arguments and push the builtin's result.
+    Call(String, usize),
+    /// The short-circuit of `and`: a left operand of 0 on top of the stack
+    /// is the result, so jump to the instruction index past the right side;
+    /// otherwise pop it and let the right side decide.
+    And(usize),
+    /// Replace the value on top with 1 if it is nonzero, else 0.
+    Truth,
+    /// Pop the condition of an `if` and jump to the else branch if it is 0.
+    JumpUnless(usize),
+    /// Skip the else branch once the then branch has run.
+    Jump(usize),
+}
+
+/// Append the postfix program for `expr`, giving each distinct variable a
+/// slot in `variables` in first-use order.
+fn compile_into(expr: &Expr, variables: &mut Vec<String>, program: &mut Vec<Instr>) {
+    match expr {
+        Expr::Number(n) => program.push(Instr::Push(*n)),
+        Expr::Variable(name) => {
+            let slot = variables.iter().position(|v| v == name).unwrap_or_else(|| {
+                variables.push(name.clone());
+                variables.len() - 1
+            });
+            program.push(Instr::Load(slot));
+        }
+        Expr::Unary { op, expr } => {
+            compile_into(expr, variables, program);
+            if *op == "-" {
+                program.push(Instr::Neg);
+            }
+        }
+        Expr::Binary { op: "and", left, right } => {
+            compile_into(left, variables, program);
+            let jump = program.len();
+            program.push(Instr::And(0));
+            compile_into(right, variables, program);
+            program.push(Instr::Truth);
+            program[jump] = Instr::And(program.len());
+        }
+        Expr::Binary { op, left, right } => {
+            compile_into(left, variables, program);
+            compile_into(right, variables, program);
+            program.push(Instr::Binary(op));
+        }
+        Expr::Call { name, args } if name == "if" => {
+            compile_into(&args[0], variables, program);
+            let unless = program.len();
+            program.push(Instr::JumpUnless(0));
+            compile_into(&args[1], variables, program);
+            let jump = program.len();
+            program.push(Instr::Jump(0));
+            program[unless] = Instr::JumpUnless(program.len());
+            compile_into(&args[2], variables, program);
+            program[jump] = Instr::Jump(program.len());
+        }
+        Expr::Call { name, args } => {
+            for arg in args {
+                compile_into(arg, variables, program);
+            }
+            program.push(Instr::Call(name.clone(), args.len()));
+        }
+    }
+}
+
+/// Run a program from `compile_into` with `values` in its variable slots,
+/// reporting the same errors as `evaluate`.
+fn run_compiled(
+    program: &[Instr],
+    variables: &[String],
+    values: &[Option<f64>],
+) -> Result<f64, String> {
+    let mut stack: Vec<f64> = Vec::new();
+    let mut pc = 0;
+    while let Some(instr) = program.get(pc) {
+        pc += 1;
+        match instr {
+            Instr::Push(n) => stack.push(*n),
+            Instr::Load(slot) => stack.push(
+                values[*slot]
+                    .ok_or_else(|| format!("undefined variable '{}'", variables[*slot]))?,
+            ),
+            Instr::Neg => {
+                let top = stack.last_mut().unwrap();
+                *top = -*top;
+            }
+            Instr::Binary(op) => {
+                let b = stack.pop().unwrap();
+                let a = stack.pop().unwrap();
+                stack.push(try_apply_binary(op, a, b)?);
+            }
+            Instr::Call(name, argc) => {
+                let args = stack.split_off(stack.len() - argc);
+                stack.push(apply_function(name, &args, &FoldOptions::default())?);
+            }
+            Instr::And(end) => {
+                if stack.last() == Some(&0.0) {
+                    pc = *end;
+                } else {
+                    stack.pop();
+                }
+            }
+            Instr::Truth => {
+                let top = stack.last_mut().unwrap();
+                *top = truth(*top != 0.0);
+            }
+            Instr::JumpUnless(target) => {
+                if stack.pop() == Some(0.0) {
+                    pc = *target;
+                }
+            }
+            Instr::Jump(target) => pc = *target,
+        }
+    }
+    Ok(stack.pop().unwrap())
+}
+
+/// An expression parsed and simplified once, then flattened into a stack
+/// program so `eval` costs no parsing or tree walking. Returned by `compile`.
+#[pyclass]
+struct Compiled {
+    program: Vec<Instr>,
+    /// Variable names by slot, in first-use order.
+    variables: Vec<String>,
+}
+
+#[pymethods]
+impl Compiled {
+    /// Evaluate with `vars` bound, raising `EvaluationError` as
+    /// `Evaluator.evaluate` would, e.g. for a variable `vars` lacks.
+    #[pyo3(signature = (vars=None))]
+    fn eval(&self, vars: Option<&Bound<'_, PyDict>>) -> PyResult<f64> {
+        let mut values = Vec::with_capacity(self.variables.len());
+        for name in &self.variables {
+            let value = match vars.map(|vars| vars.get_item(name)).transpose()?.flatten() {
+                Some(value) => Some(value.extract::<f64>()?),
+                None => None,
+            };
+            values.push(value);
+        }
+        run_compiled(&self.program, &self.variables, &values)
+            .map_err(EvaluationError::new_err)
+    }
+
+    /// The variables still free after simplifying, in first-use order.
+    fn free_variables(&self) -> Vec<String> {
+        self.variables.clone()
+    }
+}
+
+/// Parse and simplify `expression` once for repeated `Compiled.eval` calls.
+#[pyfunction]
+fn compile(expression: &str) -> PyResult<Compiled> {
+    let expr = parse_with_options(expression, &ParseOptions::default())?;
+    let (mut variables, mut program) = (Vec::new(), Vec::new());
+    compile_into(&expr, &mut variables, &mut program);
+    Ok(Compiled { program, variables })
+}
+
+/// Parser that memoizes simplified trees. Inputs are keyed on their token
+/// sequence, so `1+2` and `1 + 2` share an entry; only insignificant
+/// whitespace is normalized, not algebraic form (`2+1` is a separate entry).
+#[pyclass]
+#[derive(Default)]
+struct CachingParser {
+    cache: HashMap<String, Expr>,
+}
+
+impl CachingParser {
+    fn parse_cached(&mut self, expression: &str) -> Result<Expr, String> {
+        check_parens(expression)?;
+        let tokens = tokenize(expression);
+        // Tokens are space-joined so `1 2` and `12` keep distinct keys.
+        let key = tokens.iter().map(|t| t.text.as_str()).collect::<Vec<_>>().join(" ");
+        if let Some(expr) = self.cache.get(&key) {
+            return Ok(expr.clone());
+        }
+        let expr = simplify(parse_statement_expr(&tokens)?, &FoldOptions::default());
+        self.cache.insert(key, expr.clone());
+        Ok(expr)
+    }
+}
+
+#[pymethods]
+impl CachingParser {
+    #[new]
+    fn new() -> Self {
+        Self::default()
+    }
+
+    fn parse(&mut self, expression: &str) -> PyResult<PyObject> {
+        let expr = self.parse_cached(expression).map_err(ParseError::new_err)?;
+        Ok(Python::with_gil(|py| to_py(py, expr, Naming::Default)))
+    }
+
+    fn clear(&mut self) {
+        self.cache.clear();
+    }
+
+    fn __len__(&self) -> usize {
+        self.cache.len()
+    }
+}
+
+/// A program's variables, in order of first assignment.
+type Env = Vec<(String, f64)>;
+
+/// Execute `statements` in order, returning the assigned variables and the
+/// value of the final statement when it is an expression.
+fn execute(statements: Vec<Statement>) -> Result<(Env, Option<f64>), String> {
+    let mut env = Env::new();
+    let mut result = None;
+    for statement in statements {
+        match statement {
+            Statement::Assign(name, expr) => {
+                let value = evaluate(&expr, &env)?;
+                match env.iter_mut().find(|(n, _)| *n == name) {
+                    Some(slot) => slot.1 = value,
+                    None => env.push((name, value)),
+                }
+                result = None;
+            }
+            Statement::Expr(expr) => result = Some(evaluate(&expr, &env)?),
+        }
+    }
+    Ok((env, result))
+}
+
+/// Run `;`-separated statements and return every assigned variable plus
+/// `"__result__"`, the value of the final statement when it is an expression.
+#[pyfunction]
+fn run_program(source: &str) -> PyResult<PyObject> {
+    let statements = parse_program(source).map_err(ParseError::new_err)?;
+    let (env, result) = execute(statements).map_err(EvaluationError::new_err)?;
+    Python::with_gil(|py| {
+        let dict = PyDict::new_bound(py);
+        for (name, value) in env {
+            dict.set_item(name, value)?;
+        }
+        dict.set_item("__result__", result)?;
+        Ok(dict.into_py(py))
+    })
+}
+
+/// Names whose assigned value is never read: no later statement uses the
+/// name before it is assigned again or the program ends. A statement reads
+/// its right-hand side before it assigns, so `x = x + 1` uses the earlier `x`.
+/// Each name is listed once, in the order of its first unused assignment.
+#[pyfunction]
+fn unused_assignments(source: &str) -> PyResult<Vec<String>> {
+    let statements = parse_program(source).map_err(ParseError::new_err)?;
+    Ok(unused_names(&statements))
+}
+
+fn unused_names(statements: &[Statement]) -> Vec<String> {
+    let reads: Vec<Vec<String>> = statements
+        .iter()
+        .map(|statement| {
+            let mut names = Vec::new();
+            match statement {
+                Statement::Assign(_, expr) | Statement::Expr(expr) => {
+                    collect_variables(expr, &mut names)
+                }
+            }
+            names
+        })
+        .collect();
+    let mut unused: Vec<String> = Vec::new();
+    for (i, statement) in statements.iter().enumerate() {
+        let Statement::Assign(name, _) = statement else {
+            continue;
+        };
+        let mut used = false;
+        for (later, names) in statements[i + 1..].iter().zip(&reads[i + 1..]) {
+            if names.contains(name) {
+                used = true;
+                break;
+            }
+            if matches!(later, Statement::Assign(n, _) if n == name) {
+                break;
+            }
+        }
+        if !used && !unused.contains(name) {
+            unused.push(name.clone());
+        }
+    }
+    unused
+}
+
+/// Describe the supported operators, builtin functions and modes so callers
+/// can feature-detect. Every list is read from the table the parser or
+/// evaluator uses: `modes` holds the grammar's optional forms followed by
+/// the keywords of `parse_infix` and `evaluate_with`, and `number_formats`
+/// and `divisions` the values those keywords accept.
+#[pyfunction]
+fn features() -> PyResult<PyObject> {
+    let operators: Vec<String> = AND_OPS
+        .iter()
+        .chain(COMPARISON_OPS)
+        .chain(SHIFT_OPS)
+        .chain(ADDITIVE_OPS)
+        .chain(MULTIPLICATIVE_OPS)
+        .chain(POWER_OPS)
+        .map(|op| op.to_string())
+        .collect();
+    let unary_operators: Vec<String> = UNARY_OPS.iter().map(|op| op.to_string()).collect();
+    let constants: Vec<&str> = PHYSICAL_CONSTANTS.iter().map(|(name, _)| *name).collect();
+    let mut modes = Vec::new();
+    if Precedence::default().infix.iter().any(|e| e.assoc == Assoc::Chain) {
+        modes.push("chained_comparisons");
+    }
+    modes.push(PIECEWISE);
+    for name in ParseKeywords::NAMES.iter().chain(EvaluateKeywords::NAMES) {
+        if !modes.contains(name) {
+            modes.push(name);
+        }
+    }
+    fn names<T>(table: &[(&'static str, T)]) -> Vec<&'static str> {
+        table.iter().map(|(name, _)| *name).collect()
+    }
+    Python::with_gil(|py| {
+        let dict = PyDict::new_bound(py);
+        dict.set_item("operators", operators)?;
+        dict.set_item("unary_operators", unary_operators)?;
+        dict.set_item("functions", FUNCTIONS.iter().map(|(name, ..)| *name).collect::<Vec<_>>())?;
+        dict.set_item("constants", constants)?;
+        dict.set_item("modes", modes)?;
+        dict.set_item("number_formats", names(NUMBER_FORMATS))?;
+        dict.set_item("divisions", names(DIVISIONS))?;
+        Ok(dict.into_py(py))
+    })
+}
+
+#[pymodule]
+fn infix_parser(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(parse_infix, m)?)?;
+    m.add_function(wrap_pyfunction!(parse_infix_batch, m)?)?;
+    m.add_function(wrap_pyfunction!(parse_lines, m)?)?;
+    m.add_function(wrap_pyfunction!(try_parse, m)?)?;
+    m.add_function(wrap_pyfunction!(simplify_once, m)?)?;
+    m.add_function(wrap_pyfunction!(simplify_full, m)?)?;
+    m.add_function(wrap_pyfunction!(node_at, m)?)?;
+    m.add_function(wrap_pyfunction!(run_program, m)?)?;
+    m.add_function(wrap_pyfunction!(unused_assignments, m)?)?;
+    m.add_function(wrap_pyfunction!(features, m)?)?;
+    m.add_function(wrap_pyfunction!(evaluate_array, m)?)?;
+    m.add_function(wrap_pyfunction!(evaluate_with, m)?)?;
+    m.add_function(wrap_pyfunction!(evaluate_trace, m)?)?;
+    m.add_function(wrap_pyfunction!(replace, m)?)?;
+    m.add_function(wrap_pyfunction!(rename_variable, m)?)?;
+    m.add_function(wrap_pyfunction!(root_op, m)?)?;
+    m.add_function(wrap_pyfunction!(to_dot, m)?)?;
+    m.add_function(wrap_pyfunction!(to_edge_list, m)?)?;
+    m.add_function(wrap_pyfunction!(pretty_tree, m)?)?;
+    m.add_function(wrap_pyfunction!(format, m)?)?;
+    m.add_function(wrap_pyfunction!(redundant_parens, m)?)?;
+    m.add_function(wrap_pyfunction!(paren_depth, m)?)?;
+    m.add_function(wrap_pyfunction!(lex, m)?)?;
+    m.add_function(wrap_pyfunction!(to_rpn, m)?)?;
+    m.add_function(wrap_pyfunction!(to_rpn_string, m)?)?;
+    m.add_function(wrap_pyfunction!(parse_rpn, m)?)?;
+    m.add_function(wrap_pyfunction!(parse_rpn_string, m)?)?;
+    m.add_function(wrap_pyfunction!(to_sexpr, m)?)?;
+    m.add_function(wrap_pyfunction!(contains, m)?)?;
+    m.add_function(wrap_pyfunction!(same_under_assoc, m)?)?;
+    m.add_function(wrap_pyfunction!(semantic_hash, m)?)?;
+    m.add_function(wrap_pyfunction!(literals, m)?)?;
+    m.add_function(wrap_pyfunction!(ast_size, m)?)?;
+    m.add_function(wrap_pyfunction!(subexpressions, m)?)?;
+    m.add_function(wrap_pyfunction!(poly_coeffs, m)?)?;
+    m.add_function(wrap_pyfunction!(expand, m)?)?;
+    m.add_function(wrap_pyfunction!(roots, m)?)?;
+    m.add_function(wrap_pyfunction!(likely_equivalent, m)?)?;
+    m.add_class::<CachingParser>()?;
+    m.add_class::<ParseConfig>()?;
+    m.add_class::<Evaluator>()?;
+    m.add_class::<Compiled>()?;
+    m.add_class::<LineParser>()?;
+    m.add_function(wrap_pyfunction!(compile, m)?)?;
+    m.add("ParseError", m.py().get_type_bound::<ParseError>())?;
+    m.add("EvaluationError", m.py().get_type_bound::<EvaluationError>())?;
+    m.add("LimitError", m.py().get_type_bound::<LimitError>())?;
+    m.add("TokenizeError", m.py().get_type_bound::<TokenizeError>())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests;
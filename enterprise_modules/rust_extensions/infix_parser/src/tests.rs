@@ -0,0 +1,1743 @@
+use super::*;
+
+fn parse(source: &str) -> Expr {
+    parse_source(source).unwrap_or_else(|e| panic!("{:?} failed to parse: {}", source, e))
+}
+
+fn simplified(source: &str) -> Expr {
+    simplify(parse(source), &FoldOptions::default())
+}
+
+/// The tree with every sign kept as its own node, as `preserve` parses.
+fn parse_signed(source: &str) -> Expr {
+    let prec = Precedence { keep_signs: true, ..Precedence::default() };
+    parse_statement_with(&tokenize(source), &prec).unwrap()
+}
+
+/// The constant `source` folds to.
+fn value(source: &str) -> f64 {
+    match simplified(source) {
+        Expr::Number(n) => n,
+        other => panic!("{:?} did not fold: {:?}", source, other),
+    }
+}
+
+fn texts(source: &str) -> Vec<String> {
+    tokenize(source).into_iter().map(|token| token.text).collect()
+}
+
+fn sexpr(expr: &Expr) -> String {
+    to_sexpr_string(expr)
+}
+
+fn run(source: &str) -> Result<(Env, Option<f64>), String> {
+    execute(parse_program(source)?)
+}
+
+#[test]
+fn run_program_returns_every_assignment_and_the_result() {
+    let (env, result) = run("x = 2; y = x*3; y+1").unwrap();
+    assert_eq!(env, [("x".to_string(), 2.0), ("y".to_string(), 6.0)]);
+    assert_eq!(result, Some(7.0));
+}
+
+#[test]
+fn run_program_reassigns_in_place_and_ends_without_result_on_assignment() {
+    let (env, result) = run("x = 1; y = 2; x = x + y").unwrap();
+    assert_eq!(env, [("x".to_string(), 3.0), ("y".to_string(), 2.0)]);
+    assert_eq!(result, None);
+}
+
+#[test]
+fn run_program_names_an_undefined_variable() {
+    let message = run("a = b + 1").unwrap_err();
+    assert!(message.contains("'b'"), "{}", message);
+}
+
+fn parse_with(source: &str, options: &ParseOptions) -> Result<Expr, Failure> {
+    parse_stages(source, options, false).map(|(_, expr)| expr)
+}
+
+fn limited(limits: Limits) -> ParseOptions {
+    ParseOptions { limits, ..ParseOptions::default() }
+}
+
+#[test]
+fn each_limit_names_itself_when_tripped() {
+    let cases = [
+        (Limits { max_tokens: Some(3), ..Limits::default() }, "max_tokens exceeded: 7 > 3"),
+        (Limits { max_depth: Some(2), ..Limits::default() }, "max_depth exceeded: 4 > 2"),
+        (Limits { max_nodes: Some(4), ..Limits::default() }, "max_nodes exceeded: 7 > 4"),
+    ];
+    for (limits, message) in cases {
+        let failure = parse_with("1+2*3-4", &limited(limits)).unwrap_err();
+        assert_eq!(failure, Failure::Limit(message.to_string()));
+    }
+}
+
+#[test]
+fn limits_at_the_boundary_pass() {
+    let limits = Limits {
+        max_tokens: Some(3),
+        max_depth: Some(2),
+        max_nodes: Some(3),
+        ..Limits::default()
+    };
+    assert_eq!(parse_with("1+2", &limited(limits)), Ok(Expr::Number(3.0)));
+}
+
+#[test]
+fn symbolic_trees_are_limited_after_parsing() {
+    let limits = Limits { max_nodes: Some(4), ..Limits::default() };
+    let failure = parse_with("x*y + z", &limited(limits)).unwrap_err();
+    assert_eq!(failure, Failure::Limit("max_nodes exceeded: 5 > 4".to_string()));
+}
+
+fn has_unary_plus(expr: &Expr) -> bool {
+    matches!(expr, Expr::Unary { op: "+", .. }) || children(expr).into_iter().any(has_unary_plus)
+}
+
+#[test]
+fn simplify_drops_every_unary_plus() {
+    for source in ["+x", "+(x+1)", "+3", "-+x", "x*+y", "++-+x"] {
+        let tree = parse_signed(source);
+        assert!(has_unary_plus(&tree), "{}", source);
+        let folded = simplify(tree, &FoldOptions::default());
+        assert!(!has_unary_plus(&folded), "{} -> {}", source, sexpr(&folded));
+    }
+    let plus = |source| simplify(parse_signed(source), &FoldOptions::default());
+    assert_eq!(sexpr(&plus("+(x+1)")), "(+ x 1)");
+    assert_eq!(sexpr(&plus("++-+x")), "(- x)");
+    assert_eq!(sexpr(&simplified("+(x+1) * +2")), "(* (+ x 1) 2)");
+}
+
+#[test]
+fn unbalanced_parentheses_are_reported_precisely() {
+    let cases = [
+        ("1+2)", "unmatched ')' at position 3"),
+        ("(1+2", "missing ')' — expected 1 more"),
+        ("((1)", "missing ')' — expected 1 more"),
+        ("((1", "missing ')' — expected 2 more"),
+        ("(1))+(2", "unmatched ')' at position 3"),
+    ];
+    for (source, message) in cases {
+        assert_eq!(parse_source(source).unwrap_err(), message, "{}", source);
+    }
+    assert_eq!(parse_program("x=(1").unwrap_err(), "missing ')' — expected 1 more");
+    assert_eq!(simplified("((1)+2)"), Expr::Number(3.0));
+}
+
+#[test]
+fn one_set_of_options_serves_every_parse() {
+    let fold = FoldOptions { degrees: true, ..FoldOptions::default() };
+    let options = ParseOptions { fold, ..ParseOptions::default() };
+    let value = |source| match parse_with(source, &options) {
+        Ok(Expr::Number(n)) => n,
+        other => panic!("{} -> {:?}", source, other),
+    };
+    assert!((value("sin(90)") - 1.0).abs() < 1e-12);
+    assert!((value("cos(60)") - 0.5).abs() < 1e-12);
+    assert!((value("tan(45)") - 1.0).abs() < 1e-12);
+}
+
+#[test]
+fn every_reported_operator_parses_as_itself() {
+    let tables = [AND_OPS, COMPARISON_OPS, SHIFT_OPS, ADDITIVE_OPS, MULTIPLICATIVE_OPS, POWER_OPS];
+    for op in tables.into_iter().flatten() {
+        assert!(Precedence::default().infix(op).is_some(), "{}", op);
+        let source = format!("a {} b", op);
+        match parse(&source) {
+            Expr::Binary { op: parsed, .. } => assert_eq!(parsed, *op),
+            other => panic!("{} -> {:?}", source, other),
+        }
+    }
+}
+
+#[test]
+fn reported_modes_come_from_the_keyword_tables() {
+    for name in ["degrees", "number_format", "preserve", "allow_special"] {
+        assert!(ParseKeywords::NAMES.contains(&name), "{}", name);
+    }
+    for name in ["auto_exact", "division", "timeout_ms"] {
+        assert!(EvaluateKeywords::NAMES.contains(&name), "{}", name);
+    }
+    assert_eq!(NUMBER_FORMATS.len(), 3);
+    assert!(DIVISIONS.iter().any(|(name, _)| *name == "fraction"));
+}
+
+#[test]
+fn caching_parser_keys_on_tokens_not_spacing() {
+    let mut parser = CachingParser::default();
+    for source in ["x+2", "x + 2", "  x+  2 "] {
+        assert_eq!(sexpr(&parser.parse_cached(source).unwrap()), "(+ x 2)");
+    }
+    assert_eq!(parser.cache.len(), 1);
+    parser.parse_cached("2+x").unwrap();
+    assert_eq!(parser.cache.len(), 2);
+    assert!(parser.parse_cached("1 2").is_err());
+    assert_eq!(parser.cache.len(), 2);
+}
+
+#[test]
+fn floor_division_rounds_toward_negative_infinity() {
+    assert_eq!(value("7 // 2"), 3.0);
+    assert_eq!(value("-7 // 2"), -4.0);
+    assert_eq!(value("7 // -2"), -4.0);
+    assert_eq!(value("7 / 2"), 3.5);
+    assert_eq!(value("7 // 0"), f64::INFINITY);
+    assert_eq!(value("-7 // 0"), f64::NEG_INFINITY);
+    assert_eq!(texts("a//b/c"), ["a", "//", "b", "/", "c"]);
+    assert_eq!(sexpr(&parse("x // 2 * 3")), "(* (// x 2) 3)");
+    assert_eq!(run("a = 9; a // 4").unwrap().1, Some(2.0));
+}
+
+#[test]
+fn identifiers_take_underscores_digits_and_unicode_letters() {
+    for name in ["x_1", "theta", "α", "_tmp2", "βγ"] {
+        assert_eq!(texts(name), [name]);
+        assert_eq!(parse(name), Expr::Variable(name.to_string()));
+    }
+    assert_eq!(texts("_tmp2*βγ"), ["_tmp2", "*", "βγ"]);
+    assert_eq!(texts("2x"), ["2", "x"]);
+    let (env, result) = run("x_1 = 2; α = x_1*3; α").unwrap();
+    assert_eq!(env[1], ("α".to_string(), 6.0));
+    assert_eq!(result, Some(6.0));
+}
+
+fn replaced(source: &str, target: &str, replacement: &str) -> String {
+    to_infix(&replace_subtree(parse(source), &parse(target), &parse(replacement)))
+}
+
+#[test]
+fn replace_substitutes_structural_matches() {
+    assert_eq!(replaced("x*x", "x", "y+1"), "(y + 1) * (y + 1)");
+    assert_eq!(replaced("a-(b-c) + x*2", "b-c", "-z"), "a - -z + x * 2");
+    assert_eq!(replaced("-(x+1)*2.5/q", "q", "a*b"), "-(x + 1) * 2.5 / (a * b)");
+    // Matching is structural, so a reordered sum is not a match.
+    assert_eq!(replaced("x+1", "1+x", "0"), "x + 1");
+}
+
+#[test]
+fn flat_constant_chains_fold_without_a_tree() {
+    let chain = |source| fold_constant_chain(&tokenize(source));
+    assert_eq!(chain("1+2-3"), Some(0.0));
+    assert_eq!(chain("2*3/4//1"), Some(1.0));
+    for source in ["5", "1+2*3", "x+1", "1+x", "1+"] {
+        assert_eq!(chain(source), None, "{}", source);
+    }
+    assert_eq!(chain("1-2-3"), Some(value("1-2-3")));
+    let long = vec!["1"; 1_000_000].join("+");
+    assert_eq!(parse_with(&long, &ParseOptions::default()), Ok(Expr::Number(1_000_000.0)));
+}
+
+#[test]
+fn the_chain_fast_path_still_applies_tree_limits() {
+    let limits = Limits { max_depth: Some(2), ..Limits::default() };
+    let failure = parse_with("1+1+1", &limited(limits)).unwrap_err();
+    assert_eq!(failure, Failure::Limit("max_depth exceeded: 3 > 2".to_string()));
+}
+
+#[test]
+fn batches_report_each_failure_in_place() {
+    let batch: Vec<String> = ["1+2", "1+", "(1", "x*2", "1.2.3"].map(String::from).into();
+    let results = parse_batch(&batch, &ParseOptions::default(), false).unwrap();
+    let expected = [
+        Ok(Expr::Number(3.0)),
+        Err("expected expression after '+' at position 1".to_string()),
+        Err("missing ')' — expected 1 more".to_string()),
+        Ok(parse("x*2")),
+        Err("malformed number '1.2.3' at position 0".to_string()),
+    ];
+    assert_eq!(results, expected);
+}
+
+#[test]
+fn stopping_batches_name_the_failing_index() {
+    let batch: Vec<String> = ["x", "1.2.3", "1+"].map(String::from).into();
+    let failure = parse_batch(&batch, &ParseOptions::default(), true).unwrap_err();
+    let message = "expression 1: malformed number '1.2.3' at position 0";
+    assert_eq!(failure, Failure::Tokenize(message.to_string()));
+    let batch: Vec<String> = ["1", "1+2"].map(String::from).into();
+    let limits = Limits { max_tokens: Some(2), ..Limits::default() };
+    let failure = parse_batch(&batch, &limited(limits), true).unwrap_err();
+    assert_eq!(failure, Failure::Limit("expression 1: max_tokens exceeded: 3 > 2".to_string()));
+}
+
+#[test]
+fn root_name_reads_the_unsimplified_root() {
+    let root = |source| root_name(&parse(source)).map(str::to_string);
+    assert_eq!(root("1+2*3").as_deref(), Some("+"));
+    assert_eq!(root("sin(x)").as_deref(), Some("sin"));
+    assert_eq!(root("-x").as_deref(), Some("-"));
+    assert_eq!(root("(a < b)").as_deref(), Some("<"));
+    assert_eq!(root("5"), None);
+    assert_eq!(root("x"), None);
+}
+
+#[test]
+fn trailing_operators_are_positional_parse_errors() {
+    let cases = [
+        ("1+", "expected expression after '+' at position 1"),
+        ("2*", "expected expression after '*' at position 1"),
+        ("3-", "expected expression after '-' at position 1"),
+        ("-", "expected expression after '-' at position 0"),
+        ("2^", "expected expression after '^' at position 1"),
+    ];
+    for (source, message) in cases {
+        assert_eq!(parse_source(source).unwrap_err(), message, "{}", source);
+    }
+}
+
+#[test]
+fn identical_operands_cancel() {
+    for source in ["x/x", "(a*b)/(a*b)", "x/x*3/3"] {
+        assert_eq!(simplified(source), Expr::Number(1.0), "{}", source);
+    }
+    for source in ["x-x", "x+(-x)", "-x+x", "(a+1)-(a+1)"] {
+        assert_eq!(simplified(source), Expr::Number(0.0), "{}", source);
+    }
+    assert_eq!(sexpr(&simplified("x/y")), "(/ x y)");
+    assert_eq!(sexpr(&simplified("x-y")), "(- x y)");
+    assert_eq!(sexpr(&simplified("x+(-y)")), "(- x y)");
+}
+
+#[test]
+fn dot_graphs_give_every_node_its_own_id() {
+    let dot = dot_graph(&parse("x*x + 1"));
+    assert!(dot.starts_with("digraph AST {\n") && dot.ends_with("}\n"), "{}", dot);
+    assert_eq!(dot.matches("[label=").count(), 5);
+    assert_eq!(dot.matches(" -> ").count(), 4);
+    assert_eq!(dot.matches("[label=\"Variable(x)\"]").count(), 2, "{}", dot);
+    assert!(dot.contains("    n0 -> n1;\n") && dot.contains("    n0 -> n4;\n"), "{}", dot);
+}
+
+#[test]
+fn naming_controls_the_type_strings() {
+    let binary = parse("x + y");
+    assert_eq!(Naming::default().type_name(&binary), "Binary");
+    assert_eq!(Naming::Snake.type_name(&binary), "binary_op");
+    assert_eq!(Naming::Snake.type_name(&parse("-x")), "unary_op");
+    assert_eq!(Naming::Snake.type_name(&parse("x")), "variable");
+    assert_eq!(Naming::Default.type_name(&parse("f(x)")), "Call");
+}
+
+#[test]
+fn physical_constants_are_opt_in() {
+    let options = ParseOptions { physical_constants: true, ..ParseOptions::default() };
+    assert_eq!(parse_with("2*c", &options), Ok(Expr::Number(599_584_916.0)));
+    assert_eq!(parse_with("g", &options), Ok(Expr::Number(9.806_65)));
+    assert_eq!(parse_with("2*c", &ParseOptions::default()), Ok(parse("2*c")));
+    // Caller bindings shadow the library.
+    let constants = HashMap::from([("c".to_string(), 3.0)]);
+    let options = ParseOptions { constants, ..options };
+    assert_eq!(parse_with("2*c + h/h", &options), Ok(Expr::Number(7.0)));
+}
+
+#[test]
+fn caller_constants_fold_and_leave_other_names_symbolic() {
+    let constants = HashMap::from([("pi".to_string(), 3.0)]);
+    let options = ParseOptions { constants, ..ParseOptions::default() };
+    let expr = parse_with("r*r*pi", &options).unwrap();
+    assert_eq!(sexpr(&expr), "(* (^ r 2) 3)");
+    let constants = HashMap::from([("a".to_string(), 1.0), ("b".to_string(), 2.0)]);
+    let options = ParseOptions { constants, ..ParseOptions::default() };
+    assert_eq!(parse_with("a+b", &options), Ok(Expr::Number(3.0)));
+}
+
+#[test]
+fn rounding_builtins_round_halves_away_from_zero_unless_banker() {
+    let half_up = FoldOptions::default();
+    let banker = FoldOptions { banker_rounding: true, ..FoldOptions::default() };
+    let apply = |name, args: &[f64], fold| apply_function(name, args, fold).unwrap();
+    assert_eq!(apply("round", &[2.5], &half_up), 3.0);
+    assert_eq!(apply("round", &[-2.5], &half_up), -3.0);
+    assert_eq!(apply("round", &[2.5], &banker), 2.0);
+    assert_eq!(apply("round", &[3.5], &banker), 4.0);
+    assert_eq!(apply("round", &[0.125, 2.0], &banker), 0.12);
+    assert_eq!(apply("round", &[1234.0, -2.0], &half_up), 1200.0);
+    assert_eq!(apply("floor", &[-1.5], &half_up), -2.0);
+    assert_eq!(apply("ceil", &[-1.5], &half_up), -1.0);
+    assert_eq!(apply("trunc", &[-1.7], &half_up), -1.0);
+    assert_eq!(value("round(2.71828, 2)"), 2.72);
+    let message = "floor expects 1 argument(s), got 0 at position 0";
+    assert_eq!(parse_source("floor()").unwrap_err(), message);
+}
+
+#[test]
+fn contains_finds_structural_subtrees_only() {
+    let contains = |source, sub: &str| {
+        let sub = parse(sub);
+        find_subtree(&parse(source), &sub, structural_hash(&sub)).1
+    };
+    assert!(contains("a+(b*c)", "b*c"));
+    assert!(contains("floor(x+1)*2", "x+1"));
+    assert!(contains("x", "x"));
+    assert!(!contains("a+(b*c)", "c*b"));
+    // `a+b*c` groups as `a+(b*c)`, so `a+b` is not a subtree.
+    assert!(!contains("a+b*c", "a+b"));
+    assert_eq!(structural_hash(&parse("b*c")), structural_hash(&parse("(b) * c")));
+}
+
+#[test]
+fn comparison_chains_desugar_to_and() {
+    assert_eq!(value("1 < 5 < 10"), 1.0);
+    assert_eq!(value("1 < 20 < 10"), 0.0);
+    assert_eq!(sexpr(&parse("a < b <= c")), "(and (< a b) (<= b c))");
+    // Parentheses opt back into comparing a comparison's result.
+    assert_eq!(sexpr(&parse("(a < b) < c")), "(< (< a b) c)");
+    assert_eq!(value("(3 < 2) < 1"), 1.0);
+    assert_eq!(run("x = 0; x != 0 and 1 // x > 0").unwrap().1, Some(0.0));
+    assert_eq!(parse_source("1 <").unwrap_err(), "expected expression after '<' at position 2");
+}
+
+#[test]
+fn tree_text_draws_rails_for_open_siblings() {
+    let expected = "\
+Binary(-)
+├─ Binary(+)
+│  ├─ Number(1)
+│  └─ Binary(*)
+│     ├─ Number(2)
+│     └─ Variable(x)
+└─ Call(round)
+   ├─ Variable(y)
+   └─ Binary(+)
+      ├─ Number(2)
+      └─ Number(1)
+";
+    let source = "(1 + 2*x) - round(y, 2+1)";
+    assert_eq!(tree_text(&parse(source)), expected);
+    let folded = tree_text(&simplified(source));
+    assert!(folded.ends_with("└─ Call(round)\n   ├─ Variable(y)\n   └─ Number(3)\n"), "{}", folded);
+    assert_eq!(tree_text(&parse("x")), "Variable(x)\n");
+}
+
+#[test]
+fn inexact_int_literals_are_found_by_position() {
+    let found = |source| inexact_int_literal(&tokenize(source)).map(|t| (t.text.clone(), t.pos));
+    let huge = "99999999999999999999999";
+    assert_eq!(found("99999999999999999999999 + x"), Some((huge.to_string(), 0)));
+    assert_eq!(found("x + 9007199254740993"), Some(("9007199254740993".to_string(), 4)));
+    assert_eq!(found("9223372036854775808"), Some(("9223372036854775808".to_string(), 0)));
+    for source in ["1152921504606846976 * x", "007", "9007199254740992", "1.5e30", "0"] {
+        assert_eq!(found(source), None, "{}", source);
+    }
+}
+
+#[test]
+fn literals_are_collected_in_source_order() {
+    let literals = |source| {
+        let mut out = Vec::new();
+        collect_literals(&parse(source), &mut out);
+        out
+    };
+    assert_eq!(literals("2*x + 3.5 - 2"), [2.0, 3.5, 2.0]);
+    assert_eq!(literals("round(1.25, 1) * -4"), [1.25, 1.0, 4.0]);
+    assert!(literals("x").is_empty());
+}
+
+#[test]
+fn balance_flattens_long_sums_to_logarithmic_depth() {
+    let source = (0..1000).map(|i| format!("x{}", i)).collect::<Vec<_>>().join(" + ");
+    let env: Env = (0..1000).map(|i| (format!("x{}", i), f64::from(i))).collect();
+    let flat = parse(&source);
+    let balanced = balance(flat.clone());
+    assert_eq!(depth(&flat), 1000);
+    assert_eq!(depth(&balanced), 11);
+    assert_eq!(evaluate(&balanced, &env), Ok(499_500.0));
+    assert_eq!(evaluate(&flat, &env), evaluate(&balanced, &env));
+    // `-` is not associative, so only the `*` chain inside it is regrouped.
+    let mixed = balance(parse("a*b*c*d - e - f + g"));
+    assert_eq!(sexpr(&mixed), "(+ (- (- (* (* a b) (* c d)) e) f) g)");
+}
+
+#[test]
+fn float_literal_marks_stay_internal() {
+    let marked = |source| parse_statement_expr(&mark_float_literals(tokenize(source))).unwrap();
+    let expr = marked("2.0*x + 3 - 5e3");
+    let mark = |n| Expr::Call { name: FLOAT_LITERAL.to_string(), args: vec![Expr::Number(n)] };
+    assert!(find_subtree(&expr, &mark(2.0), structural_hash(&mark(2.0))).1);
+    assert!(find_subtree(&expr, &mark(5000.0), structural_hash(&mark(5000.0))).1);
+    assert_eq!(unmark_float_literals(expr), parse("2.0*x + 3 - 5e3"));
+    assert_eq!(unmark_float_literals(marked("-1.0 * 0xff")), parse("-1.0 * 0xff"));
+    assert!(!FUNCTIONS.iter().any(|(name, _, _)| *name == "float" || *name == FLOAT_LITERAL));
+    assert!(apply_function("float", &[2.0], &FoldOptions::default()).is_err());
+    assert_eq!(sexpr(&simplified("float(3) + x")), "(+ (float 3) x)");
+}
+
+fn levels(overrides: &[(&str, u8)]) -> Result<Precedence, String> {
+    let overrides: Vec<(String, u8)> =
+        overrides.iter().map(|&(op, level)| (op.to_string(), level)).collect();
+    Precedence::with_levels(&overrides)
+}
+
+fn parse_at(source: &str, prec: &Precedence) -> Expr {
+    parse_statement_with(&tokenize(source), prec).unwrap()
+}
+
+#[test]
+fn precedence_overrides_regroup_operators() {
+    let low_power = levels(&[("^", 15)]).unwrap();
+    assert_eq!(sexpr(&parse_at("2*3^2", &low_power)), "(^ (* 2 3) 2)");
+    assert_eq!(sexpr(&parse("2*3^2")), "(* 2 (^ 3 2))");
+    let high_times = levels(&[("*", 25)]).unwrap();
+    assert_eq!(sexpr(&parse_at("8/2*2", &high_times)), "(/ 8 (* 2 2))");
+    let high_plus = levels(&[("+", 12)]).unwrap();
+    assert_eq!(sexpr(&parse_at("a-b+c", &high_plus)), "(- a (+ b c))");
+    assert_eq!(levels(&[("%", 3)]).map(|p| p.infix("%").unwrap().power), Ok(ARITHMETIC_BASE + 3));
+}
+
+#[test]
+fn precedence_overrides_are_validated() {
+    let cases = [
+        (("^", 20), "'*' and '^' share precedence level 20 but associate differently"),
+        (("+", 0), "precedence level for '+' must be at least 1"),
+        (
+            ("<", 3),
+            "unknown operator '<' in precedence; expected one of \
+             <<, >>, +, -, *, /, //, %, div, mod, rem, ^",
+        ),
+    ];
+    for (level, message) in cases {
+        assert_eq!(levels(&[level]).unwrap_err(), message);
+    }
+}
+
+/// Expressions with the results of the parser before the Pratt rewrite, one
+/// per line: the source, its unsimplified tree, then what it simplified to
+/// under each of `PARITY_LEVELS`. A result is either `error: message` or
+/// `= infix`, a fully parenthesized form of the old simplified tree.
+const PARITY_CASES: &str = include_str!("tests/parity.tsv");
+
+const PARITY_LEVELS: [&[(&str, u8)]; 5] =
+    [&[], &[("^", 15)], &[("*", 25)], &[("+", 12)], &[("//", 30), ("^", 40)]];
+
+/// A value for every variable, distinct enough that no case divides by zero.
+fn parity_env(expr: &Expr) -> Env {
+    let mut names = Vec::new();
+    collect_variables(expr, &mut names);
+    let value = |name: &str| {
+        let sum: u32 = name.bytes().map(u32::from).sum();
+        0.75 + 0.5 * f64::from(sum % 7)
+    };
+    names.into_iter().map(|name| {
+        let value = value(&name);
+        (name, value)
+    }).collect()
+}
+
+/// Calls to functions with no builtin rewritten as a weighted sum of their
+/// arguments, so that trees using them still have a value to compare.
+fn valued_calls(expr: Expr) -> Expr {
+    match map_children(expr, &mut valued_calls) {
+        Expr::Call { name, args } if !FUNCTIONS.iter().any(|(n, _, _)| *n == name) => {
+            let offset: u32 = name.bytes().map(u32::from).sum();
+            let base = Expr::Number(1.5 + f64::from(offset % 5));
+            args.into_iter().zip(1..).fold(base, |sum, (arg, weight)| {
+                binary("+", sum, binary("*", Expr::Number(f64::from(weight)), arg))
+            })
+        }
+        other => other,
+    }
+}
+
+fn same_value(a: &Result<f64, String>, b: &Result<f64, String>) -> bool {
+    match (a, b) {
+        (Ok(a), Ok(b)) => {
+            a == b || (a.is_nan() && b.is_nan()) || (a - b).abs() <= 1e-9 * a.abs().max(b.abs())
+        }
+        (Err(a), Err(b)) => a == b,
+        _ => false,
+    }
+}
+
+#[test]
+fn the_pratt_parser_matches_the_old_parser() {
+    let mut mismatches = Vec::new();
+    for line in PARITY_CASES.lines() {
+        let columns: Vec<&str> = line.split('\t').collect();
+        let [source, tree, results @ ..] = &columns[..] else { panic!("bad case {:?}", line) };
+        let parsed = parse_source(source);
+        let got = parsed.as_ref().map_or_else(|e| format!("error: {}", e), sexpr);
+        if got != *tree {
+            mismatches.push(format!("{}: tree {} != {}", source, got, tree));
+        }
+        for (overrides, old) in PARITY_LEVELS.iter().zip(results) {
+            let prec = levels(overrides).unwrap();
+            let tokens = tokenize(source);
+            let current = check_parens(source).and_then(|()| parse_statement_with(&tokens, &prec));
+            let agrees = match (current, old.strip_prefix("= ")) {
+                (Ok(expr), Some(old)) => {
+                    let old = parse(old);
+                    let env = parity_env(&expr);
+                    let folded = valued_calls(simplify(expr, &FoldOptions::default()));
+                    same_value(&evaluate(&folded, &env), &evaluate(&valued_calls(old), &env))
+                }
+                (Err(message), None) => old.strip_prefix("error: ") == Some(message.as_str()),
+                _ => false,
+            };
+            if !agrees {
+                mismatches.push(format!("{} under {:?}: expected {}", source, overrides, old));
+            }
+        }
+    }
+    assert_eq!(PARITY_CASES.lines().count(), 463);
+    assert!(mismatches.is_empty(), "{}", mismatches.join("\n"));
+}
+
+fn rpn(source: &str) -> String {
+    let mut out = Vec::new();
+    write_rpn(&parse(source), &mut out);
+    out.join(" ")
+}
+
+#[test]
+fn rpn_strings_join_tokens_with_spaces() {
+    assert_eq!(rpn("3 + 4 * 2"), "3 4 2 * +");
+    assert_eq!(rpn("-(a - b) * +c ^ 2"), "a b - neg c 2 ^ *");
+    assert_eq!(rpn("round(x / 2, 1) < 1.5"), "x 2 / 1 round/2 1.5 <");
+    assert_eq!(rpn("f()"), "f/0");
+}
+
+#[test]
+fn mul_symbols_multiply_without_breaking_decimal_points() {
+    let with = |source, symbol| {
+        let options = ParseOptions { mul_symbol: Some(symbol), ..ParseOptions::default() };
+        parse_with(source, &options).unwrap()
+    };
+    assert_eq!(with("3·4", '·'), Expr::Number(12.0));
+    assert_eq!(with("3.4", '.'), Expr::Number(3.4));
+    assert_eq!(with("(3).(4)", '.'), Expr::Number(12.0));
+    assert_eq!(sexpr(&with("x.y + 2.5.z", '.')), "(+ (* x y) (* 2.5 z))");
+    assert_eq!(texts("3·4"), ["3", "·", "4"]);
+    let message = "unexpected token '·' at position 1";
+    assert_eq!(parse_with("3·4", &ParseOptions::default()), Err(Failure::Parse(message.into())));
+}
+
+#[test]
+fn shifts_are_checked_64_bit_integer_operations() {
+    assert_eq!(apply_shift("<<", 1.0, 10.0), Ok(1024.0));
+    assert_eq!(apply_shift(">>", -5.0, 1.0), Ok(-3.0));
+    assert_eq!(shift_i64("<<", 1, 62), Ok(1 << 62));
+    let out_of_range =
+        |n| format!("shift amount {} is out of range for a 64-bit integer (expected 0 to 63)", n);
+    assert_eq!(shift_i64("<<", 1, 64), Err(out_of_range(64)));
+    assert_eq!(shift_i64(">>", 1, -1), Err(out_of_range(-1)));
+    assert_eq!(shift_i64("<<", 3, 62), Err("3 << 62 overflows a 64-bit integer".to_string()));
+    let message = "shift operands must be 64-bit integers, got 1.5";
+    assert_eq!(apply_shift("<<", 1.5, 2.0), Err(message.to_string()));
+    // An invalid constant shift stays unfolded, and parsing reports it.
+    assert_eq!(sexpr(&simplified("1 << 64")), "(<< 1 64)");
+    assert_eq!(run("r = 1 << 64").unwrap_err(), out_of_range(64));
+    let parsed = |source| parse_with(source, &ParseOptions::default());
+    assert_eq!(parsed("1 << 10"), Ok(Expr::Number(1024.0)));
+    assert_eq!(parsed("x + (1 << 64)"), Err(Failure::Evaluation(out_of_range(64))));
+}
+
+#[test]
+fn sexprs_nest_with_balanced_parentheses() {
+    let cases = [
+        ("1 + 2*3", "(+ 1 (* 2 3))"),
+        ("-x", "(- x)"),
+        ("-(a - b) * c", "(* (- (- a b)) c)"),
+        ("round(x / 2, 1)", "(round (/ x 2) 1)"),
+        ("f()", "(f)"),
+        ("x", "x"),
+    ];
+    for (source, expected) in cases {
+        let out = sexpr(&parse(source));
+        assert_eq!(out, expected, "{}", source);
+        assert_eq!(out.matches('(').count(), out.matches(')').count(), "{}", out);
+    }
+}
+
+#[test]
+fn sign_runs_collapse_by_parity() {
+    let expected = ["x", "(- x)", "x", "(- x)", "x", "(- x)", "x"];
+    for (minuses, expected) in expected.iter().enumerate() {
+        let source = format!("{}x", "- ".repeat(minuses));
+        assert_eq!(sexpr(&parse(&source)), *expected, "{}", source);
+    }
+    assert_eq!(sexpr(&parse("-+-x")), "x");
+    assert_eq!(sexpr(&parse("--2^2")), "(^ 2 2)");
+    // With signs kept, each one is its own node.
+    assert_eq!(sexpr(&parse_signed("--x")), "(- (- x))");
+}
+
+fn special() -> ParseOptions {
+    ParseOptions { allow_special: true, ..ParseOptions::default() }
+}
+
+#[test]
+fn special_literals_follow_ieee_rules_when_allowed() {
+    let number = |source| match parse_with(source, &special()) {
+        Ok(Expr::Number(n)) => n,
+        other => panic!("{} -> {:?}", source, other),
+    };
+    assert!(number("inf - inf").is_nan());
+    assert_eq!(number("1/inf"), 0.0);
+    assert_eq!(number("-infinity * 2"), f64::NEG_INFINITY);
+    assert_eq!(number("nan == nan"), 0.0);
+    assert_eq!(parse_with("inf + 1", &ParseOptions::default()), Ok(parse("inf + 1")));
+    let constants = HashMap::from([("inf".to_string(), 5.0)]);
+    assert_eq!(parse_with("inf", &ParseOptions { constants, ..special() }), Ok(Expr::Number(5.0)));
+}
+
+#[test]
+fn sampling_separates_equivalent_from_different_expressions() {
+    let agree = |a, b, samples| agree_at_samples(&parse(a), &parse(b), samples);
+    assert_eq!(agree("x+x", "2*x", 50), Ok(true));
+    assert_eq!(agree("(a+b)^2", "a^2 + 2*a*b + b^2", 100), Ok(true));
+    assert_eq!(agree("x+1", "x+2", 50), Ok(false));
+    assert_eq!(agree("x*y", "x", 10), Ok(false));
+    assert_eq!(agree("1", "2", 0), Ok(true));
+    assert_eq!(agree("f(x)", "x", 5), Err("unknown function 'f'".to_string()));
+}
+
+#[test]
+fn unused_assignments_ignore_overwritten_and_final_reads() {
+    let unused = |source| unused_names(&parse_program(source).unwrap());
+    assert_eq!(unused("a=1; b=2; a+0"), ["b"]);
+    // The first `a` is overwritten before anything reads it.
+    assert_eq!(unused("a=1; a=2; a"), ["a"]);
+    assert_eq!(unused("x=1; x = x+1; y = x"), ["y"]);
+    assert!(unused("1+2").is_empty());
+}
+
+#[test]
+fn call_depth_is_limited_separately_from_length() {
+    let source = format!("{}x{}", "sin(".repeat(10_000), ")".repeat(10_000));
+    let limits = Limits { max_call_depth: Some(100), ..Limits::default() };
+    let failure = parse_with(&source, &limited(limits)).unwrap_err();
+    assert_eq!(failure, Failure::Limit("max_call_depth exceeded: 10000 > 100".to_string()));
+    // Grouping parentheses are not calls.
+    assert_eq!(call_depth(&tokenize("f(g(h(x))) + (((k(1))))")), 3);
+    let limits = Limits { max_call_depth: Some(2), ..Limits::default() };
+    let failure = parse_with("f(g(h(x)))", &limited(limits)).unwrap_err();
+    assert_eq!(failure, Failure::Limit("max_call_depth exceeded: 3 > 2".to_string()));
+}
+
+#[test]
+fn deep_nesting_is_refused_before_parsing() {
+    let nesting = |source| nesting_depth(&tokenize(source), &Precedence::default());
+    assert_eq!(nesting("-(2^-(x))"), 5);
+    assert_eq!(nesting("- - -x + ((y))"), 2);
+    assert_eq!(nesting("f(-a, -b) * -c^d^e"), 3);
+    let signed = Precedence { keep_signs: true, ..Precedence::default() };
+    assert_eq!(nesting_depth(&tokenize("- - -x"), &signed), 3);
+    let source = format!("{}1{}", "-(".repeat(5000), ")".repeat(5000));
+    let message = "input nests 10000 levels deep, above the limit of 1000";
+    let limits = Limits { max_depth: Some(50), ..Limits::default() };
+    assert_eq!(parse_with(&source, &limited(limits)), Err(Failure::Limit(message.to_string())));
+    let unlimited = parse_with(&source, &ParseOptions::default());
+    assert_eq!(unlimited, Err(Failure::Limit(message.to_string())));
+    assert_eq!(parse_source(&source), Err(message.to_string()));
+}
+
+#[test]
+fn powers_of_a_common_base_combine() {
+    let cases = [
+        ("x^2*x^3", "x ^ 5"),
+        ("x*x", "x ^ 2"),
+        ("x*x*x", "x ^ 3"),
+        ("x^5/x^2", "x ^ 3"),
+        ("x/x^3", "x ^ (-2)"),
+        ("x^2*y*x^3", "x ^ 5 * y"),
+        ("y/x/x", "y / x ^ 2"),
+        ("x^2/x^2", "1"),
+        ("x*y/x", "y"),
+        ("2*x*x", "2 * x ^ 2"),
+        // A symbolic exponent is its own base to the first power.
+        ("x^y*x^y", "(x ^ y) ^ 2"),
+        ("x^y*x", "x ^ y * x"),
+        // Only the chain to the left is searched, not nested products.
+        ("x*(x*y)", "x * (x * y)"),
+    ];
+    for (source, expected) in cases {
+        assert_eq!(to_infix(&simplified(source)), expected, "{}", source);
+    }
+}
+
+#[test]
+fn parse_errors_report_their_position() {
+    let position = |source| match parse_with(source, &ParseOptions::default()) {
+        Err(Failure::Parse(message) | Failure::Tokenize(message)) => error_position(&message),
+        other => panic!("{} -> {:?}", source, other),
+    };
+    assert_eq!(position("1+"), Some(1));
+    assert_eq!(position("1 2"), Some(2));
+    assert_eq!(position("2 + 1.2.3"), Some(4));
+    assert_eq!(position("(1+2"), None);
+    assert_eq!(error_position("a at position 3 then at position 12"), Some(12));
+}
+
+#[test]
+fn like_terms_combine_only_when_asked() {
+    let fold = FoldOptions { like_terms: true, ..FoldOptions::default() };
+    let cases = [
+        ("x + x", "2 * x"),
+        ("x + x + x", "3 * x"),
+        ("3*x + 2*x", "5 * x"),
+        ("x*3 + x", "4 * x"),
+        ("x - 2*x", "-x"),
+        ("x + y - x", "y"),
+        ("x - y - x", "-y"),
+        ("2 + x + 3 - x", "5"),
+        ("x*y + 2*(x*y)", "3 * (x * y)"),
+    ];
+    for (source, expected) in cases {
+        assert_eq!(to_infix(&simplify(parse(source), &fold)), expected, "{}", source);
+        assert_eq!(simplified(source), parse(source), "{}", source);
+    }
+}
+
+fn rpn_tree(source: &str) -> Result<String, String> {
+    let tokens: Vec<&str> = source.split_whitespace().collect();
+    read_rpn(&tokens).map(|expr| sexpr(&expr))
+}
+
+#[test]
+fn rpn_strings_parse_back_into_trees() {
+    assert_eq!(rpn_tree("3 4 + 2 *").as_deref(), Ok("(* (+ 3 4) 2)"));
+    let tokens: Vec<&str> = "3 4 + 2 *".split_whitespace().collect();
+    assert_eq!(simplify(read_rpn(&tokens).unwrap(), &FoldOptions::default()), Expr::Number(14.0));
+    assert_eq!(rpn_tree("3  neg\t2 -").as_deref(), Ok("(- (- 3) 2)"));
+    assert_eq!(rpn_tree("2 3 //").as_deref(), Ok("(// 2 3)"));
+    assert_eq!(rpn_tree("x 2 ^ sin/1").as_deref(), Ok("(sin (^ x 2))"));
+    let leftover = "3 values left on the stack; expected 1";
+    assert_eq!(rpn_tree("1 2 3").unwrap_err(), leftover);
+    assert_eq!(rpn_tree("+").unwrap_err(), "'+' at token 0 needs 2 operand(s), found 0");
+    assert_eq!(rpn_tree(&rpn("-(a - b) * +c ^ 2")), Ok(sexpr(&parse("-(a - b) * c ^ 2"))));
+}
+
+#[test]
+fn traces_record_every_value_in_post_order() {
+    let mut steps = Vec::new();
+    let env = [("x".to_string(), 4.0)];
+    let result = evaluate_observed(&parse("2*(3+x)"), &env, &mut |node, value| {
+        steps.push((to_infix(node), value))
+    });
+    assert_eq!(result, Ok(14.0));
+    let expected = [("2", 2.0), ("3", 3.0), ("x", 4.0), ("3 + x", 7.0), ("2 * (3 + x)", 14.0)];
+    assert_eq!(steps, expected.map(|(node, value)| (node.to_string(), value)));
+    // The right side of a false `and` is skipped, so it is not traced.
+    let mut nodes = Vec::new();
+    evaluate_observed(&parse("0 and 1/0"), &[], &mut |node, _| nodes.push(to_infix(node)))
+        .unwrap();
+    assert_eq!(nodes, ["0", "0 and 1 / 0"]);
+}
+
+#[test]
+fn a_false_left_side_folds_and_away() {
+    assert_eq!(value("0 and y"), 0.0);
+    assert_eq!(value("(1 > 2) and f(y)"), 0.0);
+    // Evaluation reads `y` first here, so it must stay.
+    assert_eq!(sexpr(&simplified("y and 0")), "(and y 0)");
+}
+
+#[test]
+fn gcd_and_lcm_fold_integers_and_reject_the_rest() {
+    assert_eq!(value("gcd(12, 18)"), 6.0);
+    assert_eq!(value("lcm(4, 6)"), 12.0);
+    assert_eq!(value("gcd(12, 18, 8)"), 2.0);
+    assert_eq!(value("lcm(-4)"), 4.0);
+    assert_eq!(value("gcd(0, 0)"), 0.0);
+    assert_eq!(value("lcm(0, 5)"), 0.0);
+    let fold = FoldOptions::default();
+    let overflow = apply_function("lcm", &[4_611_686_018_427_387_904.0, 3.0], &fold);
+    assert!(overflow.is_err_and(|e| e.contains("lcm")));
+    let rejected = |source| parse_with(source, &ParseOptions::default()).unwrap_err();
+    let message = "gcd arguments must be 64-bit integers, got 1.5";
+    assert_eq!(rejected("gcd(1.5, 3)"), Failure::Evaluation(message.to_string()));
+    assert_eq!(rejected("x + gcd(1.5, 3)"), Failure::Evaluation(message.to_string()));
+    // Symbolic arguments leave the check to evaluation.
+    assert_eq!(sexpr(&simplified("gcd(x, 1.5)")), "(gcd x 1.5)");
+    assert_eq!(sexpr(&simplified("f(1.5)")), "(f 1.5)");
+    assert_eq!(value("if(1, 2, gcd(1.5, 3))"), 2.0);
+    // Validation skips the branch a constant condition discards.
+    assert_eq!(rejected_fold(&parse("if(1, 2, gcd(1.5, 3))"), &fold), None);
+    assert_eq!(rejected_fold(&parse("if(0, 2, gcd(1.5, 3))"), &fold), Some(message.to_string()));
+}
+
+#[test]
+fn hyperbolics_fold_inside_their_domains() {
+    assert_eq!(value("sinh(0)"), 0.0);
+    assert_eq!(value("cosh(0)"), 1.0);
+    assert_eq!(value("tanh(0)"), 0.0);
+    assert_eq!(value("asinh(0)"), 0.0);
+    assert_eq!(value("acosh(1)"), 0.0);
+    assert!((value("atanh(0.5)") - 0.5_f64.atanh()).abs() < 1e-12);
+    let rejected = |source| parse_with(source, &ParseOptions::default()).unwrap_err();
+    for (source, message) in [
+        ("atanh(2)", "atanh domain error: |2| >= 1"),
+        ("atanh(-1)", "atanh domain error: |-1| >= 1"),
+        ("acosh(0.5)", "acosh domain error: 0.5 < 1"),
+        ("asin(2)", "asin domain error: |2| > 1"),
+    ] {
+        assert_eq!(rejected(source), Failure::Evaluation(message.to_string()), "{}", source);
+    }
+    assert_eq!(sexpr(&simplified("atanh(x)")), "(atanh x)");
+}
+
+fn formatted(source: &str, number_format: NumberFormat) -> Vec<String> {
+    tokenize_with(source, None, number_format).into_iter().map(|token| token.text).collect()
+}
+
+#[test]
+fn number_formats_drop_grouping_and_read_the_decimal_mark() {
+    assert_eq!(formatted("1,234.56", NumberFormat::Us), ["1234.56"]);
+    assert_eq!(formatted("1.234,56", NumberFormat::Eu), ["1234.56"]);
+    assert_eq!(formatted("1.234.567", NumberFormat::Eu), ["1234567"]);
+    assert_eq!(formatted("1,234.56", NumberFormat::Plain), ["1", ",", "234.56"]);
+    // A separator without exactly three digits after it separates arguments.
+    assert_eq!(formatted("f(1,23)", NumberFormat::Us), ["f", "(", "1", ",", "23", ")"]);
+    assert_eq!(formatted("f(1, 234)", NumberFormat::Us), ["f", "(", "1", ",", "234", ")"]);
+    assert_eq!(formatted("f(1,5)", NumberFormat::Eu), ["f", "(", "1.5", ")"]);
+    let eu = ParseOptions { number_format: NumberFormat::Eu, ..ParseOptions::default() };
+    assert_eq!(parse_with("1.234,5 * 2", &eu).unwrap(), Expr::Number(2469.0));
+}
+
+#[test]
+fn max_variables_counts_distinct_free_names() {
+    let two = limited(Limits { max_variables: Some(2), ..Limits::default() });
+    let failure = parse_with("a+b+c", &two).unwrap_err();
+    assert_eq!(failure, Failure::Limit("max_variables exceeded: 3 > 2".to_string()));
+    assert!(parse_with("a*b + a*b", &two).is_ok());
+    // Bound constants are not variables.
+    let bound = ParseOptions { constants: [("c".to_string(), 1.0)].into(), ..two };
+    assert!(parse_with("a+b+c", &bound).is_ok());
+}
+
+#[test]
+fn percent_is_modulo_between_operands_and_postfix_otherwise() {
+    assert_eq!(value("7 % 3"), 1.0);
+    assert_eq!(value("-7 % 3"), 2.0);
+    assert_eq!(value("7 % (-3)"), -2.0);
+    assert_eq!(value("50%"), 0.5);
+    assert_eq!(value("50% + 10"), 10.5);
+    // A sign spaced as one starts the divisor; otherwise it subtracts.
+    assert_eq!(value("7 % -3"), -2.0);
+    assert_eq!(value("7 % +3"), 1.0);
+    assert_eq!(value("7%-3"), 7.0 / 100.0 - 3.0);
+    assert_eq!(value("50% - 10"), -9.5);
+    assert_eq!(sexpr(&parse("x % -(y)")), "(% x (- y))");
+    assert_eq!(value("200%%"), 0.02);
+    assert_eq!(value("max(50%, 1)"), 1.0);
+    assert_eq!(sexpr(&parse("x % y")), "(% x y)");
+    assert_eq!(sexpr(&parse("x%")), "(/ x 100)");
+    assert_eq!(sexpr(&parse("2^50%")), "(^ 2 (/ 50 100))");
+    assert_eq!(sexpr(&parse("(x)% (y)")), "(% x y)");
+    assert_eq!(to_infix(&parse("x % (-y)")), "x % (-y)");
+}
+
+#[test]
+fn a_simplify_pass_reports_whether_it_changed_the_tree() {
+    let fold = FoldOptions::default();
+    let (folded, changed) = simplify_pass(parse("x * (1 + 2)"), &fold);
+    assert_eq!((sexpr(&folded), changed), ("(* x 3)".to_string(), true));
+    let (stable, changed) = simplify_pass(folded, &fold);
+    assert_eq!((sexpr(&stable), changed), ("(* x 3)".to_string(), false));
+    assert!(!simplify_pass(parse("x"), &fold).1);
+}
+
+#[test]
+fn auto_exact_divides_as_fraction_and_rejects_other_modes() {
+    for (_, division) in DIVISIONS {
+        assert_eq!(division.with_auto_exact(false), Ok(*division));
+    }
+    assert_eq!(Division::Float.with_auto_exact(true), Ok(Division::Fraction));
+    assert_eq!(Division::Fraction.with_auto_exact(true), Ok(Division::Fraction));
+    assert_eq!(Division::IntWhenExact.with_auto_exact(true), Err(Division::IntWhenExact));
+    assert_eq!(Division::AlwaysFloat.with_auto_exact(true), Err(Division::AlwaysFloat));
+}
+
+fn node_text(source: &str, position: usize) -> Option<String> {
+    innermost_node(source, position).unwrap().map(|node| sexpr(&node))
+}
+
+#[test]
+fn node_at_picks_the_innermost_span_containing_the_position() {
+    let source = "1 + 2*3";
+    assert_eq!(node_text(source, 0).as_deref(), Some("1"));
+    assert_eq!(node_text(source, 2).as_deref(), Some("(+ 1 (* 2 3))"));
+    assert_eq!(node_text(source, 4).as_deref(), Some("2"));
+    assert_eq!(node_text(source, 5).as_deref(), Some("(* 2 3)"));
+    assert_eq!(node_text(source, 7), None);
+    // Parentheses belong to the enclosing node; calls span their arguments.
+    assert_eq!(node_text("(x + 1)*y", 0).as_deref(), Some("(* (+ x 1) y)"));
+    assert_eq!(node_text("(x + 1)*y", 3).as_deref(), Some("(+ x 1)"));
+    assert_eq!(node_text("max(a, b)", 7).as_deref(), Some("b"));
+    assert_eq!(node_text("max(a, b)", 5).as_deref(), Some("(max a b)"));
+    // A comparison chain repeats its middle operand, which keeps one span.
+    assert_eq!(node_text("a < b < c", 4).as_deref(), Some("b"));
+    assert_eq!(node_text("a < b < c", 6).as_deref(), Some("(< b c)"));
+    assert!(innermost_node("(1", 0).is_err());
+}
+
+fn compiled(source: &str, env: &[(&str, f64)]) -> Result<f64, String> {
+    let (mut variables, mut program) = (Vec::new(), Vec::new());
+    compile_into(&simplified(source), &mut variables, &mut program);
+    let values: Vec<Option<f64>> = variables
+        .iter()
+        .map(|name| env.iter().find(|(n, _)| n == name).map(|(_, v)| *v))
+        .collect();
+    run_compiled(&program, &variables, &values)
+}
+
+#[test]
+fn compiled_programs_agree_with_tree_evaluation() {
+    let env = [("x", 3.0), ("y", -2.0), ("z", 0.0)];
+    let owned: Env = env.iter().map(|(n, v)| (n.to_string(), *v)).collect();
+    for source in [
+        "x + y*2",
+        "-(x - y)^2",
+        "x // y + x % y",
+        "max(x, y, 1) - min(x, 4)",
+        "x > y and y < z",
+        "z and 1/z",
+        "x and y",
+        "1 < x < 4",
+        "sqrt(x^2 + y^2)",
+        "x / z",
+    ] {
+        assert_eq!(compiled(source, &env), evaluate(&simplified(source), &owned), "{}", source);
+    }
+    assert_eq!(compiled("x + w", &env), Err("undefined variable 'w'".to_string()));
+    assert!(compiled("sqrt(y)", &env).is_err());
+}
+
+#[test]
+fn compiled_variables_take_slots_in_first_use_order() {
+    let (mut variables, mut program) = (Vec::new(), Vec::new());
+    compile_into(&parse("b*a + b"), &mut variables, &mut program);
+    assert_eq!(variables, ["b", "a"]);
+    assert_eq!(program.iter().filter(|i| matches!(i, Instr::Load(0))).count(), 2);
+}
+
+#[test]
+fn if_folds_only_the_branch_a_constant_condition_takes() {
+    assert_eq!(value("if(1, 2, 3)"), 2.0);
+    assert_eq!(value("if(2 > 3, 2, 3)"), 3.0);
+    assert_eq!(sexpr(&simplified("if(0, 1/x, y * (1 + 1))")), "(* y 2)");
+    assert_eq!(sexpr(&simplified("if(x, 1 + 1, y)")), "(if x 2 y)");
+    let env: Env = vec![("x".to_string(), 0.0)];
+    assert_eq!(evaluate(&parse("if(x, y, 5)"), &env), Ok(5.0));
+    assert_eq!(compiled("if(x, y, 5)", &[("x", 0.0)]), Ok(5.0));
+    assert_eq!(compiled("if(x, 5, y)", &[("x", 1.0)]), Ok(5.0));
+    assert_eq!(compiled("1 + if(x, 2, 3) * 10", &[("x", 1.0)]), Ok(21.0));
+    for source in ["if(1, 2)", "if(1, 2, 3, 4)"] {
+        let message = parse_source(source).unwrap_err();
+        assert!(message.contains("if expects 3 argument(s)"), "{}", message);
+    }
+    // Each condition is simplified once, so this takes 40 steps, not 2^40.
+    let nested = format!("{}x{}", "if(".repeat(40), ", 1, 2)".repeat(40));
+    let expected = format!("{}x{}", "(if ".repeat(40), " 1 2)".repeat(40));
+    assert_eq!(sexpr(&simplified(&nested)), expected);
+    let constants = HashMap::from([("x".to_string(), 0.0)]);
+    let options = ParseOptions { constants, ..ParseOptions::default() };
+    assert_eq!(parse_with(&nested, &options), Ok(Expr::Number(1.0)));
+}
+
+#[test]
+fn whitespace_tokens_reconstruct_the_source() {
+    for source in ["1 +  2", "  f( x,\ty )\n", "a<=b", "x^ -2 ", ""] {
+        assert_eq!(lex(source, true).concat(), source);
+        assert_eq!(lex(source, false), texts(source));
+    }
+    assert_eq!(lex(" 1 +\t 2", true), [" ", "1", " ", "+", "\t ", "2"]);
+    let tokens = lex_tokens("x  *y", None, NumberFormat::Plain);
+    let positions: Vec<usize> = tokens.iter().map(|token| token.pos).collect();
+    assert_eq!(positions, [0, 1, 3, 4]);
+}
+
+fn styled(source: &str, spaces: bool, keep_parens: bool, precision: Option<usize>) -> String {
+    styled_source(source, &Style { spaces, keep_parens, precision }).unwrap()
+}
+
+#[test]
+fn format_styles_spacing_parens_and_precision() {
+    assert_eq!(styled("1+2*x", true, false, None), "1 + 2 * x");
+    assert_eq!(styled("1 + 2 * x", false, false, None), "1+2*x");
+    assert_eq!(styled("max(a,b)", true, false, None), "max(a, b)");
+    assert_eq!(styled("max(a, b)", false, false, None), "max(a,b)");
+    assert_eq!(styled("(1*2)+((x))", true, false, None), "1 * 2 + x");
+    assert_eq!(styled("(1*2)+((x))", true, true, None), "(1 * 2) + ((x))");
+    assert_eq!(styled("(1+2)*3", false, false, None), "(1+2)*3");
+    assert_eq!(styled("3.14159*r", true, false, Some(2)), "3.14 * r");
+    assert_eq!(styled("2.50 + 1.0", true, false, Some(3)), "2.5 + 1");
+    assert!(styled_source("(1", &Style::default()).is_err());
+}
+
+fn size(source: &str) -> (usize, usize, usize) {
+    let mut size = Size::default();
+    measure(&parse(source), 1, &mut size);
+    (size.nodes, size.depth, size.leaves)
+}
+
+#[test]
+fn size_counts_nodes_depth_and_leaves_in_one_walk() {
+    assert_eq!(size("(1+2)*(3+4)"), (7, 3, 4));
+    assert_eq!(size("x"), (1, 1, 1));
+    assert_eq!(size("-x"), (2, 2, 1));
+    assert_eq!(size("max(1, 2, y^2)"), (6, 3, 4));
+}
+
+#[test]
+fn only_signs_may_follow_another_operator() {
+    assert_eq!(value("1 ++ 2"), 3.0);
+    assert_eq!(value("1 + -2"), -1.0);
+    assert_eq!(value("2 ^ -1"), 0.5);
+    assert_eq!(value("1 - - 2"), 3.0);
+    for (source, message) in [
+        ("3 */ 4", "operator '/' cannot follow '*' at position 3"),
+        ("x and and y", "operator 'and' cannot follow 'and' at position 6"),
+        ("2 ^ * 3", "operator '*' cannot follow '^' at position 4"),
+        ("1 < >= 2", "operator '>=' cannot follow '<' at position 4"),
+    ] {
+        assert_eq!(parse_source(source).unwrap_err(), message, "{}", source);
+    }
+}
+
+#[test]
+fn subexpressions_list_every_node_in_post_order() {
+    let all = subexpression_texts(&parse("1 + 2*3"));
+    assert_eq!(all, ["1", "2", "3", "2 * 3", "1 + 2 * 3"]);
+    let repeated = subexpression_texts(&parse("x*x - f(x)"));
+    assert_eq!(repeated, ["x", "x", "x * x", "x", "f(x)", "x * x - f(x)"]);
+}
+
+#[test]
+fn keeping_the_raw_tree_returns_it_beside_the_simplified_one() {
+    let constants = [("k".to_string(), 4.0)].into();
+    let options = ParseOptions { constants, ..ParseOptions::default() };
+    let (raw, simplified) = parse_stages("2 + 3", &options, true).unwrap();
+    assert_eq!(sexpr(&raw.unwrap()), "(+ 2 3)");
+    assert_eq!(simplified, Expr::Number(5.0));
+    let (raw, simplified) = parse_stages("k * x", &options, true).unwrap();
+    assert_eq!(sexpr(&raw.unwrap()), "(* k x)");
+    assert_eq!(sexpr(&simplified), "(* 4 x)");
+    assert_eq!(parse_stages("2 + 3", &options, false).unwrap().0, None);
+}
+
+fn tokenize_failure(source: &str) -> Option<Failure> {
+    parse_with(source, &ParseOptions::default()).err().filter(|f| matches!(f, Failure::Tokenize(_)))
+}
+
+#[test]
+fn malformed_numbers_are_tokenize_errors() {
+    for (source, message) in [
+        ("1.2.3", "malformed number '1.2.3' at position 0"),
+        ("2 * 1..5", "malformed number '1..5' at position 4"),
+        (".", "number '.' has no digits at position 0"),
+        ("x + .", "number '.' has no digits at position 4"),
+    ] {
+        assert_eq!(tokenize_failure(source), Some(Failure::Tokenize(message.to_string())));
+    }
+    // A trailing point is allowed, as in Python.
+    assert_eq!(value("1."), 1.0);
+    assert_eq!(tokenize_failure("1. + 2"), None);
+}
+
+#[test]
+fn degrees_apply_to_trig_arguments_and_inverse_results() {
+    let degrees = FoldOptions { degrees: true, ..FoldOptions::default() };
+    let fold = |source| match simplify(parse(source), &degrees) {
+        Expr::Number(n) => n,
+        other => panic!("{:?} did not fold: {:?}", source, other),
+    };
+    let close = |a: f64, b: f64| (a - b).abs() < 1e-9;
+    assert!(close(fold("atan(1)"), 45.0));
+    assert!(close(fold("atan2(1, 1)"), 45.0));
+    assert!(close(fold("atan2(1, -1)"), 135.0));
+    assert!(close(fold("asin(1)"), 90.0));
+    assert!(close(fold("acos(0)"), 90.0));
+    assert!(close(fold("sin(30)"), 0.5));
+    assert!(close(fold("sin(asin(0.5))"), 0.5));
+    assert!(close(value("atan2(1, 1)"), std::f64::consts::FRAC_PI_4));
+    assert!(close(value("atan(1)"), std::f64::consts::FRAC_PI_4));
+}
+
+fn coefficients(source: &str) -> Result<Vec<f64>, String> {
+    polynomial(&simplified(source), "x")
+}
+
+#[test]
+fn polynomial_coefficients_expand_products_and_powers() {
+    assert_eq!(coefficients("2*x^2 + 3*x + 1"), Ok(vec![1.0, 3.0, 2.0]));
+    assert_eq!(coefficients("(x + 1)^3"), Ok(vec![1.0, 3.0, 3.0, 1.0]));
+    assert_eq!(coefficients("(x - 1)*(x + 1)"), Ok(vec![-1.0, 0.0, 1.0]));
+    assert_eq!(coefficients("x/2 - x/2"), Ok(vec![0.0]));
+    assert_eq!(coefficients("7"), Ok(vec![7.0]));
+    for (source, message) in [
+        ("sin(x)", "'sin(x)' is not a polynomial in x"),
+        ("1/x", "'1 / x' is not a polynomial in x"),
+        ("x*y", "'y' is not a polynomial in x"),
+        ("x^0.5", "'x ^ 0.5' is not a polynomial in x"),
+    ] {
+        assert_eq!(coefficients(source), Err(message.to_string()), "{}", source);
+    }
+}
+
+#[test]
+fn polynomial_degree_is_capped_before_expanding() {
+    assert_eq!(coefficients("x^1024").map(|c| c.len()), Ok(1025));
+    let message = "'(x + 1) ^ 1000000000' has degree 1000000000 in x, above the limit of 1024";
+    assert_eq!(coefficients("(x + 1)^1000000000"), Err(message.to_string()));
+    let product = coefficients("x^600 * x^600").unwrap_err();
+    assert!(product.contains("has degree 1200 in x"), "{}", product);
+    // A constant base costs nothing to raise.
+    assert_eq!(coefficients("(x^0)^1000000000"), Ok(vec![1.0]));
+}
+
+#[test]
+fn approx_compares_within_an_absolute_tolerance() {
+    assert_eq!(value("approx(0.1 + 0.2, 0.3, 1e-9)"), 1.0);
+    assert_eq!(value("0.1 + 0.2 == 0.3"), 0.0);
+    assert_eq!(value("approx(1, 1.5, 0.5)"), 1.0);
+    assert_eq!(value("approx(1, 1.5, 0.25)"), 0.0);
+    assert_eq!(sexpr(&simplified("approx(x, 1, 0.1)")), "(approx x 1 0.1)");
+    let env: Env = vec![("x".to_string(), 1.05)];
+    assert_eq!(evaluate(&parse("approx(x, 1, 0.1)"), &env), Ok(1.0));
+    assert!(parse_source("approx(1, 2)").is_err());
+}
+
+#[test]
+fn int_powers_are_refused_past_the_bit_limit() {
+    assert_eq!(check_pow_bits(4, 1_000_000), Ok(()));
+    assert_eq!(check_pow_bits(1, u64::MAX), Ok(()));
+    assert_eq!(check_pow_bits(0, u64::MAX), Ok(()));
+    assert_eq!(check_pow_bits(2, MAX_POW_BITS), Ok(()));
+    let message = "'^' result would have at least 1162261467 bits, above the limit of 4194304";
+    // 9^9^9 = 9^387420489, and 9 has 4 bits.
+    assert_eq!(check_pow_bits(4, 387_420_489), Err(message.to_string()));
+    assert!(check_pow_bits(u64::MAX, u64::MAX).is_err());
+}
+
+fn expansion(source: &str) -> String {
+    expanded_text(&simplified(source))
+}
+
+#[test]
+fn expand_distributes_products_and_small_powers() {
+    assert_eq!(expansion("(a+b)*(c+d)"), "a * c + a * d + b * c + b * d");
+    assert_eq!(expansion("(a+b)^2"), "a ^ 2 + 2 * a * b + b ^ 2");
+    assert_eq!(expansion("(a-b)*(a+b)"), "a ^ 2 - b ^ 2");
+    assert_eq!(expansion("2*(x+1) - 2*x"), "2");
+    assert_eq!(expansion("(x+1)*(x-1) - x^2 + 1"), "0");
+    assert_eq!(expansion("a*(b*(c+d))"), "a * b * c + a * b * d");
+    // Calls and high powers stay whole.
+    assert_eq!(expansion("sin(x)*(y+1)"), "sin(x) * y + sin(x)");
+    assert_eq!(expansion("(a+b)^17"), "(a + b) ^ 17");
+}
+
+#[test]
+fn arity_errors_describe_the_accepted_range() {
+    let message = parse_source("max()").unwrap_err();
+    assert_eq!(message, "max expects at least 1 argument(s), got 0 at position 0");
+    assert_eq!(value("max(3)"), 3.0);
+    assert_eq!(value("min(3, -1, 2)"), -1.0);
+    assert_eq!(arity_error("f", 0, 0, 0), None);
+    assert_eq!(arity_error("f", 0, 2, 2), None);
+    assert_eq!(arity_error("f", 0, 0, 1).unwrap(), "f expects 0 argument(s), got 1");
+    assert_eq!(arity_error("f", 1, 2, 3).unwrap(), "f expects 1 to 2 argument(s), got 3");
+    let unbounded = arity_error("f", 2, usize::MAX, 1).unwrap();
+    assert_eq!(unbounded, "f expects at least 2 argument(s), got 1");
+}
+
+#[test]
+fn a_point_may_lead_or_trail_the_digits() {
+    assert_eq!(texts("1+.5"), ["1", "+", ".5"]);
+    assert_eq!(value("1+.5"), 1.5);
+    assert_eq!(value(".5"), 0.5);
+    assert_eq!(value("-.25*4"), -1.0);
+    assert_eq!(value("5."), 5.0);
+    assert_eq!(value("5.*2"), 10.0);
+    assert_eq!(value(".5e1"), 5.0);
+    let message = "number '.' has no digits at position 2";
+    assert_eq!(tokenize_failure("1+."), Some(Failure::Tokenize(message.to_string())));
+}
+
+#[test]
+fn negated_right_operands_flip_the_sign() {
+    assert_eq!(to_infix(&simplified("a - -b")), "a + b");
+    assert_eq!(to_infix(&simplified("a - (-b)")), "a + b");
+    assert_eq!(to_infix(&simplified("a + -b")), "a - b");
+    assert_eq!(to_infix(&simplified("a + -2")), "a - 2");
+    assert_eq!(to_infix(&simplified("a - -2")), "a + 2");
+    assert_eq!(to_infix(&simplified("a - -(b*c)")), "a + b * c");
+    // Only the right operand of `+` and `-` is normalized.
+    assert_eq!(to_infix(&simplified("-a + b")), "-a + b");
+    assert_eq!(to_infix(&simplified("a * -b")), "a * -b");
+}
+
+fn solved(source: &str) -> Solution {
+    solve(&coefficients(source).unwrap())
+}
+
+#[test]
+fn quadratics_and_lines_are_solved_for_zero() {
+    assert_eq!(solved("x^2 - 5*x + 6"), Solution::Real(vec![2.0, 3.0]));
+    assert_eq!(solved("x^2"), Solution::Real(vec![0.0]));
+    assert_eq!(solved("(x - 1)^2"), Solution::Real(vec![1.0]));
+    assert_eq!(solved("2*x + 1"), Solution::Real(vec![-0.5]));
+    assert_eq!(solved("3"), Solution::Real(vec![]));
+    assert_eq!(solved("x^2 + 1"), Solution::Conjugate(0.0, 1.0));
+    assert_eq!(solved("x^2 + 2*x + 5"), Solution::Conjugate(-1.0, 2.0));
+    assert_eq!(solved("x - x"), Solution::Everywhere);
+    assert_eq!(solved("x^3 - 1"), Solution::Unsupported(3));
+    // Both roots stay accurate when one is tiny beside the other.
+    let Solution::Real(roots) = solved("x^2 - 100000000*x + 1") else { panic!() };
+    assert!((roots[0] - 1e-8).abs() < 1e-20 && (roots[1] - 1e8).abs() < 1e-6);
+    // The double root of `-x^2` is `0.0`, not `-0.0`.
+    let Solution::Real(roots) = solved("-x^2") else { panic!() };
+    assert!(roots[0].is_sign_positive());
+}
+
+#[test]
+fn exponents_take_either_case_and_need_digits() {
+    assert_eq!(value("5E3"), 5000.0);
+    assert_eq!(value("5e+0"), 5.0);
+    assert_eq!(value("2.5e-1"), 0.25);
+    assert_eq!(value("1e2 + 1"), 101.0);
+    assert_eq!(texts("5e+3*x"), ["5e+3", "*", "x"]);
+    for (source, text) in [("5e", "5e"), ("5e+", "5e+"), ("1 + 5E-", "5E-")] {
+        let position = source.len() - text.len();
+        let message = format!(
+            "malformed number '{}' (the exponent has no digits) at position {}",
+            text, position
+        );
+        assert_eq!(tokenize_failure(source), Some(Failure::Tokenize(message)), "{}", source);
+    }
+}
+
+#[test]
+fn int_policies_decide_from_the_parsed_tree() {
+    let allows = |policy: IntPolicy, source| policy.allows_int(&parse(source));
+    assert!(!allows(IntPolicy::Never, "2*3"));
+    assert!(allows(IntPolicy::Integral, "6/2"));
+    assert!(allows(IntPolicy::NoDivision, "2*3"));
+    assert!(!allows(IntPolicy::NoDivision, "6/2"));
+    assert!(!allows(IntPolicy::NoDivision, "1 + max(2, 6/2)"));
+    // A percent divides by 100.
+    assert!(!allows(IntPolicy::NoDivision, "200%"));
+    assert_eq!(as_i64(value("2*3")), Some(6));
+    assert_eq!(as_i64(value("7/2")), None);
+}
+
+fn same_grouping(a: &str, b: &str) -> bool {
+    lean_left(parse(a)) == lean_left(parse(b))
+}
+
+#[test]
+fn regrouping_matches_only_associative_chains_in_order() {
+    assert!(same_grouping("(1+2)+3", "1+(2+3)"));
+    assert!(same_grouping("a*(b*(c*d))", "((a*b)*c)*d"));
+    assert!(same_grouping("f((a+b)+c)", "f(a+(b+c))"));
+    assert!(!same_grouping("(1-2)-3", "1-(2-3)"));
+    assert!(!same_grouping("2^(3^2)", "(2^3)^2"));
+    assert!(!same_grouping("1+2", "2+1"));
+    assert!(!same_grouping("(a+b)*c", "a+(b*c)"));
+}
+
+#[test]
+fn max_literal_names_the_first_literal_over_the_bound() {
+    let bounded = ParseOptions { max_literal: Some(1e6), ..ParseOptions::default() };
+    let failure = parse_with("x + 2000000", &bounded).unwrap_err();
+    let message = "max_literal exceeded: literal 2000000 > 1000000 at position 4";
+    assert_eq!(failure, Failure::Limit(message.to_string()));
+    assert!(parse_with("1000000 * 1000000", &bounded).is_ok());
+    // The bound applies to literals as written, not to a sign or a result.
+    assert!(parse_with("-999999 - 999999", &bounded).is_ok());
+    let failure = parse_with("1e7", &bounded).unwrap_err();
+    assert!(failure.message().contains("literal 10000000"), "{:?}", failure);
+}
+
+fn edge_list(source: &str) -> (Vec<String>, Vec<(usize, usize, String)>) {
+    let expr = parse(source);
+    let (mut nodes, mut edges) = (Vec::new(), Vec::new());
+    collect_edges(&expr, &mut nodes, &mut edges);
+    (nodes.into_iter().map(node_label).collect(), edges)
+}
+
+#[test]
+fn edge_lists_number_nodes_in_pre_order() {
+    let (nodes, edges) = edge_list("(1+2)*(3+4)");
+    assert_eq!(nodes.len(), 7);
+    assert_eq!(nodes[0], "Binary(*)");
+    assert_eq!(nodes[1], "Binary(+)");
+    let labelled = |p, c, label: &str| (p, c, label.to_string());
+    assert_eq!(
+        edges,
+        [
+            labelled(0, 1, "left"),
+            labelled(1, 2, "left"),
+            labelled(1, 3, "right"),
+            labelled(0, 4, "right"),
+            labelled(4, 5, "left"),
+            labelled(4, 6, "right"),
+        ]
+    );
+    let (nodes, edges) = edge_list("-f(x, 2)");
+    assert_eq!(nodes, ["Unary(-)", "Call(f)", "Variable(x)", "Number(2)"]);
+    let labels: Vec<&str> = edges.iter().map(|(_, _, label)| label.as_str()).collect();
+    assert_eq!(labels, ["expr", "arg0", "arg1"]);
+}
+
+#[test]
+fn trivial_powers_reduce_even_with_a_symbolic_side() {
+    assert_eq!(simplified("x^0"), Expr::Number(1.0));
+    assert_eq!(simplified("1^x"), Expr::Number(1.0));
+    assert_eq!(sexpr(&simplified("(x + y)^1")), "(+ x y)");
+    assert_eq!(sexpr(&simplified("0^x")), "(^ 0 x)");
+    assert_eq!(value("0^0"), 1.0);
+    assert_eq!(value("0^3"), 0.0);
+    assert_eq!(value("0^-1"), f64::INFINITY);
+    assert_eq!(sexpr(&simplified("x^(2 - 1)")), "x");
+}
+
+fn renamed(source: &str, old: &str, new: &str) -> Result<String, String> {
+    renamed_variable(parse(source), old, new).map(|expr| to_infix(&expr))
+}
+
+#[test]
+fn renaming_leaves_functions_and_other_variables_alone() {
+    assert_eq!(renamed("x + x*y", "x", "z"), Ok("z + z * y".to_string()));
+    assert_eq!(renamed("x(x) + y", "x", "t"), Ok("x(t) + y".to_string()));
+    assert_eq!(renamed("x + 1", "x", "x"), Ok("x + 1".to_string()));
+    let merge = "renaming 'x' to 'y' would merge it with the existing variable 'y'";
+    assert_eq!(renamed("x + y", "x", "y"), Err(merge.to_string()));
+    assert_eq!(renamed("x", "x", "2a"), Err("invalid variable name '2a'".to_string()));
+    assert_eq!(renamed("x", "x", "and"), Err("invalid variable name 'and'".to_string()));
+}
+
+#[test]
+fn symbolic_fallback_shows_the_simplified_marked_tree() {
+    let marked = |source| parse_statement_expr(&mark_float_literals(tokenize(source))).unwrap();
+    assert_eq!(symbolic_form(marked("x + 1")), "x + 1");
+    assert_eq!(symbolic_form(marked("x * (2.0 + 3)")), "x * 5");
+    assert_eq!(symbolic_form(marked("1.5e1 * y")), "15 * y");
+}
+
+#[test]
+fn redundant_parens_count_each_removable_parenthesis() {
+    let count = |source| redundant_paren_count(source).unwrap();
+    assert_eq!(count("((1+2))*3"), 2);
+    assert_eq!(count("(1+2)*3"), 0);
+    assert_eq!(count("1+(2*3)"), 2);
+    assert_eq!(count("(x)"), 2);
+    assert_eq!(count("1-(2-3)"), 0);
+    assert_eq!(count("(1-2)-3"), 2);
+    assert_eq!(count("2^(3^2)"), 2);
+    assert_eq!(count("(2^3)^2"), 0);
+    assert_eq!(count("f((x), y)"), 2);
+    assert_eq!(count("f(x)"), 0);
+    assert!(redundant_paren_count("(1").is_err());
+}
+
+#[test]
+fn lines_parse_one_expression_each_and_skip_blanks() {
+    assert_eq!(parse_line("1 + 2\n"), Some(Ok(Expr::Number(3.0))));
+    assert_eq!(parse_line("x*2\r\n").map(|r| r.map(|e| sexpr(&e))), Some(Ok("(* x 2)".into())));
+    assert_eq!(parse_line("   \n"), None);
+    assert_eq!(parse_line(""), None);
+    let message = "expected expression after '+' at position 2";
+    assert_eq!(parse_line("1 +\n"), Some(Err(message.to_string())));
+}
+
+#[test]
+fn empty_parentheses_are_reported_where_they_open() {
+    for (source, position) in [("()", 0), ("(  )", 0), ("1 + ()", 4), ("2 * (())", 5)] {
+        let message = format!("empty parentheses at position {}", position);
+        assert_eq!(parse_source(source), Err(message), "{}", source);
+    }
+    // A call's empty argument list is an arity question, not this error.
+    assert!(parse_source("f()").is_ok());
+}
+
+#[test]
+fn literals_are_ints_exactly_when_written_without_point_or_exponent() {
+    let is_marked = |literal: &str| {
+        let tokens = mark_float_literals(tokenize(literal));
+        tokens.first().is_some_and(|token| token.text == FLOAT_LITERAL)
+    };
+    for literal in ["3.0", "3.", "5e3", "5E0", "0x1p4", "0x10.0"] {
+        assert!(is_marked(literal), "{}", literal);
+    }
+    // Non-integral literals are `float`s anyway and need no mark.
+    for literal in ["3", "0xff", "1.5", "2.5e-1"] {
+        assert!(!is_marked(literal), "{}", literal);
+    }
+    let marked = parse_statement_expr(&mark_float_literals(tokenize("3.0/2"))).unwrap();
+    assert_eq!(sexpr(&marked), "(/ (float literal 3) 2)");
+}
+
+#[test]
+fn operator_annotations_come_from_the_parse_table() {
+    let prec = Precedence::default();
+    let entry = |op| prec.infix(op).unwrap();
+    let annotation = |op| (entry(op).power, entry(op).assoc.name());
+    assert!(entry("+").power < entry("*").power);
+    assert!(entry("*").power < prec.prefix_power());
+    assert!(prec.prefix_power() < entry("^").power);
+    assert_eq!(annotation("+").1, "left");
+    assert_eq!(annotation("^").1, "right");
+    assert_eq!(annotation("<").1, "chain");
+    // Overrides change the annotations along with the parse.
+    let tight = levels(&[("+", 25)]).unwrap();
+    assert!(tight.infix("+").unwrap().power > tight.infix("*").unwrap().power);
+}
+
+#[test]
+fn distributing_multiplies_constants_through_sums() {
+    let fold = |source, like_terms| {
+        let options = FoldOptions { distribute: true, like_terms, ..FoldOptions::default() };
+        to_infix(&simplify(parse(source), &options))
+    };
+    assert_eq!(fold("2*(x+3)", false), "2 * x + 6");
+    assert_eq!(fold("(x-3)*2", false), "2 * x - 6");
+    assert_eq!(fold("3*(2*x + y)", false), "6 * x + 3 * y");
+    assert_eq!(fold("2*(x+1) + 3*x", true), "5 * x + 2");
+    assert_eq!(fold("x*(y+1)", false), "x * (y + 1)");
+    assert_eq!(to_infix(&simplified("2*(x+3)")), "2 * (x + 3)");
+}
+
+#[test]
+fn paren_depth_counts_source_nesting_not_tree_depth() {
+    let depth = |source| max_paren_depth(&tokenize(source));
+    assert_eq!(depth("((1+2)*(3))"), 2);
+    assert_eq!(depth("1+2"), 0);
+    assert_eq!(depth("((1))"), 2);
+    assert_eq!(depth("f(g(x), (y))"), 2);
+    assert_eq!(size("((1))").1, 1);
+}
+
+#[test]
+fn word_operators_divide_at_multiplicative_precedence() {
+    assert_eq!(value("7 mod 3"), 1.0);
+    assert_eq!(value("-7 mod 3"), 2.0);
+    assert_eq!(value("-7 rem 3"), -1.0);
+    assert_eq!(value("7 rem -3"), 1.0);
+    assert_eq!(value("7 div 2"), 3.0);
+    assert_eq!(value("-7 div 2"), -4.0);
+    assert_eq!(sexpr(&parse("1 + a mod b * c")), "(+ 1 (* (mod a b) c))");
+    assert_eq!(texts("modx + divy"), ["modx", "+", "divy"]);
+    // Reserved: a word operator cannot be an operand.
+    assert!(parse_source("mod + 1").is_err());
+    assert!(parse_source("2 * rem").is_err());
+}
+
+#[test]
+fn settling_repeats_passes_that_expose_more_work() {
+    let both = FoldOptions { like_terms: true, distribute: true, ..FoldOptions::default() };
+    // Merging the like terms makes a product the next pass distributes.
+    let (once, changed) = simplify_pass(parse("y + (x+1) + (x+1)"), &both);
+    assert_eq!((to_infix(&once).as_str(), changed), ("y + 2 * (x + 1)", true));
+    let (stable, passes) = settle(parse("y + (x+1) + (x+1)"), &both).unwrap();
+    assert_eq!((to_infix(&stable).as_str(), passes), ("y + (2 * x + 2)", 2));
+    let (stable, passes) = settle(parse("2*(x+1)"), &both).unwrap();
+    assert_eq!((to_infix(&stable).as_str(), passes), ("2 * x + 2", 1));
+    assert_eq!(settle(parse("x + y"), &both).map(|(_, passes)| passes), Ok(0));
+}
+
+#[test]
+fn hex_float_literals_read_bit_exactly() {
+    assert_eq!(parse_hex_float("0x1.8p3"), Some(12.0));
+    assert_eq!(parse_hex_float("0x1p-1"), Some(0.5));
+    assert_eq!(parse_hex_float("0xff"), Some(255.0));
+    assert_eq!(parse_hex_float("0x1.8"), Some(1.5));
+    assert_eq!(parse_hex_float("0X.8P1"), Some(1.0));
+    assert_eq!(parse_hex_float("0x1.fffffffffffffp1023"), Some(f64::MAX));
+    assert_eq!(parse_hex_float("0x1p-1074"), Some(f64::from_bits(1)));
+    assert_eq!(value("0x1.8p3 + 1"), 13.0);
+    for malformed in ["0x", "0x1.2.3", "0x1p", "0xg", "0x1p+"] {
+        assert_eq!(parse_hex_float(malformed), None, "{}", malformed);
+        let message = format!("malformed hex literal '{}' at position 0", malformed);
+        assert_eq!(tokenize_failure(malformed), Some(Failure::Tokenize(message)));
+    }
+}
+
+fn semantic_key(source: &str) -> u64 {
+    fnv1a(to_sexpr_string(&commute(simplified(source))).as_bytes())
+}
+
+#[test]
+fn semantic_hashes_ignore_operand_order_of_commutative_operators() {
+    assert_eq!(semantic_key("a+b"), semantic_key("b + a"));
+    assert_eq!(semantic_key("x*y*2"), semantic_key("2*(y*x)"));
+    assert_eq!(semantic_key("1+2"), semantic_key("3"));
+    assert_ne!(semantic_key("a-b"), semantic_key("b-a"));
+    assert_ne!(semantic_key("a/b"), semantic_key("b/a"));
+    assert_ne!(semantic_key("2*x"), semantic_key("x+x"));
+    // FNV-1a is fixed, so stored keys stay valid: these are its test vectors.
+    assert_eq!(fnv1a(b""), 0xcbf29ce484222325);
+    assert_eq!(fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+}
+
+#[test]
+fn piecewise_reads_as_nested_ifs() {
+    let source = "piecewise((x < 0, -x), (x < 10, x), 10)";
+    assert_eq!(sexpr(&parse(source)), "(if (< x 0) (- x) (if (< x 10) x 10))");
+    assert_eq!(value("piecewise((1 > 2, 5), (2 > 1, 6), 7)"), 6.0);
+    assert_eq!(value("piecewise((0, 5), 7)"), 7.0);
+    assert_eq!(value("piecewise((0, 5), (7))"), 7.0);
+    assert_eq!(sexpr(&simplified("piecewise((0, 1), (x, 2), 3)")), "(if x 2 3)");
+    for x in [-4.0, 4.0, 40.0] {
+        let env: Env = vec![("x".to_string(), x)];
+        let expected = if x < 0.0 { -x } else { x.min(10.0) };
+        assert_eq!(evaluate(&parse(source), &env), Ok(expected));
+    }
+    let message = "piecewise expects (condition, value) pairs then a default at position";
+    for bad in [
+        "piecewise(1)",
+        "piecewise((1, 2))",
+        "piecewise((1, 2) 3)",
+        "piecewise((1, 2, 3), 4)",
+    ] {
+        let error = parse_source(bad).unwrap_err();
+        assert!(error.starts_with(message), "{}: {}", bad, error);
+    }
+    // Spans stay in step with the nodes.
+    assert_eq!(node_text(source, 0).as_deref(), Some(sexpr(&parse(source)).as_str()));
+}
+
+#[test]
+fn allowed_vars_reject_other_variables_by_name() {
+    let allowed = Some(vec!["x".to_string(), "y".to_string()]);
+    let options = ParseOptions { allowed_vars: allowed, ..ParseOptions::default() };
+    assert!(parse_with("x*y + sin(x)", &options).is_ok());
+    let message = "variable 'z' is not allowed; allowed_vars is [x, y]";
+    assert_eq!(parse_with("x + z", &options), Err(Failure::Parse(message.to_string())));
+    // Checked before folding, so a cancelling variable still counts.
+    assert!(parse_with("x + z - z", &options).is_err());
+    let bound = ParseOptions { constants: [("z".to_string(), 1.0)].into(), ..options };
+    assert!(parse_with("x + z", &bound).is_ok());
+}
+
+/// A small deterministic generator, so a failing tree can be replayed.
+struct Lcg(u64);
+
+impl Lcg {
+    fn below(&mut self, n: usize) -> usize {
+        self.0 = self.0.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1);
+        ((self.0 >> 33) % n as u64) as usize
+    }
+
+    fn expr(&mut self, depth: usize) -> Expr {
+        const NUMBERS: &[f64] =
+            &[0.0, -0.0, 1.0, 2.5, -3.0, 0.1, 1e20, f64::INFINITY, f64::NEG_INFINITY, f64::NAN];
+        const BINARY: &[&str] = &[
+            "+", "-", "*", "/", "//", "%", "mod", "^", "^", "^", "<<", "<", "==", "and",
+        ];
+        let leaf = depth == 0 || self.below(4) == 0;
+        match self.below(if leaf { 2 } else { 5 }) {
+            0 => Expr::Number(NUMBERS[self.below(NUMBERS.len())]),
+            1 => Expr::Variable(["x", "y", "z1"][self.below(3)].to_string()),
+            2 => Expr::Unary { op: "-", expr: Box::new(self.expr(depth - 1)) },
+            3 => {
+                let args = (0..self.below(3)).map(|_| self.expr(depth - 1)).collect();
+                Expr::Call { name: ["f", "g"][self.below(2)].to_string(), args }
+            }
+            _ => Expr::Binary {
+                op: BINARY[self.below(BINARY.len())],
+                left: Box::new(self.expr(depth - 1)),
+                right: Box::new(self.expr(depth - 1)),
+            },
+        }
+    }
+}
+
+/// `expr` as the parser builds it: a negative literal is a sign on its
+/// magnitude, and any NaN reads back as the positive one.
+fn as_parsed(expr: Expr) -> Expr {
+    match expr {
+        Expr::Number(n) if n.is_nan() => Expr::Number(f64::NAN),
+        Expr::Number(n) if n.is_sign_negative() => {
+            Expr::Unary { op: "-", expr: Box::new(Expr::Number(-n)) }
+        }
+        other => map_children(other, &mut as_parsed),
+    }
+}
+
+/// Structural equality with numbers compared by bits, so NaN equals itself
+/// and `-0` differs from `0`.
+fn same_tree(a: &Expr, b: &Expr) -> bool {
+    let shallow = match (a, b) {
+        (Expr::Number(x), Expr::Number(y)) => x.to_bits() == y.to_bits(),
+        (Expr::Variable(x), Expr::Variable(y)) => x == y,
+        (Expr::Unary { op: x, .. }, Expr::Unary { op: y, .. })
+        | (Expr::Binary { op: x, .. }, Expr::Binary { op: y, .. }) => x == y,
+        (Expr::Call { name: x, args: xs }, Expr::Call { name: y, args: ys }) => {
+            x == y && xs.len() == ys.len()
+        }
+        _ => false,
+    };
+    shallow && children(a).into_iter().zip(children(b)).all(|(x, y)| same_tree(x, y))
+}
+
+#[test]
+fn to_infix_reparses_to_the_same_tree() {
+    let mut rng = Lcg(0x5eed);
+    for _ in 0..5000 {
+        let expr = as_parsed(rng.expr(5));
+        let text = to_infix(&expr);
+        let reparsed = parse_source(&text).unwrap_or_else(|e| panic!("{:?}: {}", text, e));
+        let reparsed = bind_constants(reparsed, &|name| special().constant(name));
+        assert!(same_tree(&reparsed, &expr), "{:?}\n{:?}\n{:?}", text, expr, reparsed);
+    }
+}
+
+#[test]
+fn to_infix_keeps_nested_signs_and_special_numbers() {
+    let cases = [
+        ("-(-x)", "(- (- x))"),
+        ("-(-(-2))", "(- (- (- 2)))"),
+        ("(-2) ^ x", "(^ (- 2) x)"),
+        ("2 ^ 3 ^ x", "(^ 2 (^ 3 x))"),
+        ("(2 ^ 3) ^ x", "(^ (^ 2 3) x)"),
+        ("-x ^ 2", "(- (^ x 2))"),
+    ];
+    for (source, expected) in cases {
+        assert_eq!(sexpr(&parse(source)), expected, "{}", source);
+        assert_eq!(to_infix(&parse(source)), source);
+    }
+    let number = |n: f64| to_infix(&Expr::Number(n));
+    assert_eq!(number(-0.0), "-0");
+    assert_eq!(number(f64::NEG_INFINITY), "-inf");
+    assert_eq!(number(-f64::NAN), "nan");
+}
+
+/// The options `parse_infix` builds for `preserve=True`.
+fn preserving() -> ParseOptions {
+    let precedence = Precedence { keep_signs: true, ..Precedence::default() };
+    ParseOptions { preserve: true, precedence, ..ParseOptions::default() }
+}
+
+#[test]
+fn preserve_returns_the_source_structure_unfolded() {
+    let number = |n| Box::new(Expr::Number(n));
+    let product = Expr::Binary { op: "*", left: number(2.0), right: number(3.0) };
+    let sum = Expr::Binary { op: "+", left: number(1.0), right: Box::new(product) };
+    let expected = Expr::Binary { op: "-", left: Box::new(sum), right: number(4.0) };
+    assert_eq!(parse_with("1 + 2 * 3 - 4", &preserving()), Ok(expected));
+    // Operands keep their order and signs their nodes; constants still bind.
+    assert_eq!(sexpr(&parse_with("x * 1 + 0", &preserving()).unwrap()), "(+ (* x 1) 0)");
+    assert_eq!(sexpr(&parse_with("- -x + +y", &preserving()).unwrap()), "(+ (- (- x)) (+ y))");
+    let constants = HashMap::from([("k".to_string(), 2.0)]);
+    let bound = parse_with("k * x", &ParseOptions { constants, ..preserving() });
+    assert_eq!(sexpr(&bound.unwrap()), "(* 2 x)");
+    assert!(matches!(parse_with("1 +", &preserving()), Err(Failure::Parse(_))));
+}
+
+#[test]
+fn comparison_results_flow_into_arithmetic() {
+    assert_eq!(value("(3 > 0) * 5"), 5.0);
+    assert_eq!(value("(3 < 0) * 5"), 0.0);
+    assert_eq!(value("(2 > 1) + (1 > 2) + (1 == 1)"), 2.0);
+    assert_eq!(sexpr(&parse("(x > 0) * x")), "(* (> x 0) x)");
+    // Without the parentheses the comparison takes the whole product.
+    assert_eq!(sexpr(&parse("x > 0 * x")), "(> x (* 0 x))");
+    let relu = parse("(x > 0) * x");
+    for (x, expected) in [(-2.0, 0.0), (0.0, 0.0), (3.5, 3.5)] {
+        assert_eq!(evaluate(&relu, &[("x".to_string(), x)]), Ok(expected), "x = {}", x);
+    }
+}
@@ -0,0 +1,124 @@
+//! The Python-facing behaviour, which the unit tests can't reach since
+//! they can't call into Python: each test runs a script against the module
+//! this build produced, in the interpreter PyO3 builds for.
+
+use std::env;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::OnceLock;
+
+/// A directory holding the built library under the name Python imports.
+fn module_dir() -> &'static PathBuf {
+    static DIR: OnceLock<PathBuf> = OnceLock::new();
+    DIR.get_or_init(|| {
+        // Test binaries live in `target/<profile>/deps`, the library one up.
+        let exe = env::current_exe().unwrap();
+        let profile = exe.parent().and_then(|deps| deps.parent()).unwrap();
+        let (prefix, suffix) = (env::consts::DLL_PREFIX, env::consts::DLL_SUFFIX);
+        let library = profile.join(format!("{}infix_parser{}", prefix, suffix));
+        let dir = profile.join("python");
+        std::fs::create_dir_all(&dir).unwrap();
+        let module = if cfg!(windows) { "infix_parser.pyd" } else { "infix_parser.so" };
+        std::fs::copy(&library, dir.join(module))
+            .unwrap_or_else(|e| panic!("copying {}: {}", library.display(), e));
+        dir
+    })
+}
+
+/// Run `script` with the module imported as `ip`, failing the test if it
+/// raises.
+fn python(script: &str) {
+    let interpreter = env::var("PYO3_PYTHON").unwrap_or_else(|_| "python3".to_string());
+    let output = Command::new(interpreter)
+        .arg("-c")
+        .arg(format!("import infix_parser as ip\n{}", script))
+        .env("PYTHONPATH", module_dir())
+        .output()
+        .expect("python should run");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+}
+
+#[test]
+fn and_evaluates_as_it_folds() {
+    python(
+        r#"
+def folded(source):
+    node = ip.parse_infix(source)
+    assert node["type"] == "Number", node
+    return node["value"]
+
+for source in ["2 and 1", "0.5 and 1", "1 and 0", "0 and undefined"]:
+    value = ip.evaluate_with(source, {})
+    assert value == folded(source), (source, value)
+# The right side is skipped once the left is 0.
+assert ip.evaluate_with("x and y", {"x": 0}) == 0
+assert ip.evaluate_with("x and y", {"x": 2, "y": 0.5}) == 1
+"#,
+    );
+}
+
+#[test]
+fn invalid_constant_shifts_raise_when_parsed() {
+    python(
+        r#"
+assert ip.parse_infix("1 << 10") == {"type": "Number", "value": 1024.0}
+for source in ["1 << 64", "x + (1 >> -1)", "2.5 << 1"]:
+    try:
+        ip.parse_infix(source)
+    except ip.EvaluationError as e:
+        assert "shift" in str(e), e
+    else:
+        raise AssertionError(source)
+"#,
+    );
+}
+
+#[test]
+fn hooks_skip_the_branch_a_constant_if_discards() {
+    python(
+        r#"
+seen = []
+evaluator = ip.Evaluator()
+evaluator.register_operator("+", lambda a, b: seen.append((a, b)))
+assert evaluator.parse("if(1, 2, 3 + 4)") == {"type": "Number", "value": 2.0}
+assert evaluator.parse("if(0, 1 + 2, 5)") == {"type": "Number", "value": 5.0}
+assert seen == [], seen
+assert evaluator.parse("if(1, 2, gcd(1.5, 2))") == {"type": "Number", "value": 2.0}
+assert ip.parse_infix("if(1, 2, gcd(1.5, 2))") == {"type": "Number", "value": 2.0}
+evaluator.parse("if(x, 1 + 2, 3)")
+assert seen == [(1.0, 2.0)], seen
+"#,
+    );
+}
+
+#[test]
+fn a_huge_sum_times_out_cleanly() {
+    python(
+        r#"
+def pairs(depth):
+    return "x" if depth == 0 else "(%s + %s)" % (pairs(depth - 1), pairs(depth - 1))
+
+source = pairs(16)
+assert ip.evaluate_with(source, {"x": 1}) == 65536
+try:
+    ip.evaluate_with(source, {"x": 1}, timeout_ms=1)
+except TimeoutError as e:
+    assert str(e) == "evaluation exceeded timeout_ms=1", e
+else:
+    raise AssertionError("no timeout")
+try:
+    ip.evaluate_with("x ^ y", {"x": 3, "y": 10**6}, timeout_ms=1)
+except TimeoutError:
+    pass
+else:
+    raise AssertionError("no timeout")
+try:
+    ip.evaluate_with("x^x^x", {"x": 9}, timeout_ms=1000)
+except ip.LimitError:
+    pass
+else:
+    raise AssertionError("no limit")
+assert ip.evaluate_with(source, {"x": 1}, timeout_ms=60000) == 65536
+"#,
+    );
+}